@@ -40,6 +40,23 @@ def sort_text_objects(text_objects):
     return sorted(text_objects, key=cmp_to_key(cmp_sort_by_pos))
 
 
+def get_text_box_size(obj):
+    """Return world-space width, height and font size of the text.
+    Uses the first text box if it has one, otherwise the current text dimensions"""
+    scale = obj.matrix_world.to_scale()
+    font_size = obj.data.size * scale.y
+
+    box = obj.data.text_boxes[0] if len(obj.data.text_boxes) > 0 else None
+    if box and box.width > 0:
+        width = box.width * scale.x
+        height = box.height * scale.y if box.height > 0 else 0.0
+    else:
+        width = obj.dimensions.x
+        height = obj.dimensions.y
+
+    return width, height, font_size
+
+
 class OBJECT_OT_export_text_objects_csv(bpy.types.Operator):
     """Export all TEXT objects into a CSV file"""
     bl_idname = "object.export_text_objects_csv"
@@ -66,14 +83,18 @@ class OBJECT_OT_export_text_objects_csv(bpy.types.Operator):
                                 quotechar='"', quoting=csv.QUOTE_ALL)
 
             # Header
-            writer.writerow(["datablock_name", "Collection", "Text Contents"])
+            writer.writerow(["datablock_name", "Collection", "Text Contents",
+                             "Width", "Height", "Font Size"])
 
             for obj in text_objects:
                 # Get first collection name (if any)
                 col_name = obj.users_collection[0].name if obj.users_collection else ""
 
+                width, height, font_size = get_text_box_size(obj)
+
                 # Write row
-                writer.writerow([obj.name, col_name, obj.data.body])
+                writer.writerow([obj.name, col_name, obj.data.body,
+                                 f"{width:.4f}", f"{height:.4f}", f"{font_size:.4f}"])
 
         self.report(
             {'INFO'}, f"Exported {len(text_objects)} TEXT objects to {self.filepath}")
@@ -0,0 +1,133 @@
+//! The services the prompts are sent to. The pipeline only talks to [`Backend`]s, so other
+//! vendors, mocks or replays of recorded answers can be added by implementing it and passing
+//! it to [`crate::translator::Translator::register_backend`].
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+use crate::open_ai::{self, AiSettings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    pub role: Role,
+    pub content: &'a str,
+}
+
+/// Tokens used by a request, as reported by the backend.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// Why the backend stopped generating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The answer is complete.
+    Stop,
+    /// The answer was cut off by the token limit.
+    Length,
+    /// The answer was withheld or cut off by a content filter.
+    ContentFilter,
+    Other(String),
+}
+
+impl FinishReason {
+    pub fn parse(reason: &str) -> FinishReason {
+        match reason {
+            "stop" | "end_turn" | "stop_sequence" => FinishReason::Stop,
+            "length" | "max_tokens" => FinishReason::Length,
+            "content_filter" => FinishReason::ContentFilter,
+            _ => FinishReason::Other(reason.to_string()),
+        }
+    }
+}
+
+/// The answer to a request.
+#[derive(Debug, Default)]
+pub struct Completion {
+    pub text: String,
+    pub usage: Option<Usage>,
+    pub finish_reason: Option<FinishReason>,
+}
+
+pub type CompletionFuture<'a> = BoxFuture<'a, Result<Completion, Box<dyn std::error::Error>>>;
+
+pub type EmbeddingsFuture<'a> = BoxFuture<'a, Result<Vec<Vec<f32>>, Box<dyn std::error::Error>>>;
+
+/// Something that answers chat messages. Backends must honour `settings.timeout_secs`, failing
+/// with `Error::Timeout` when it's exceeded.
+pub trait Backend: Send + Sync {
+    /// Name to select it with --backend.
+    fn name(&self) -> &str;
+
+    /// Sends the messages and waits for the whole answer.
+    fn send<'a>(
+        &'a self,
+        settings: &'a AiSettings<'_>,
+        messages: &'a [Message<'a>],
+    ) -> CompletionFuture<'a>;
+
+    /// Like `send`, but calls `on_text` with each piece of the answer as it arrives (see
+    /// --stream). Backends that can't stream call it once, with the whole answer.
+    fn stream<'a>(
+        &'a self,
+        settings: &'a AiSettings<'_>,
+        messages: &'a [Message<'a>],
+        on_text: &'a mut (dyn FnMut(&str) + Send),
+    ) -> CompletionFuture<'a> {
+        async move {
+            let completion = self.send(settings, messages).await?;
+            on_text(&completion.text);
+            Ok(completion)
+        }
+        .boxed()
+    }
+
+    /// The embedding of each of `texts`, in the same order, computed by `model` at `endpoint`
+    /// (see --embeddings-endpoint). Backends without embeddings fail.
+    fn embed<'a>(
+        &'a self,
+        _settings: &'a AiSettings<'_>,
+        _endpoint: &'a str,
+        _model: &'a str,
+        _texts: &'a [&'a str],
+    ) -> EmbeddingsFuture<'a> {
+        let error = format!("The {} backend has no embeddings", self.name());
+        async move { Err(error.into()) }.boxed()
+    }
+}
+
+/// The backends available out of the box.
+pub fn builtin() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(open_ai::OpenAi::new())]
+}
+
+/// The first of `backends` named `name`.
+pub fn find<'a>(backends: &'a [Box<dyn Backend>], name: &str) -> Result<&'a dyn Backend, String> {
+    backends
+        .iter()
+        .find(|b| b.name() == name)
+        .map(|b| b.as_ref())
+        .ok_or_else(|| {
+            let names: Vec<&str> = backends.iter().map(|b| b.name()).collect();
+            format!("Unknown backend {}. Available: {}", name, names.join(", "))
+        })
+}
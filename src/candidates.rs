@@ -0,0 +1,102 @@
+use std::{fmt, str::FromStr};
+
+use crate::similarity::{self, Score, Scorer};
+
+/// Context and batch settings used to produce one candidate translation.
+/// Written as "PRE/BATCH/POS", e.g. "2/6/2".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidateConfig {
+    pub pre_ctx: u16,
+    pub batch_size: u16,
+    pub pos_ctx: u16,
+}
+
+impl FromStr for CandidateConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').map(str::trim).collect();
+        let err = || {
+            format!(
+                "Invalid candidate '{}'. Expected PRE/BATCH/POS, e.g. 2/6/2",
+                s
+            )
+        };
+        if parts.len() != 3 {
+            return Err(err());
+        }
+        let config = CandidateConfig {
+            pre_ctx: parts[0].parse().map_err(|_| err())?,
+            batch_size: parts[1].parse().map_err(|_| err())?,
+            pos_ctx: parts[2].parse().map_err(|_| err())?,
+        };
+        if config.batch_size == 0 {
+            return Err(err());
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for CandidateConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.pre_ctx, self.batch_size, self.pos_ctx)
+    }
+}
+
+/// How to pick the best candidate of each entry.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// The candidate whose back translation is closest to the original. Requires --src-lang.
+    #[default]
+    BackTranslation,
+    /// The candidate with the best accuracy + fluency given by the reviewer (see --review-model).
+    Judge,
+    /// The candidate most similar to all the others (i.e. the one most candidates agree with).
+    Majority,
+}
+
+/// Index of the candidate most similar to the rest. Empty candidates (e.g. the AI gave up)
+/// are never picked unless all of them are empty.
+pub fn pick_majority(texts: &[&str]) -> usize {
+    let mut best = 0;
+    let mut best_agreement = f64::MIN;
+    for (i, text) in texts.iter().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let agreement: f64 = texts
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, other)| similarity::chrf(text, other) + similarity::chrf(other, text))
+            .sum();
+        if agreement > best_agreement {
+            best = i;
+            best_agreement = agreement;
+        }
+    }
+    best
+}
+
+/// Index of the candidate with the best back translation score.
+pub fn pick_by_score(scores: &[Score]) -> usize {
+    let mut best = 0;
+    for (i, score) in scores.iter().enumerate().skip(1) {
+        if Scorer::is_better(score, &scores[best]) {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Index of the candidate with the highest value (e.g. the reviewer's accuracy + fluency).
+/// Candidates without a value come last.
+pub fn pick_highest(values: &[Option<f64>]) -> usize {
+    let mut best = 0;
+    for (i, value) in values.iter().enumerate().skip(1) {
+        if value.unwrap_or(f64::MIN) > values[best].unwrap_or(f64::MIN) {
+            best = i;
+        }
+    }
+    best
+}
@@ -0,0 +1,85 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, fmt::Write, fs::File, io::Read};
+
+/// Description of a character. All fields are optional.
+/// Free-form values (e.g. "17" or 17 for the age) are accepted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CharacterProfile {
+    pub gender: Option<Value>,
+    pub age: Option<Value>,
+    pub personality: Option<Value>,
+    pub speech_style: Option<Value>,
+    /// How they address others, e.g. "Calls Anna 'Anna-senpai'. Uses 'omae' with John".
+    pub addresses_others: Option<Value>,
+    pub formality: Option<Value>,
+    pub notes: Option<Value>,
+}
+
+/// Character profiles, keyed by speaker name (as it appears in the Collection column).
+///
+/// ```json
+/// {
+///     "Anna": { "gender": "female", "age": 17, "personality": "Cheerful", "formality": "casual" },
+///     "John": { "gender": "male", "speech_style": "Talks like a pirate" }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Characters {
+    profiles: HashMap<String, CharacterProfile>,
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+impl Characters {
+    pub fn load(path: &str) -> Result<Characters, Box<dyn std::error::Error>> {
+        let mut json_str = String::new();
+        File::open(path)?.read_to_string(&mut json_str)?;
+        Ok(Characters {
+            profiles: serde_json::from_str(&json_str)?,
+        })
+    }
+
+    /// Appends the profiles of the given speakers to the prompt, in order of appearance.
+    /// Unknown speakers are skipped. Nothing is written if no speaker has a profile.
+    pub fn write_prompt<'a>(&self, prompt: &mut String, speakers: impl Iterator<Item = &'a str>) {
+        let mut seen: Vec<&str> = Vec::new();
+        for speaker in speakers {
+            if !seen.contains(&speaker) && self.profiles.contains_key(speaker) {
+                seen.push(speaker);
+            }
+        }
+
+        if seen.is_empty() {
+            return;
+        }
+
+        writeln!(prompt, "# CHARACTERS BEGIN").unwrap();
+        for speaker in seen {
+            let profile = &self.profiles[speaker];
+            writeln!(prompt, "## {}", speaker).unwrap();
+
+            let fields = [
+                ("Gender", &profile.gender),
+                ("Age", &profile.age),
+                ("Personality", &profile.personality),
+                ("Speech style", &profile.speech_style),
+                ("Addresses others", &profile.addresses_others),
+                ("Formality", &profile.formality),
+                ("Notes", &profile.notes),
+            ];
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    writeln!(prompt, "{}: {}", name, value_to_string(value)).unwrap();
+                }
+            }
+        }
+        writeln!(prompt, "# CHARACTERS END").unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+//! The command line: the subcommands and their flags.
+
+use serde_json::Value;
+use std::{env, fs::File, io::Read};
+
+use crate::{
+    backend, candidates, convert, dedup, estimate, format, merge, open_ai, scenes, similarity,
+    terms, validation,
+};
+
+/// Translate dialogue (CSV, subtitles) and key/value spreadsheets (ODS) with an LLM,
+/// using the surrounding lines as context.
+#[derive(clap::Parser, Debug)]
+#[command(version, about, long_about = None, args_override_self = true)]
+pub struct Cli {
+    /// Project file (TOML) with the default value of any of the flags, e.g. endpoint, model
+    /// and prompts. Flags given in the command line override it.
+    /// Defaults to context_translate.toml, if it exists in the working directory.
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Named profile of the project file to use (e.g. "fast-local"). Its settings override
+    /// the top-level ones.
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Translate a CSV, a subtitle file (.srt, .vtt, .ass) or an ODS spreadsheet.
+    Translate(Box<Args>),
+    /// Translate the output (CSV) of a previous run back to the source language and score it.
+    BackTranslate(BackTranslateArgs),
+    /// Check the output of a previous run (glossary, placeholders, line count, length limits,
+    /// formality) without the AI.
+    Validate(validation::ValidateArgs),
+    /// Estimate how many requests and tokens a translation needs, without the AI.
+    Estimate(estimate::EstimateArgs),
+    /// Merge or compare the outputs of several runs.
+    Merge(merge::MergeArgs),
+    /// Convert translations between formats: CSV, ODS, TMX and translation memories.
+    Convert(convert::ConvertArgs),
+    /// Find speakers and recurring terms and ask the AI for consistent translations,
+    /// written as a glossary CSV.
+    Glossary(terms::GlossaryArgs),
+}
+
+/// Connection to the LLM.
+#[derive(clap::Args, Debug, Clone)]
+pub struct AiArgs {
+    /// OpenAI API key. You can also set the OPENAI_API_KEY environment variable. Cmd line is higher priority.
+    #[arg(short, long)]
+    pub api_key: Option<String>,
+    /// LLM Model to use. e.g. "mistralai_Mistral-Small-3.1-24B-Instruct-2503-Q4_K_M.gguf"
+    #[arg(short, long)]
+    pub model: String,
+
+    /// URI to API endpoint, for example https://api.openai.com/v1/chat/completions or
+    /// http://127.0.0.1:8081/v1/chat/completions
+    #[arg(short, long)]
+    pub endpoint: String,
+
+    /// Path to JSON file to customize more options (like temperature, top_p, etc).
+    #[arg(long, short, value_hint = clap::ValueHint::FilePath)]
+    pub llm_options: Option<String>,
+
+    /// Timeout in seconds for each batch before considering it an AI error.
+    #[arg(long)]
+    pub timeout_secs: u64,
+
+    /// Show prompt in stdio.
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Service the prompts are sent to. "openai" works with any OpenAI-compatible endpoint
+    /// (OpenAI, llama.cpp, vLLM, ...). Programs using the library can register more.
+    #[arg(long, default_value = "openai")]
+    pub backend: String,
+
+    /// Receive the answers as they're generated (shown with --debug). --timeout-secs then
+    /// applies to the wait for each piece of the answer, so long batches aren't cut off.
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Where the answers of the AI that couldn't be parsed are written, along with their
+    /// prompts. With several --dst-lang, each language gets its own (errors.es.log, ...).
+    #[arg(long, default_value = "errors.log", value_hint = clap::ValueHint::FilePath)]
+    pub error_log: String,
+}
+
+impl AiArgs {
+    /// Contents of --llm-options. Kept apart from `settings` as AiSettings borrows them.
+    pub fn llm_options(&self) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        match &self.llm_options {
+            Some(llm_options_path) => {
+                let mut file = File::open(llm_options_path)?;
+                let mut json_str = String::new();
+                file.read_to_string(&mut json_str)?;
+                Ok(Some(serde_json::from_str(&json_str)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The settings to send prompts to the --backend, chosen among `backends`.
+    pub fn settings<'a>(
+        &self,
+        system_prompt: String,
+        extra_options: &'a Option<Value>,
+        backends: &'a [Box<dyn backend::Backend>],
+    ) -> Result<open_ai::AiSettings<'a>, Box<dyn std::error::Error>> {
+        // Read API key from environment variable
+        let api_key = match self.api_key {
+            Some(ref s) => s.to_string(),
+            None => env::var("OPENAI_API_KEY").map_err(|_| {
+                "Please set the OPENAI_API_KEY environment variable or via command line argument. try '--help'"
+            })?,
+        };
+
+        Ok(open_ai::AiSettings {
+            endpoint: self.endpoint.clone(),
+            api_key,
+            system_prompt,
+            model: self.model.clone(),
+            timeout_secs: self.timeout_secs,
+            extra_options: extra_options.as_ref().and_then(|o| o.as_object()),
+            debug: self.debug,
+            backend: backend::find(backends, &self.backend)?,
+            stream: self.stream,
+        })
+    }
+}
+
+/// Settings of a translation run.
+#[derive(clap::Args, Debug, Clone)]
+#[command(args_override_self = true)]
+pub struct Args {
+    #[command(flatten)]
+    pub ai: AiArgs,
+
+    /// Path to the system prompt location.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub system_prompt: String,
+
+    /// Source Language, as a BCP-47 tag (e.g. "en-US") or a name (e.g. "English").
+    /// Can be left blank to auto-detect BUT "translation back" won't be available.
+    /// "translation back" is very helpful for diagnosing if the translated text retained its original meaning.
+    /// Highly recommended.
+    #[arg(short, long)]
+    pub src_lang: Option<String>,
+    /// Destination Language to translate to, as a BCP-47 tag (e.g. "es-AR", "zh-Hant-TW") or a name.
+    /// Tags are validated, and their locale conventions (quotes, punctuation, plural categories)
+    /// are sent to the AI and stamped in the output. Separate several with commas (e.g. "es,fr,de")
+    /// to translate to all of them: each gets its own output file (e.g. out.es.csv),
+    /// and ODS outputs also get one column per language.
+    #[arg(short, long)]
+    pub dst_lang: String,
+
+    /// File to translate. The format is detected from its extension: a semicolon-separated
+    /// CSV, a subtitle file (.srt, .vtt, .ass) or an ODS spreadsheet (.ods, key/value mode).
+    #[arg(short, long, alias = "src-csv", value_hint = clap::ValueHint::FilePath)]
+    pub input: String,
+    /// Output file. The format is detected from its extension too: CSV, ODS, or a subtitle
+    /// file (only if --input is one). Anything else is written as CSV.
+    #[arg(short, long, alias = "dst-csv", value_hint = clap::ValueHint::FilePath)]
+    pub output: String,
+
+    /// Translate the entries as lines of dialogue (with speakers and the surrounding lines as
+    /// context) or as independent keys (with the --ods-columns as context). By default, ODS
+    /// files are translated as keys and everything else as dialogue.
+    #[arg(long, value_enum)]
+    pub pipeline: Option<format::Pipeline>,
+
+    #[command(flatten)]
+    pub batch: BatchArgs,
+
+    #[command(flatten)]
+    pub read: ReadArgs,
+
+    /// Glossary with fixed translations for names, places, items, etc.
+    /// Either a semicolon-separated CSV with "Source;Target;DNT;Notes" columns or a TBX file.
+    /// Terms marked as DNT (do-not-translate) must be kept in the source language.
+    /// Only the entries that appear in each batch are sent to the AI.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub glossary: Option<String>,
+
+    /// What to do when a translation doesn't use the glossary's target term,
+    /// or alters a do-not-translate term.
+    #[arg(long, value_enum, default_value_t = validation::CheckPolicy::Warn)]
+    pub glossary_check: validation::CheckPolicy,
+
+    /// Translation memory (SQLite file, created if it doesn't exist). Lines translated before
+    /// are reused without asking the AI, and similar ones are sent to it as reference.
+    /// Accepted translations (those that passed every check) are recorded for the next runs.
+    /// Requires --src-lang.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub memory: Option<String>,
+
+    /// Minimum similarity (chrF, 0 to 100) for a translation memory entry to be sent as reference.
+    #[arg(long, default_value_t = 70.0)]
+    pub memory_threshold: f64,
+
+    /// With several --dst-lang, translate to all of them at the same time.
+    #[arg(long)]
+    pub parallel_languages: bool,
+
+    /// Approved translations to retrieve few-shot examples from: a TMX file, or the output
+    /// (CSV, or ODS in ODS mode) of a previous run. The most similar ones to each batch are
+    /// sent to the AI, found with --embeddings-endpoint if set, or with BM25 otherwise.
+    /// With several --dst-lang, the output of each language is used (e.g. ex.csv -> ex.fr.csv).
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub examples: Option<String>,
+
+    /// How many examples are retrieved per line (see --examples).
+    #[arg(long, default_value_t = 3)]
+    pub num_examples: usize,
+
+    /// JSON file describing the characters (speakers): gender, age, personality, speech style,
+    /// how they address others and formality level.
+    /// Only the profiles of speakers present in each batch and its context are sent to the AI.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub characters: Option<String>,
+
+    /// JSON file with formality, honorific and register policies: how to address the listener
+    /// (formal, informal, voseo) and what to do with Japanese honorifics (keep, drop, adapt),
+    /// globally and per speaker or speaker pair. They're sent to the AI and checked.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub formality: Option<String>,
+
+    /// What to do when a translation obviously breaks the --formality policies
+    /// (e.g. "tú" when the address must be formal, or "-san" when honorifics must be dropped).
+    #[arg(long, value_enum, default_value_t = validation::CheckPolicy::Warn)]
+    pub formality_check: validation::CheckPolicy,
+
+    /// Swap placeholders and markup ({0}, %s, %1$d, <b>, [color=red], \n escapes, $VARIABLE$)
+    /// for opaque tokens before sending the text to the AI, and restore them afterwards.
+    #[arg(long)]
+    pub protect_placeholders: bool,
+
+    /// With --protect-placeholders, what to do when placeholders were lost, duplicated
+    /// or illegally reordered by the AI.
+    #[arg(long, value_enum, default_value_t = validation::CheckPolicy::Retry)]
+    pub placeholder_check: validation::CheckPolicy,
+
+    /// What to do when a translation doesn't have the same number of lines as the original.
+    /// Speech bubbles often depend on them.
+    #[arg(long, value_enum, default_value_t = validation::NewlinePolicy::Warn)]
+    pub newline_policy: validation::NewlinePolicy,
+
+    /// Maximum number of characters per line for every entry.
+    /// Entries can override it with a "Max Chars" column in the CSV.
+    #[arg(long)]
+    pub max_chars: Option<usize>,
+
+    /// Maximum display width per line for every entry, where CJK and other full-width
+    /// characters count as 2. Entries can override it with a "Max Width" column in the CSV.
+    #[arg(long)]
+    pub max_width: Option<usize>,
+
+    /// What to do when a translation exceeds the max chars / max width limits.
+    #[arg(long, value_enum, default_value_t = validation::CheckPolicy::Retry)]
+    pub length_check: validation::CheckPolicy,
+
+    /// Maximum reading speed in characters per second for subtitles (e.g. 17).
+    /// Needs cue durations: either a subtitle file (.srt, .vtt, .ass) as --input,
+    /// or a "Length" column (in frames) in the CSV.
+    #[arg(long)]
+    pub max_cps: Option<f32>,
+
+    /// Use common subtitle guidelines for the destination language as the default
+    /// --max-cps and --max-chars (e.g. 17 CPS / 42 CPL for most languages, 4 CPS / 13 CPL for Japanese).
+    #[arg(long)]
+    pub subtitle_defaults: bool,
+
+    #[command(flatten)]
+    pub back_score: BackScoreArgs,
+
+    /// After translating, ask a reviewer model to evaluate each translation (accuracy, fluency,
+    /// detected errors and a suggested fix). Results are written to extra columns
+    /// (or an extra "review" sheet in ODS mode).
+    #[arg(long)]
+    pub review: bool,
+
+    /// Model used by --review. Defaults to --model.
+    #[arg(long)]
+    pub review_model: Option<String>,
+
+    /// Endpoint used by --review. Defaults to --endpoint.
+    #[arg(long)]
+    pub review_endpoint: Option<String>,
+
+    /// Translate several times with different settings and pick the best translation of each line.
+    /// Comma-separated list of PRE/BATCH/POS (--pre-ctx, --batch-size, --pos-ctx),
+    /// e.g. "2/6/2,6/6/6,4/2/4". Repeat a configuration to get several samples of it.
+    /// The other candidates are written to the Alternatives column. Not available in ODS mode.
+    #[arg(long, value_delimiter = ',')]
+    pub candidates: Vec<candidates::CandidateConfig>,
+
+    /// How to pick the best candidate (see --candidates).
+    #[arg(long, value_enum, default_value_t = candidates::Selection::BackTranslation)]
+    pub select: candidates::Selection,
+
+    /// Output CSV (or ODS in ODS mode) of a previous run. Lines whose source text didn't change
+    /// (matched by datablock_name or key, or by their text if they don't have one) keep their
+    /// previous translation, and only new or modified lines are sent to the AI (still with the
+    /// surrounding context). With several --dst-lang, the output of each language is used
+    /// (e.g. out.csv -> out.fr.csv).
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub previous: Option<String>,
+
+    /// Translate repeated lines only once and copy the translation to the other occurrences.
+    #[arg(long, value_enum, default_value_t = dedup::DedupPolicy::Off)]
+    pub dedup: dedup::DedupPolicy,
+
+    #[command(flatten)]
+    pub scenes: SceneArgs,
+
+    /// When --input is a subtitle file, also write the translated subtitles to this path,
+    /// keeping the original timings and styles.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub dst_subtitles: Option<String>,
+}
+
+/// How the lines are grouped into prompts.
+#[derive(clap::Args, Debug, Clone)]
+pub struct BatchArgs {
+    /// How many lines to translate per AI prompt. Higher values translate faster,
+    /// but has a higher chance of being inaccurate or hallucinating.
+    /// Extremely high values may cause performance issues due to LLM context window handling.
+    #[arg(short, long, default_value_t = 6, value_parser = clap::value_parser!(u16).range(1..))]
+    pub batch_size: u16,
+
+    /// How many preceeding lines to send alongside the batch as context.
+    /// Very low values may result in less accurate translations.
+    /// If increasing this too much, consider raising batch-size instead.
+    #[arg(long, default_value_t = 3)]
+    pub pre_ctx: u16,
+
+    /// How many subsequent lines to send alongside the batch as context.
+    /// Very low values may result in less accurate translations.
+    /// If increasing this too much, consider raising batch-size instead.
+    #[arg(long, default_value_t = 3)]
+    pub pos_ctx: u16,
+}
+
+impl BatchArgs {
+    /// The context sent around each batch, which never crosses `scenes`.
+    pub fn window<'a>(&self, scenes: &'a [std::ops::Range<usize>]) -> scenes::ContextWindow<'a> {
+        scenes::ContextWindow {
+            scenes,
+            pre: self.pre_ctx as usize,
+            pos: self.pos_ctx as usize,
+        }
+    }
+}
+
+/// How --input is read.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReadArgs {
+    /// With an ODS --input, comma-separated 0-based columns of its "all" sheet.
+    /// The first column is the source language, the other columns add additional context.
+    #[arg(long, alias = "ods-key-mode-columns", default_value = "1")]
+    pub ods_columns: String,
+
+    /// In ODS mode, 0-based column containing the max chars per line of each entry.
+    #[arg(long)]
+    pub ods_max_chars_column: Option<u32>,
+
+    /// In ODS mode, 0-based column containing the max display width per line of each entry.
+    #[arg(long)]
+    pub ods_max_width_column: Option<u32>,
+
+    /// Frame rate used to convert the "Length" column to seconds.
+    #[arg(long, default_value_t = 24.0)]
+    pub fps: f64,
+}
+
+impl ReadArgs {
+    pub fn read_options(&self) -> format::ReadOptions {
+        format::ReadOptions {
+            fps: self.fps,
+            ods_columns: self.ods_columns.clone(),
+            ods_max_chars_column: self.ods_max_chars_column,
+            ods_max_width_column: self.ods_max_width_column,
+        }
+    }
+}
+
+/// Where the lines are split into scenes.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SceneArgs {
+    /// Begin a new scene when there are more than this many seconds between two cues.
+    /// Batches and context never cross scenes. Needs timings: a subtitle file
+    /// or "From"/"Length" columns (in frames) in the CSV.
+    /// A "Scene" column in the CSV also begins a new scene whenever its value changes.
+    #[arg(long)]
+    pub scene_gap: Option<f64>,
+
+    /// Rows with an empty speaker (e.g. comments) begin a new scene.
+    #[arg(long)]
+    pub scene_markers: bool,
+}
+
+/// How back translations are scored against the original.
+#[derive(clap::Args, Debug, Clone)]
+pub struct BackScoreArgs {
+    /// Entries whose back translation has a chrF score (0-100) below this value are flagged.
+    /// Requires --src-lang.
+    #[arg(long)]
+    pub back_score_threshold: Option<f64>,
+
+    /// OpenAI-compatible embeddings endpoint (e.g. http://127.0.0.1:8081/v1/embeddings).
+    /// If set, the embedding similarity between the original and its back translation
+    /// is also computed, and --examples are retrieved with embeddings instead of BM25.
+    #[arg(long)]
+    pub embeddings_endpoint: Option<String>,
+
+    /// Model to use with --embeddings-endpoint. Defaults to --model.
+    #[arg(long)]
+    pub embeddings_model: Option<String>,
+
+    /// Entries whose back translation has an embedding similarity (0-100) below this value
+    /// are flagged. Requires --embeddings-endpoint.
+    #[arg(long)]
+    pub back_similarity_threshold: Option<f64>,
+
+    /// What to do with entries below --back-score-threshold / --back-similarity-threshold.
+    /// retry translates them again (and back) keeping the best scoring attempt.
+    /// In ODS mode retry behaves like warn.
+    #[arg(long, value_enum, default_value_t = validation::CheckPolicy::Warn)]
+    pub back_score_check: validation::CheckPolicy,
+}
+
+impl BackScoreArgs {
+    /// `model` is the --model, used when there's no --embeddings-model.
+    pub fn scorer(&self, model: &str) -> similarity::Scorer {
+        similarity::Scorer {
+            embeddings_endpoint: self.embeddings_endpoint.clone(),
+            embeddings_model: self
+                .embeddings_model
+                .clone()
+                .unwrap_or_else(|| model.to_string()),
+            chrf_threshold: self.back_score_threshold,
+            embedding_threshold: self.back_similarity_threshold,
+            policy: self.back_score_check,
+        }
+    }
+}
+
+/// Settings of the back translation of a previous run.
+#[derive(clap::Args, Debug, Clone)]
+#[command(args_override_self = true)]
+pub struct BackTranslateArgs {
+    #[command(flatten)]
+    pub ai: AiArgs,
+
+    /// Path to the system prompt location.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub system_prompt: String,
+
+    /// Language of the original text, which the translation is translated back to.
+    /// As a BCP-47 tag (e.g. "en-US") or a name (e.g. "English").
+    #[arg(short, long)]
+    pub src_lang: String,
+    /// Language the previous run translated to, as a BCP-47 tag (e.g. "es-AR") or a name.
+    #[arg(short, long)]
+    pub dst_lang: String,
+
+    /// Output of the previous run. The original text is taken from its Original column.
+    #[arg(short, long, alias = "src-csv", value_hint = clap::ValueHint::FilePath)]
+    pub input: String,
+    /// Output file: --input with the Original Back and score columns filled in.
+    #[arg(short, long, alias = "dst-csv", value_hint = clap::ValueHint::FilePath)]
+    pub output: String,
+
+    #[command(flatten)]
+    pub batch: BatchArgs,
+
+    #[command(flatten)]
+    pub read: ReadArgs,
+
+    /// Swap placeholders and markup ({0}, %s, %1$d, <b>, [color=red], \n escapes, $VARIABLE$)
+    /// for opaque tokens before sending the text to the AI, and restore them afterwards.
+    #[arg(long)]
+    pub protect_placeholders: bool,
+
+    #[command(flatten)]
+    pub back_score: BackScoreArgs,
+
+    #[command(flatten)]
+    pub scenes: SceneArgs,
+}
+
+impl Args {
+    /// Parses the flags of the `translate` command, e.g. `["--dst-lang", "fr", ...]`.
+    pub fn from_flags<I, T>(flags: I) -> Result<Args, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        use clap::{Args as _, FromArgMatches};
+        let command = Args::augment_args(clap::Command::new("translate"));
+        let argv = std::iter::once("translate".into()).chain(flags.into_iter().map(Into::into));
+        Args::from_arg_matches(&command.try_get_matches_from(argv)?)
+    }
+}
@@ -0,0 +1,391 @@
+use clap::{ArgAction, CommandFactory, Parser, ValueHint};
+use log::info;
+use std::{ffi::OsString, path::Path};
+use toml::{Table, Value};
+
+use crate::Cli;
+
+/// Project file used when --config isn't given, if it exists in the working directory.
+pub const DEFAULT_PATH: &str = "context_translate.toml";
+
+/// Parses the command line on top of the settings of the project file (see --config).
+///
+/// The file's keys are the names of the command line flags (e.g. `pre-ctx` or `pre_ctx`).
+/// Each subcommand takes the settings it has a flag for and ignores the rest, so the same file
+/// serves e.g. `translate` and `glossary`.
+/// Named profiles under `[profiles.<name>]` override the top-level settings when selected
+/// with --profile, and the command line overrides both.
+/// `<setting>-env = "VAR"` reads the value of a setting (e.g. a secret such as `api-key`) from
+/// the environment variable VAR, if it's set.
+/// Relative paths are relative to the directory of the file.
+/// A flag turned on in the file (e.g. `review = true`) is turned off with `--no-<flag>`.
+/// Flags with several values (e.g. `candidates`) given in the command line replace the ones of
+/// the file instead of adding to them.
+///
+/// ```toml
+/// endpoint = "http://127.0.0.1:8081/v1/chat/completions"
+/// system-prompt = "examples/manga/system_prompt.txt"
+/// api-key-env = "OPENAI_API_KEY"
+///
+/// [profiles.fast-local]
+/// model = "mistralai_Mistral-Small-3.1-24B-Instruct-2503-Q4_K_M.gguf"
+/// batch-size = 10
+/// ```
+pub fn parse_args() -> Result<Cli, Box<dyn std::error::Error>> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let profile = flag_value(&cli, "profile");
+
+    // Without a subcommand there's nothing to apply the settings to. Let clap explain it.
+    let Some((position, subcommand)) = find_subcommand(&cli) else {
+        return Ok(Cli::parse_from(cli));
+    };
+    let (cli, negated) = take_negations(cli, &subcommand);
+
+    let path = match flag_value(&cli, "config") {
+        Some(path) => path,
+        None if Path::new(DEFAULT_PATH).exists() => DEFAULT_PATH.to_string(),
+        None => {
+            if profile.is_some() {
+                return Err(format!(
+                    "--profile needs a project file (--config or {})",
+                    DEFAULT_PATH
+                )
+                .into());
+            }
+            return Ok(Cli::parse_from(cli));
+        }
+    };
+
+    info!("Opening Project File {}", path);
+    let settings = load(&path, profile.as_deref())?;
+
+    // The settings go right after the subcommand, so the flags given after them win.
+    let mut argv = cli[..=position].to_vec();
+    argv.extend(
+        to_flags(&path, settings, &subcommand, &cli[position + 1..], &negated)?
+            .into_iter()
+            .map(OsString::from),
+    );
+    argv.extend(cli[position + 1..].iter().cloned());
+    Ok(Cli::parse_from(argv))
+}
+
+/// Position and name of the subcommand in the command line.
+fn find_subcommand(cli: &[OsString]) -> Option<(usize, String)> {
+    let command = Cli::command();
+    let mut skip_value = false;
+    for (i, arg) in cli.iter().enumerate().skip(1) {
+        let arg = arg.to_string_lossy();
+        if std::mem::take(&mut skip_value) {
+            continue;
+        }
+        if arg == "--config" || arg == "--profile" {
+            skip_value = true;
+        } else if command.find_subcommand(arg.as_ref()).is_some() {
+            return Some((i, arg.to_string()));
+        }
+    }
+    None
+}
+
+/// Removes the `--no-<flag>` of the boolean flags of `subcommand` from the command line.
+/// Returns the rest of it and the names of those flags.
+fn take_negations(cli: Vec<OsString>, subcommand: &str) -> (Vec<OsString>, Vec<String>) {
+    let mut command = Cli::command();
+    command.build();
+    let target = command.find_subcommand(subcommand).unwrap();
+    let is_switch = |name: &str| {
+        target
+            .get_arguments()
+            .any(|a| a.get_long() == Some(name) && matches!(a.get_action(), ArgAction::SetTrue))
+    };
+
+    let mut negated = Vec::new();
+    let mut rest = Vec::with_capacity(cli.len());
+    for arg in cli {
+        match arg.to_str().and_then(|a| a.strip_prefix("--no-")) {
+            Some(name) if is_switch(name) => negated.push(name.to_string()),
+            _ => rest.push(arg),
+        }
+    }
+    (rest, negated)
+}
+
+/// Whether the command line has the flag `arg`, in its long or short form.
+fn is_given(cli: &[OsString], arg: &clap::Arg) -> bool {
+    cli.iter().any(|a| {
+        let a = a.to_string_lossy();
+        let long = arg
+            .get_long()
+            .and_then(|l| a.strip_prefix("--")?.strip_prefix(l));
+        let short = arg
+            .get_short()
+            .and_then(|s| a.strip_prefix('-')?.strip_prefix(s));
+        matches!(long, Some(rest) if rest.is_empty() || rest.starts_with('='))
+            || (short.is_some() && !a.starts_with("--"))
+    })
+}
+
+/// Value of `--name value` or `--name=value` in the command line.
+fn flag_value(cli: &[OsString], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    cli.iter().enumerate().find_map(|(i, arg)| {
+        let arg = arg.to_string_lossy();
+        if arg == flag {
+            cli.get(i + 1).map(|v| v.to_string_lossy().to_string())
+        } else {
+            arg.strip_prefix(&prefix).map(str::to_string)
+        }
+    })
+}
+
+/// The top-level settings of the file, overridden by those of `profile`.
+fn load(path: &str, profile: Option<&str>) -> Result<Table, Box<dyn std::error::Error>> {
+    let mut settings: Table = std::fs::read_to_string(path)?.parse()?;
+    let profiles = match settings.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(format!("'profiles' in {} must be a table", path).into()),
+        None => Table::new(),
+    };
+
+    if let Some(profile) = profile {
+        match profiles.get(profile) {
+            Some(Value::Table(overrides)) => settings.extend(overrides.clone()),
+            _ => {
+                let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                return Err(format!(
+                    "Profile '{}' not found in {}. Available: {}",
+                    profile,
+                    path,
+                    names.join(", ")
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Turns the settings into command line flags of `subcommand`.
+/// Settings that only other subcommands have, the `negated` flags, and the flags with several
+/// values that are also in `cli` (the command line after the subcommand) are skipped.
+fn to_flags(
+    path: &str,
+    settings: Table,
+    subcommand: &str,
+    cli: &[OsString],
+    negated: &[String],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut command = Cli::command();
+    command.build();
+    let has_flag = |command: &clap::Command, name: &str| {
+        command.get_arguments().any(|a| a.get_long() == Some(name))
+    };
+    let target = command.find_subcommand(subcommand).unwrap();
+
+    let mut flags = Vec::new();
+    for (key, value) in settings {
+        let mut name = key.replace('_', "-");
+        let from_env = name.ends_with("-env");
+        let value = match name.strip_suffix("-env") {
+            Some(setting) => {
+                let Value::String(var) = &value else {
+                    return Err(format!("'{}' in {} must be a variable name", key, path).into());
+                };
+                let Ok(value) = std::env::var(var) else {
+                    continue;
+                };
+                name = setting.to_string();
+                Value::String(value)
+            }
+            None => value,
+        };
+
+        if name == "config" || name == "profile" {
+            return Err(format!("'{}' can't be set in {}", key, path).into());
+        }
+        let Some(arg) = target
+            .get_arguments()
+            .find(|a| a.get_long() == Some(name.as_str()))
+        else {
+            if command.get_subcommands().any(|c| has_flag(c, &name)) {
+                continue;
+            }
+            return Err(format!("Unknown setting '{}' in {}", key, path).into());
+        };
+        // Values of these flags add up, so the last source has to be the only one.
+        if matches!(arg.get_action(), ArgAction::Append) && is_given(cli, arg) {
+            continue;
+        }
+        let multiple_values = arg.get_num_args().is_some_and(|n| n.max_values() > 1);
+
+        // Paths from the environment are relative to the working directory, like the flags.
+        let is_path = arg.get_value_hint() == ValueHint::FilePath && !from_env;
+        let to_string = |value: &Value| match value {
+            Value::String(s) if is_path => Ok(dir.join(s).to_string_lossy().to_string()),
+            Value::String(s) => Ok(s.clone()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Float(f) => Ok(f.to_string()),
+            _ => Err(format!("Unsupported value for '{}' in {}", key, path)),
+        };
+        match &value {
+            Value::Boolean(true) if !negated.contains(&name) => flags.push(format!("--{}", name)),
+            Value::Boolean(_) => {}
+            Value::Array(items) => {
+                let items = items.iter().map(to_string).collect::<Result<Vec<_>, _>>()?;
+                if multiple_values {
+                    flags.push(format!("--{}", name));
+                    flags.extend(items);
+                } else {
+                    flags.push(format!("--{}={}", name, items.join(",")));
+                }
+            }
+            value => flags.push(format!("--{}={}", name, to_string(value)?)),
+        }
+    }
+
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+
+    const PROJECT: &str = r#"
+        endpoint = "http://127.0.0.1:8081/v1/chat/completions"
+        model = "big"
+        timeout-secs = 60
+        system-prompt = "prompts/system.txt"
+        input = "in.csv"
+        output = "out.csv"
+        dst-lang = "es"
+        review = true
+        candidates = ["2/6/2", "6/6/6"]
+        api-key-env = "PATH"
+        review-model-env = "CONTEXT_TRANSLATE_TEST_UNSET_VARIABLE"
+        min-term-occurrences = 3
+
+        [profiles.fast]
+        model = "small"
+        batch_size = 10
+    "#;
+
+    fn write_project(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("context_translate_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("project.toml");
+        std::fs::write(&path, PROJECT).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn remove_project(path: &str) {
+        std::fs::remove_dir_all(Path::new(path).parent().unwrap()).unwrap();
+    }
+
+    /// Parses `cli` (after "translate") on top of the project file, as `parse_args` does.
+    fn parse(path: &str, profile: Option<&str>, cli: &[&str]) -> crate::Args {
+        let cli: Vec<OsString> = ["context_translate", "translate"]
+            .iter()
+            .chain(cli)
+            .map(OsString::from)
+            .collect();
+        let (cli, negated) = take_negations(cli, "translate");
+        let settings = load(path, profile).unwrap();
+        let mut argv = cli[..2].to_vec();
+        let flags = to_flags(path, settings, "translate", &cli[2..], &negated).unwrap();
+        argv.extend(flags.into_iter().map(OsString::from));
+        argv.extend(cli[2..].iter().cloned());
+        match Cli::try_parse_from(argv).unwrap().command {
+            Command::Translate(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn profiles_override_the_top_level() {
+        let path = write_project("profiles");
+        let settings = load(&path, None).unwrap();
+        assert_eq!(settings["model"].as_str(), Some("big"));
+        assert!(!settings.contains_key("profiles"));
+
+        let settings = load(&path, Some("fast")).unwrap();
+        assert_eq!(settings["model"].as_str(), Some("small"));
+        assert_eq!(settings["batch_size"].as_integer(), Some(10));
+        assert_eq!(settings["timeout-secs"].as_integer(), Some(60));
+
+        let error = load(&path, Some("slow")).unwrap_err().to_string();
+        assert!(error.contains("Profile 'slow' not found"), "{}", error);
+        assert!(error.ends_with("Available: fast"), "{}", error);
+        remove_project(&path);
+    }
+
+    #[test]
+    fn settings_become_flags() {
+        let path = write_project("flags");
+        let dir = Path::new(&path).parent().unwrap();
+        let args = parse(&path, Some("fast"), &[]);
+        assert_eq!(args.ai.model, "small");
+        assert_eq!(args.batch.batch_size, 10);
+        assert!(args.review);
+        // Relative paths are relative to the project file, unlike the ones of the command line.
+        assert_eq!(
+            Path::new(&args.system_prompt),
+            dir.join("prompts/system.txt")
+        );
+        let args = parse(&path, None, &["--system-prompt", "other.txt"]);
+        assert_eq!(args.system_prompt, "other.txt");
+        assert_eq!(args.ai.model, "big");
+        remove_project(&path);
+    }
+
+    #[test]
+    fn settings_from_the_environment() {
+        let path = write_project("environment");
+        let args = parse(&path, None, &[]);
+        assert_eq!(args.ai.api_key, std::env::var("PATH").ok());
+        // Unset variables leave the setting out.
+        assert_eq!(args.review_model, None);
+        let args = parse(&path, None, &["--api-key", "secret"]);
+        assert_eq!(args.ai.api_key.as_deref(), Some("secret"));
+        remove_project(&path);
+    }
+
+    #[test]
+    fn negated_flags() {
+        let path = write_project("negated");
+        assert!(parse(&path, None, &[]).review);
+        assert!(!parse(&path, None, &["--no-review"]).review);
+        remove_project(&path);
+    }
+
+    #[test]
+    fn command_line_replaces_multiple_values() {
+        let path = write_project("multiple");
+        let args = parse(&path, None, &[]);
+        assert_eq!(args.candidates.len(), 2);
+        let args = parse(&path, None, &["--candidates", "4/2/4"]);
+        assert_eq!(args.candidates.len(), 1);
+        let args = parse(&path, None, &["--candidates=1/1/1,3/3/3,5/5/5"]);
+        assert_eq!(args.candidates.len(), 3);
+        remove_project(&path);
+    }
+
+    #[test]
+    fn unknown_and_forbidden_settings() {
+        let settings: Table = "min-term-occurrences = 3\nreview = true".parse().unwrap();
+        // Settings of other subcommands are skipped.
+        let flags = to_flags("p.toml", settings, "translate", &[], &[]).unwrap();
+        assert_eq!(flags, ["--review"]);
+
+        let settings: Table = "colour = 3".parse().unwrap();
+        let error = to_flags("p.toml", settings, "translate", &[], &[]).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown setting 'colour' in p.toml");
+        let settings: Table = "profile = \"fast\"".parse().unwrap();
+        assert!(to_flags("p.toml", settings, "translate", &[], &[]).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use icu_locale_core::locale;
+use log::info;
+use spreadsheet_ods::{CompressionMethod, OdsWriteOptions, Sheet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::memory::{self, Memory, Unit};
+use crate::{AI_GAVE_UP, BlenderTextRow};
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// File to read. The format is detected from its extension: the output of a run
+    /// (.csv or .ods), a TMX file (.tmx) or a translation memory (.db, .sqlite, .sqlite3).
+    pub input: String,
+
+    /// File to write, in any of the formats of the input. Translation memories are
+    /// added to (and created if they don't exist).
+    pub output: String,
+
+    /// Source Language of the translations, as used in the TMX file or translation memory.
+    #[arg(short, long)]
+    pub src_lang: String,
+
+    /// Destination Language of the translations, as used in the TMX file or translation memory.
+    #[arg(short, long)]
+    pub dst_lang: String,
+}
+
+enum Format {
+    Csv,
+    Ods,
+    Tmx,
+    Memory,
+}
+
+fn format(path: &str) -> Result<Format, String> {
+    let lower = path.to_lowercase();
+    let extension = lower.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+    match extension {
+        "csv" => Ok(Format::Csv),
+        "ods" => Ok(Format::Ods),
+        "tmx" => Ok(Format::Tmx),
+        "db" | "sqlite" | "sqlite3" => Ok(Format::Memory),
+        _ => Err(format!(
+            "Unknown format of {} (expected .csv, .ods, .tmx, .db, .sqlite or .sqlite3)",
+            path
+        )),
+    }
+}
+
+/// Whether `path` is the output of a run (.csv or .ods), as opposed to a TMX file or a
+/// translation memory, which hold several language pairs.
+pub fn is_run_output(path: &str) -> bool {
+    matches!(format(path), Ok(Format::Csv | Format::Ods))
+}
+
+/// Reads the translations of `path` from `src_lang` to `dst_lang`, with their remarks
+/// (only the output of a run has them).
+/// Languages are only needed for TMX files and translation memories.
+fn read_remarked_units(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+) -> Result<Vec<(Unit, String)>, Box<dyn std::error::Error>> {
+    let without_remarks =
+        |units: Vec<Unit>| units.into_iter().map(|u| (u, String::new())).collect();
+    Ok(match format(path)? {
+        Format::Csv => crate::read_csv(path)?
+            .into_iter()
+            .map(|row| {
+                let unit = Unit {
+                    key: row.datablock_name,
+                    speaker: row.speaker,
+                    source: row.original.unwrap_or_default(),
+                    target: row.text,
+                };
+                (unit, row.remarks.unwrap_or_default())
+            })
+            .collect(),
+        Format::Ods => read_ods(path)?,
+        Format::Tmx => without_remarks(memory::read_tmx(path, src_lang, dst_lang)?),
+        Format::Memory => without_remarks(
+            Memory::open(path, src_lang, dst_lang, 0.0)?
+                .units()
+                .to_vec(),
+        ),
+    })
+}
+
+/// Reads the translations of `path` from `src_lang` to `dst_lang`.
+/// Languages are only needed for TMX files and translation memories.
+pub fn read_units(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+) -> Result<Vec<Unit>, Box<dyn std::error::Error>> {
+    Ok(read_remarked_units(path, src_lang, dst_lang)?
+        .into_iter()
+        .map(|(unit, _)| unit)
+        .collect())
+}
+
+/// Like `read_units`, but skips the translations of a run that have remarks or that the AI
+/// gave up on.
+pub fn read_approved_units(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+) -> Result<Vec<Unit>, Box<dyn std::error::Error>> {
+    Ok(read_remarked_units(path, src_lang, dst_lang)?
+        .into_iter()
+        .filter(|(unit, remarks)| remarks.trim().is_empty() && unit.target != AI_GAVE_UP)
+        .map(|(unit, _)| unit)
+        .collect())
+}
+
+/// Reads the "output" sheet written in ODS key mode, with the remarks.
+fn read_ods(path: &str) -> Result<Vec<(Unit, String)>, Box<dyn std::error::Error>> {
+    let book = spreadsheet_ods::read_ods(path)?;
+    let sheet = book.sheet(
+        book.sheet_idx("output")
+            .ok_or_else(|| format!("{} has no 'output' sheet", path))?,
+    );
+    let (num_rows, _) = sheet.used_grid_size();
+
+    Ok((1..num_rows)
+        .map(|row| {
+            let cell = |col| sheet.value(row, col).as_cow_str_or("").to_string();
+            let unit = Unit {
+                key: cell(0),
+                speaker: String::new(),
+                source: cell(1),
+                target: cell(2),
+            };
+            (unit, cell(4))
+        })
+        .collect())
+}
+
+/// Writes an "output" sheet like the one of ODS key mode, so it can be read back.
+fn write_ods(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+    units: &[Unit],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sheet = Sheet::new("output");
+    for (col, header) in ["Key", src_lang, dst_lang].iter().enumerate() {
+        sheet.set_value(0, col as u32, *header);
+    }
+    for (i, u) in units.iter().enumerate() {
+        let row = i as u32 + 1;
+        sheet.set_value(row, 0, &u.key);
+        sheet.set_value(row, 1, &u.source);
+        sheet.set_value(row, 2, &u.target);
+    }
+
+    let mut wb = spreadsheet_ods::WorkBook::new(locale!("en-US"));
+    wb.push_sheet(sheet);
+    let mut write = BufWriter::new(File::create(path)?);
+    OdsWriteOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .write_ods(&mut wb, &mut write)?;
+    write.flush()?;
+    Ok(())
+}
+
+fn write_csv(path: &str, units: Vec<Unit>) -> Result<(), csv::Error> {
+    let mut wr = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .from_writer(File::create(path)?);
+    for u in units {
+        wr.serialize(BlenderTextRow {
+            datablock_name: u.key,
+            speaker: u.speaker,
+            text: u.target,
+            original: Some(u.source),
+            ..Default::default()
+        })?;
+    }
+    wr.flush()?;
+    Ok(())
+}
+
+/// Converts translations between the output of a run, TMX files and translation memories.
+pub fn convert(args: &ConvertArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let output_format = format(&args.output)?;
+
+    info!("Opening file {}", args.input);
+    let units = read_units(&args.input, &args.src_lang, &args.dst_lang)?;
+    info!("{} translations", units.len());
+
+    info!("Writing {}", args.output);
+    match output_format {
+        Format::Csv => write_csv(&args.output, units)?,
+        Format::Ods => write_ods(&args.output, &args.src_lang, &args.dst_lang, &units)?,
+        Format::Tmx => memory::write_tmx(&args.output, &args.src_lang, &args.dst_lang, &units)?,
+        Format::Memory => {
+            let memory = Memory::open(&args.output, &args.src_lang, &args.dst_lang, 0.0)?;
+            let num_recorded = memory.record(&units)?;
+            info!("{} translations recorded", num_recorded);
+        }
+    }
+
+    Ok(())
+}
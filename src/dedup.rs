@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::layout;
+
+/// Which repeated source lines are translated only once (see --dedup).
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupPolicy {
+    /// Every line is translated, even if repeated.
+    #[default]
+    Off,
+    /// Lines with the same text are translated once, whoever says them.
+    Text,
+    /// Lines with the same speaker and text are translated once.
+    SpeakerText,
+    /// Like speaker-text, but only when their context (the previous and next lines, or the
+    /// context columns in ODS mode) is also the same. Keeps dialogue lines separate.
+    Context,
+}
+
+/// What makes two entries equal under `policy`. None if the entry is never deduplicated.
+/// Entries with different length limits are never merged, as their translations may differ.
+pub fn key(
+    policy: DedupPolicy,
+    speaker: &str,
+    text: &str,
+    context: &[&str],
+    constraints: &layout::Constraints,
+) -> Option<String> {
+    if text.trim().is_empty() {
+        return None;
+    }
+    let mut parts = match policy {
+        DedupPolicy::Off => return None,
+        DedupPolicy::Text => vec![text],
+        DedupPolicy::SpeakerText => vec![speaker, text],
+        DedupPolicy::Context => [speaker, text].iter().chain(context).copied().collect(),
+    };
+    let constraints = format!("{:?}", constraints);
+    parts.push(&constraints);
+    // Unit separator, so that the parts can't be confused.
+    Some(parts.join("\u{1f}"))
+}
+
+/// For each entry, the index of the first entry with the same key, if it's a repetition.
+pub fn find_duplicates(keys: impl Iterator<Item = Option<String>>) -> Vec<Option<usize>> {
+    let mut first: HashMap<String, usize> = HashMap::new();
+    keys.enumerate()
+        .map(|(i, key)| {
+            let key = key?;
+            match first.get(&key) {
+                Some(leader) => Some(*leader),
+                None => {
+                    first.insert(key, i);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(
+        policy: DedupPolicy,
+        entries: &[(&str, &str, &[&str])],
+        constraints: &layout::Constraints,
+    ) -> Vec<Option<usize>> {
+        find_duplicates(
+            entries
+                .iter()
+                .map(|(speaker, text, context)| key(policy, speaker, text, context, constraints)),
+        )
+    }
+
+    const ENTRIES: &[(&str, &str, &[&str])] = &[
+        ("Anna", "Hello", &["Hi", "Bye"]),
+        ("Bob", "Hello", &["Hi", "Bye"]),
+        ("Anna", "Hello", &["Hey", "Bye"]),
+        ("Anna", "Hello", &["Hi", "Bye"]),
+        ("Anna", "", &[]),
+        ("Bob", "", &[]),
+    ];
+
+    #[test]
+    fn policies() {
+        let c = layout::Constraints::default();
+        assert_eq!(keys(DedupPolicy::Off, ENTRIES, &c), [None; 6]);
+        assert_eq!(
+            keys(DedupPolicy::Text, ENTRIES, &c),
+            [None, Some(0), Some(0), Some(0), None, None]
+        );
+        assert_eq!(
+            keys(DedupPolicy::SpeakerText, ENTRIES, &c),
+            [None, None, Some(0), Some(0), None, None]
+        );
+        assert_eq!(
+            keys(DedupPolicy::Context, ENTRIES, &c),
+            [None, None, None, Some(0), None, None]
+        );
+    }
+
+    #[test]
+    fn parts_cant_be_confused() {
+        let c = layout::Constraints::default();
+        let a = key(DedupPolicy::SpeakerText, "Anna", "Hello", &[], &c);
+        let b = key(DedupPolicy::SpeakerText, "An", "naHello", &[], &c);
+        assert_ne!(a, b);
+        let a = key(DedupPolicy::Context, "Anna", "Hello", &["a", "b"], &c);
+        let b = key(DedupPolicy::Context, "Anna", "Hello", &["a b"], &c);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn different_limits_are_not_merged() {
+        let narrow = layout::Constraints {
+            max_chars: Some(10),
+            ..Default::default()
+        };
+        let wide = layout::Constraints {
+            max_chars: Some(20),
+            ..Default::default()
+        };
+        let a = key(DedupPolicy::Text, "", "Hello", &[], &narrow);
+        let b = key(DedupPolicy::Text, "", "Hello", &[], &wide);
+        let c = key(DedupPolicy::Text, "", "Hello", &[], &narrow);
+        assert_eq!(
+            find_duplicates([a, b, c].into_iter()),
+            [None, None, Some(0)]
+        );
+    }
+}
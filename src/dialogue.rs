@@ -0,0 +1,452 @@
+//! The dialogue pipeline (see [`crate::format::Pipeline::Dialogue`]): lines of dialogue sent
+//! with their speakers and the lines around them as context, split into scenes.
+
+use log::{info, warn};
+use std::fmt::Write;
+use std::fs::File;
+
+use crate::error::Error;
+use crate::examples::Examples;
+use crate::format::{self, Format, Output, Pipeline};
+use crate::glossary::Glossary;
+use crate::locale::{self, Language};
+use crate::memory::Memory;
+use crate::open_ai::AiSettings;
+use crate::target::Target;
+use crate::{
+    Args, BackScoring, BlenderTextRow, Prompts, SceneArgs, candidates, dedup, error, incremental,
+    mark_duplicates, open_ai, output_rows, placeholders, review_blender_lines, scenes,
+    score_blender_lines, similarity, translate_blender_lines,
+};
+
+/// Tells the AI how much text fits in each entry. Nothing is written if there are no limits.
+fn write_length_limits(prompt: &mut String, to_translate: &[BlenderTextRow], target: &Target<'_>) {
+    let limits: Vec<String> = to_translate
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let constraints = line.constraints().or(target.validator.constraints);
+            constraints
+                .describe()
+                .map(|d| format!("Entry {} ({}): {}", i + 1, line.speaker, d))
+        })
+        .collect();
+    if limits.is_empty() {
+        return;
+    }
+
+    writeln!(prompt, "# LENGTH LIMITS BEGIN").unwrap();
+    writeln!(
+        prompt,
+        "The translated entries must fit in the space available. Shorten or rephrase if needed. Entries are numbered in the order they appear in the TEXT section."
+    )
+    .unwrap();
+    for limit in limits {
+        writeln!(prompt, "{}", limit).unwrap();
+    }
+    writeln!(prompt, "# LENGTH LIMITS END").unwrap();
+}
+
+pub fn generate_blender_prompt(
+    pre_cxt: &[BlenderTextRow],
+    to_translate: &[BlenderTextRow],
+    pos_cxt: &[BlenderTextRow],
+    target: &Target<'_>,
+) -> String {
+    let mut prompt = String::new();
+
+    writeln!(prompt, "Translate to {}", target.language).unwrap();
+    if let Some(locale) = target.locale {
+        locale::write_prompt(&mut prompt, locale);
+    }
+    if let Some(glossary) = target.glossary {
+        let matches = glossary.matching(to_translate.iter().map(|e| e.text.as_str()));
+        Glossary::write_prompt(&mut prompt, &matches);
+    }
+    if let Some(memory) = target.memory {
+        let matches = memory.matching(to_translate.iter().map(|e| e.text.as_str()));
+        Memory::write_prompt(&mut prompt, &matches);
+    }
+    if let Some(examples) = target.examples {
+        let matches = examples.matching(to_translate.iter().map(|e| e.text.as_str()));
+        Examples::write_prompt(&mut prompt, &matches);
+    }
+    if let Some(characters) = target.characters {
+        let speakers = pre_cxt.iter().chain(to_translate).chain(pos_cxt);
+        characters.write_prompt(&mut prompt, speakers.map(|e| e.speaker.as_str()));
+    }
+    if let Some(formality) = target.formality {
+        let speakers = pre_cxt.iter().chain(to_translate).chain(pos_cxt);
+        formality.write_prompt(&mut prompt, speakers.map(|e| e.speaker.as_str()));
+    }
+    placeholders::write_prompt(&mut prompt, to_translate.iter().map(|e| e.text.as_str()));
+    write_length_limits(&mut prompt, to_translate, target);
+    writeln!(prompt, "# CONTEXT PREVIOUS BEGIN").unwrap();
+    for line in pre_cxt {
+        writeln!(prompt, "## {}", line.speaker).unwrap();
+        writeln!(prompt, "{}", line.text).unwrap();
+    }
+    writeln!(prompt, "# CONTEXT PREVIOUS END").unwrap();
+
+    writeln!(prompt, "# TEXT BEGIN").unwrap();
+    for line in to_translate {
+        writeln!(prompt, "{{SPK}}{}{{SPK}}", line.speaker).unwrap();
+        writeln!(prompt, "{}", line.text).unwrap();
+    }
+    writeln!(prompt, "# TEXT END").unwrap();
+
+    writeln!(prompt, "# CONTEXT AFTER BEGIN").unwrap();
+    for line in pos_cxt {
+        writeln!(prompt, "## {}", line.speaker).unwrap();
+        writeln!(prompt, "{}", line.text).unwrap();
+    }
+    writeln!(prompt, "# CONTEXT AFTER END").unwrap();
+
+    prompt
+}
+
+pub fn process_ai_response_impl(
+    response: &str,
+    entries: &[BlenderTextRow],
+) -> Result<Vec<BlenderTextRow>, Error> {
+    if response.is_empty() {
+        return Err(error::Error::InvalidTranslation);
+    }
+
+    // We can't use response.len() - 1 for out-of-bounds check because that may not be a char boundary.
+    // Find the last character.
+    let last_char_start = response.char_indices().last().unwrap_or((0, 'A')).0;
+
+    let mut translated = Vec::with_capacity(entries.len());
+
+    let mut start_idx = 0;
+    for entry in entries {
+        if start_idx >= response.len() {
+            // In previous iteration we reached the end but we were expecting more entries.
+            return Err(error::Error::InvalidTranslation);
+        }
+
+        let speaker_pattern = format!("{{SPK}}{}{{SPK}}", entry.speaker);
+        let haystack = response[start_idx..]
+            .find(&speaker_pattern)
+            .ok_or(error::Error::InvalidTranslation)?;
+        start_idx = std::cmp::min(
+            start_idx + haystack + speaker_pattern.len() + 1,
+            last_char_start,
+        );
+        let end_idx = match response[start_idx..].find("{SPK}") {
+            Some(idx) => start_idx + idx,
+            None => match response[start_idx..].find("```") {
+                Some(idx) => start_idx + idx,
+                None => response.len(),
+            },
+        };
+
+        let parts = response[start_idx..end_idx]
+            .split_once("{RMK}")
+            .unwrap_or((&response[start_idx..end_idx], ""));
+        let text = parts.0.trim_start().trim_end();
+        let remarks = parts.1.trim_start().trim_end();
+
+        translated.push(BlenderTextRow {
+            datablock_name: entry.datablock_name.clone(),
+            speaker: entry.speaker.clone(),
+            text: text.to_string(),
+            original: Some(entry.text.clone()),
+            original_back: None,
+            remarks: Some(remarks.to_string()),
+            ..Default::default()
+        });
+
+        start_idx = end_idx
+    }
+
+    Ok(translated)
+}
+
+/// Splits the lines into scenes using the Scene column, marker rows and gaps between cues.
+pub fn split_scenes(args: &SceneArgs, lines: &[BlenderTextRow]) -> Vec<std::ops::Range<usize>> {
+    let hints: Vec<scenes::SceneHint> = lines
+        .iter()
+        .map(|l| scenes::SceneHint {
+            scene: l.scene.as_deref(),
+            start: l.start,
+            end: l.start.zip(l.duration).map(|(s, d)| s + d as f64),
+            is_marker: args.scene_markers && l.speaker.trim().is_empty(),
+        })
+        .collect();
+    scenes::split(&hints, args.scene_gap)
+}
+
+/// Translates the entries of `output.document` as lines of dialogue and writes them to `output`.
+pub async fn translate_dialogue(
+    args: &Args,
+    output: &Output<'_>,
+    error_log: &mut File,
+    ai_settings: &AiSettings<'_>,
+    target: &Target<'_>,
+) -> Result<Vec<BlenderTextRow>, Box<dyn std::error::Error>> {
+    let src_language = args.src_lang.as_deref().map(Language::parse).transpose()?;
+    let mut lines = output.document.entries.clone();
+    if let Some(path) = &args.previous {
+        info!("Opening previous output {}", path);
+        let previous = format::read(path, &args.read.read_options())?;
+        let num_reused = incremental::attach_previous(&mut lines, previous.entries);
+        info!("Reusing {} of {} lines", num_reused, lines.len());
+    }
+    if let Some(memory) = target.memory {
+        let num_reused = incremental::attach_memory(&mut lines, memory);
+        info!("{} lines found in the translation memory", num_reused);
+    }
+    if args.dedup != dedup::DedupPolicy::Off {
+        let num_duplicates = mark_duplicates(args.dedup, &mut lines, Pipeline::Dialogue);
+        info!(
+            "{} repeated lines will copy the translation of their first occurrence",
+            num_duplicates
+        );
+    }
+    let scenes = split_scenes(&args.scenes, &lines);
+    if scenes.len() > 1 {
+        info!("Found {} scenes", scenes.len());
+    }
+    if args.dst_subtitles.is_some() && !format::Subtitles.matches(&output.document.path) {
+        return Err(
+            "--dst-subtitles requires a subtitle file (.srt, .vtt, .ass) as --input".into(),
+        );
+    }
+
+    // Translate to target lang.
+    info!("Begin Translation");
+    let (mut translated, candidates_back) = if args.candidates.is_empty() {
+        let translated = translate_blender_lines(
+            args.batch.batch_size as usize,
+            &Prompts::Dialogue(args.batch.window(&scenes)),
+            &lines,
+            ai_settings,
+            target,
+            error_log,
+        )
+        .await?;
+        (translated, None)
+    } else {
+        translate_candidates(args, &lines, &scenes, ai_settings, target, error_log).await?
+    };
+
+    // Now translate it back to the original lang for validation (if src_lang was provided).
+    let original_back = match &src_language {
+        Some(src_language) => {
+            let scoring = BackScoring {
+                scorer: args.back_score.scorer(&args.ai.model),
+                prompts: Prompts::Dialogue(args.batch.window(&scenes)),
+                back_prompts: Prompts::Dialogue(args.batch.window(&scenes)),
+                target: target.back(&src_language.name),
+            };
+            // Candidates selected by back translation already have theirs.
+            let back = match candidates_back {
+                Some(back) => Ok(back),
+                None => {
+                    info!("Begin Back Translation");
+                    translate_blender_lines(
+                        args.batch.batch_size as usize,
+                        &scoring.back_prompts,
+                        &translated,
+                        ai_settings,
+                        &scoring.target,
+                        error_log,
+                    )
+                    .await
+                }
+            };
+            match back {
+                Ok(mut r) => {
+                    score_blender_lines(
+                        &scoring,
+                        &lines,
+                        &mut translated,
+                        &mut r,
+                        ai_settings,
+                        target,
+                        error_log,
+                    )
+                    .await?;
+                    r
+                }
+                Err(_) => {
+                    warn!("Back Translation Error. It won't be available.");
+                    let mut blank = Vec::new();
+                    blank.resize_with(translated.len(), BlenderTextRow::default);
+                    blank
+                }
+            }
+        }
+        None => {
+            let mut blank = Vec::new();
+            blank.resize_with(translated.len(), BlenderTextRow::default);
+            blank
+        }
+    };
+
+    if args.review {
+        info!("Begin Review");
+        review_blender_lines(
+            args,
+            &Prompts::Dialogue(args.batch.window(&scenes)),
+            &lines,
+            &mut translated,
+            ai_settings,
+            target,
+            error_log,
+        )
+        .await?;
+    }
+
+    if let Some(memory) = target.memory {
+        let recorded = memory.record(&incremental::accepted_units(&lines, &translated))?;
+        info!(
+            "Recorded {} translations in the translation memory",
+            recorded
+        );
+    }
+
+    if let Some(path) = &args.dst_subtitles {
+        info!("Writing subtitles to {}", path);
+        format::Subtitles.write(path, output.document, output.language, &translated)?;
+    }
+
+    info!("Writing results to {}", args.output);
+    let rows = output_rows(translated, original_back, output.language.tag().as_deref());
+    output.write(&rows)?;
+    Ok(rows)
+}
+
+/// Translates the lines once per configuration in --candidates, then picks the best
+/// translation of each line following --select. The others are kept as alternatives.
+/// With --select back-translation, also returns the back translation of the chosen
+/// candidates, whose rows already carry their scores.
+async fn translate_candidates(
+    args: &Args,
+    lines: &[BlenderTextRow],
+    scenes: &[std::ops::Range<usize>],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<(Vec<BlenderTextRow>, Option<Vec<BlenderTextRow>>), Box<dyn std::error::Error>> {
+    let mut candidates = Vec::with_capacity(args.candidates.len());
+    for (i, config) in args.candidates.iter().enumerate() {
+        info!(
+            "Candidate {} / {} ({})",
+            i + 1,
+            args.candidates.len(),
+            config
+        );
+        let prompts = Prompts::Dialogue(scenes::ContextWindow {
+            scenes,
+            pre: config.pre_ctx as usize,
+            pos: config.pos_ctx as usize,
+        });
+        candidates.push(
+            translate_blender_lines(
+                config.batch_size as usize,
+                &prompts,
+                lines,
+                ai_settings,
+                target,
+                error_log,
+            )
+            .await?,
+        );
+    }
+
+    info!("Selecting candidates");
+    // Back translation and scores of each line of each candidate, if --select used them.
+    let mut candidate_backs: Vec<Vec<BlenderTextRow>> = Vec::new();
+    let mut scores: Vec<Vec<similarity::Score>> = Vec::new();
+    let choices: Vec<usize> = match args.select {
+        candidates::Selection::Majority => (0..lines.len())
+            .map(|idx| {
+                let texts: Vec<&str> = candidates.iter().map(|c| c[idx].text.as_str()).collect();
+                candidates::pick_majority(&texts)
+            })
+            .collect(),
+        candidates::Selection::BackTranslation => {
+            let src_language = Language::parse(
+                args.src_lang
+                    .as_deref()
+                    .ok_or("--select back-translation requires --src-lang")?,
+            )?;
+            let scorer = args.back_score.scorer(&args.ai.model);
+            let originals: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+
+            scores = vec![Vec::new(); lines.len()];
+            for candidate in &candidates {
+                let back = translate_blender_lines(
+                    args.batch.batch_size as usize,
+                    &Prompts::Dialogue(args.batch.window(scenes)),
+                    candidate,
+                    ai_settings,
+                    &target.back(&src_language.name),
+                    error_log,
+                )
+                .await?;
+                let backs: Vec<&str> = back.iter().map(|l| l.text.as_str()).collect();
+                let candidate_scores = scorer.score(ai_settings, &originals, &backs).await?;
+                for (idx, score) in candidate_scores.into_iter().enumerate() {
+                    scores[idx].push(score);
+                }
+                candidate_backs.push(back);
+            }
+            scores
+                .iter()
+                .map(|s| candidates::pick_by_score(s))
+                .collect()
+        }
+        candidates::Selection::Judge => {
+            // The reviews of the chosen candidates are kept in the output.
+            let prompts = Prompts::Dialogue(args.batch.window(scenes));
+            for candidate in &mut candidates {
+                review_blender_lines(
+                    args,
+                    &prompts,
+                    lines,
+                    candidate,
+                    ai_settings,
+                    target,
+                    error_log,
+                )
+                .await?;
+            }
+            (0..lines.len())
+                .map(|idx| {
+                    let totals: Vec<Option<f64>> = candidates
+                        .iter()
+                        .map(|c| c[idx].review_accuracy.zip(c[idx].review_fluency))
+                        .map(|scores| scores.map(|(accuracy, fluency)| accuracy + fluency))
+                        .collect();
+                    candidates::pick_highest(&totals)
+                })
+                .collect()
+        }
+    };
+
+    let mut output = Vec::with_capacity(lines.len());
+    let mut chosen_back = Vec::with_capacity(candidate_backs.first().map_or(0, |_| lines.len()));
+    for (idx, choice) in choices.into_iter().enumerate() {
+        let mut row = candidates[choice][idx].clone();
+        row.candidate = Some(format!("{} ({})", choice + 1, args.candidates[choice]));
+        if !candidate_backs.is_empty() {
+            row.back_score = Some(scores[idx][choice].chrf);
+            row.back_similarity = scores[idx][choice].embedding;
+            chosen_back.push(candidate_backs[choice][idx].clone());
+        }
+        let alternatives: Vec<String> = candidates
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != choice)
+            .map(|(i, c)| format!("[{}] {}", i + 1, c[idx].text.replace('\n', " ")))
+            .collect();
+        row.alternatives = Some(alternatives.join("\n"));
+        output.push(row);
+    }
+
+    Ok((output, (!candidate_backs.is_empty()).then_some(chosen_back)))
+}
@@ -4,6 +4,7 @@ use std::fmt;
 pub enum Error {
     HttpStatus(u16),
     InvalidTranslation,
+    Timeout,
 }
 
 impl fmt::Display for Error {
@@ -11,6 +12,7 @@ impl fmt::Display for Error {
         match self {
             Error::HttpStatus(v) => write!(f, "HTTP Status Code: {}", v),
             Error::InvalidTranslation => write!(f, "Invalid Translation"),
+            Error::Timeout => write!(f, "AI took too long"),
         }
     }
 }
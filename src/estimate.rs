@@ -0,0 +1,239 @@
+use log::info;
+
+use crate::candidates::Selection;
+use crate::format::{self, Pipeline};
+use crate::locale::Language;
+use crate::target::Target;
+use crate::{BatchArgs, BlenderTextRow, ReadArgs, SceneArgs, candidates, review, scenes};
+
+/// Rough number of output tokens of each review (scores, errors and a suggestion).
+const REVIEW_TOKENS_PER_ENTRY: usize = 60;
+
+/// Requests and tokens of one step of the translation.
+#[derive(Default)]
+struct Stage {
+    requests: usize,
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+impl Stage {
+    fn add(&mut self, other: &Stage) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+
+    fn print(&self, name: &str) {
+        info!(
+            "{:<18} {:>6} requests {:>10} input tokens {:>10} output tokens",
+            name, self.requests, self.input_tokens, self.output_tokens
+        );
+    }
+}
+
+/// Very rough, but good enough to compare settings: about 4 characters per token.
+fn tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// One pass over all the lines, as translate_blender_lines would do it.
+fn pass(
+    lines: &[BlenderTextRow],
+    scenes: &[std::ops::Range<usize>],
+    (pre, batch_size, pos): (u16, u16, u16),
+    target: &Target<'_>,
+    system_tokens: usize,
+) -> Stage {
+    let window = scenes::ContextWindow {
+        scenes,
+        pre: pre as usize,
+        pos: pos as usize,
+    };
+
+    let mut stage = Stage::default();
+    for batch in scenes::batches(scenes, batch_size as usize) {
+        let (pre_range, pos_range) = window.around(batch.start, batch.end);
+        let prompt = crate::dialogue::generate_blender_prompt(
+            &lines[pre_range],
+            &lines[batch.clone()],
+            &lines[pos_range],
+            target,
+        );
+        stage.requests += 1;
+        stage.input_tokens += system_tokens + tokens(&prompt);
+        // The answer repeats the speakers and has about as much text as the batch.
+        stage.output_tokens += lines[batch]
+            .iter()
+            .map(|l| tokens(&format!("{{SPK}}{}{{SPK}}\n{}\n", l.speaker, l.text)))
+            .sum::<usize>();
+    }
+    stage
+}
+
+#[derive(clap::Args, Debug)]
+#[command(args_override_self = true)]
+pub struct EstimateArgs {
+    /// Path to the system prompt location. Only its length is used.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub system_prompt: String,
+
+    /// Source language. If given, the back translation is included.
+    #[arg(short, long)]
+    pub src_lang: Option<String>,
+    /// Destination language(s), as in `translate`. Each language repeats the requests.
+    #[arg(short, long)]
+    pub dst_lang: String,
+
+    /// File to translate (CSV, subtitle file or ODS spreadsheet).
+    #[arg(short, long, alias = "src-csv", value_hint = clap::ValueHint::FilePath)]
+    pub input: String,
+
+    /// Translate the entries as lines of dialogue or as independent keys, as in `translate`.
+    #[arg(long, value_enum)]
+    pub pipeline: Option<Pipeline>,
+
+    #[command(flatten)]
+    pub batch: BatchArgs,
+
+    #[command(flatten)]
+    pub read: ReadArgs,
+
+    /// Include the review pass (see `translate --review`).
+    #[arg(long)]
+    pub review: bool,
+
+    /// Configurations to translate with, as in `translate --candidates`.
+    #[arg(long, value_delimiter = ',')]
+    pub candidates: Vec<candidates::CandidateConfig>,
+
+    /// How the best candidate is picked, as in `translate --select`.
+    #[arg(long, value_enum, default_value_t = Selection::BackTranslation)]
+    pub select: Selection,
+
+    #[command(flatten)]
+    pub scenes: SceneArgs,
+}
+
+/// Estimates how many requests and tokens translating with these settings needs, without
+/// calling the AI. The glossary, translation memory and examples aren't loaded, and every
+/// line is counted even if --previous or --memory would reuse it, so it's an upper bound
+/// of the prompts, not a quote.
+pub fn estimate(args: &EstimateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let languages = crate::dst_languages(&args.dst_lang);
+    let dst_language = Language::parse(languages.first().ok_or("--dst-lang is empty")?)?;
+    let src_language = args.src_lang.as_deref().map(Language::parse).transpose()?;
+    let system_tokens = tokens(&crate::read_system_prompt(&args.system_prompt)?);
+
+    info!("Opening file {}", args.input);
+    let formats = format::builtin();
+    let format = format::find(&formats, &args.input);
+    let document = format.read(&args.input, &args.read.read_options())?;
+    let (lines, scenes) = match args.pipeline.unwrap_or(format.pipeline()) {
+        Pipeline::KeyValue => {
+            // Keys are independent, and the context fields aren't counted.
+            let lines: Vec<BlenderTextRow> = document
+                .texts()
+                .map(|e| BlenderTextRow {
+                    text: e.text.clone(),
+                    ..Default::default()
+                })
+                .collect();
+            let scenes = std::iter::once(0..lines.len()).collect();
+            (lines, scenes)
+        }
+        Pipeline::Dialogue => {
+            let scenes = crate::dialogue::split_scenes(&args.scenes, &document.entries);
+            (document.entries, scenes)
+        }
+    };
+    info!("{} entries in {} scenes", lines.len(), scenes.len());
+
+    let target = Target {
+        locale: dst_language.locale.as_ref(),
+        ..Target::new(&dst_language.name)
+    };
+    let configs: Vec<(u16, u16, u16)> = match args.candidates.is_empty() {
+        true => vec![(
+            args.batch.pre_ctx,
+            args.batch.batch_size,
+            args.batch.pos_ctx,
+        )],
+        false => args
+            .candidates
+            .iter()
+            .map(|c| (c.pre_ctx, c.batch_size, c.pos_ctx))
+            .collect(),
+    };
+    let own = (
+        args.batch.pre_ctx,
+        args.batch.batch_size,
+        args.batch.pos_ctx,
+    );
+
+    let mut translation = Stage::default();
+    for config in &configs {
+        translation.add(&pass(&lines, &scenes, *config, &target, system_tokens));
+    }
+
+    let mut back = Stage::default();
+    if let Some(src_language) = &src_language {
+        let back_target = target.back(&src_language.name);
+        let one_pass = pass(&lines, &scenes, own, &back_target, system_tokens);
+        back.add(&one_pass);
+        if !args.candidates.is_empty() && args.select == Selection::BackTranslation {
+            for _ in &configs {
+                back.add(&one_pass);
+            }
+        }
+    }
+
+    // Reviews send the source and the translation of each batch, with the same context.
+    let mut reviews = Stage::default();
+    // --review reuses the reviews of the judge.
+    let num_reviews = match args.select {
+        Selection::Judge if !args.candidates.is_empty() => configs.len(),
+        _ => args.review as usize,
+    };
+    if num_reviews > 0 {
+        let one_pass = pass(
+            &lines,
+            &scenes,
+            own,
+            &target,
+            tokens(review::REVIEW_SYSTEM_PROMPT),
+        );
+        for _ in 0..num_reviews {
+            reviews.add(&Stage {
+                requests: one_pass.requests,
+                input_tokens: one_pass.input_tokens + one_pass.output_tokens,
+                output_tokens: lines.len() * REVIEW_TOKENS_PER_ENTRY,
+            });
+        }
+    }
+
+    let mut total = Stage::default();
+    info!("Per language ({}):", dst_language.name);
+    for (name, stage) in [
+        ("Translation", &translation),
+        ("Back translation", &back),
+        ("Review", &reviews),
+    ] {
+        if stage.requests > 0 {
+            stage.print(name);
+            total.add(stage);
+        }
+    }
+
+    let num_languages = languages.len();
+    let all = Stage {
+        requests: total.requests * num_languages,
+        input_tokens: total.input_tokens * num_languages,
+        output_tokens: total.output_tokens * num_languages,
+    };
+    info!("Total ({} languages):", num_languages);
+    all.print("");
+    info!("Tokens are estimated as 4 characters each. Prompt retries aren't included.");
+
+    Ok(())
+}
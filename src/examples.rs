@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::convert;
+use crate::memory::Unit;
+use crate::open_ai;
+use crate::similarity;
+
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+/// Whether `c` belongs to a script written without spaces between words: CJK ideographs,
+/// kana and Thai/Lao/Khmer/Myanmar.
+fn is_unspaced(c: char) -> bool {
+    matches!(c,
+        '\u{0E00}'..='\u{0EFF}' // Thai, Lao
+        | '\u{1000}'..='\u{109F}' // Myanmar
+        | '\u{1780}'..='\u{17FF}' // Khmer
+        | '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{31F0}'..='\u{31FF}' // Katakana Phonetic Extensions
+        | '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}' // Halfwidth Katakana
+        | '\u{20000}'..='\u{2FA1F}' // CJK Unified Ideographs Extension B to F, Supplement
+    )
+}
+
+/// Lowercase words. Characters of scripts without word separators (e.g. CJK) are
+/// tokens on their own.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() && !is_unspaced(c) {
+            word.extend(c.to_lowercase());
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            tokens.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// BM25 index of the source texts of the examples. Used when there's no embeddings endpoint.
+struct Bm25 {
+    docs: Vec<HashMap<String, usize>>,
+    lengths: Vec<usize>,
+    avg_length: f64,
+    doc_freq: HashMap<String, usize>,
+}
+
+impl Bm25 {
+    fn new<'a>(texts: impl Iterator<Item = &'a str>) -> Bm25 {
+        let mut docs = Vec::new();
+        let mut lengths = Vec::new();
+        let mut doc_freq: HashMap<String, usize> = HashMap::new();
+        for text in texts {
+            let tokens = tokenize(text);
+            let mut freq: HashMap<String, usize> = HashMap::new();
+            for token in &tokens {
+                *freq.entry(token.clone()).or_default() += 1;
+            }
+            for token in freq.keys() {
+                *doc_freq.entry(token.clone()).or_default() += 1;
+            }
+            lengths.push(tokens.len());
+            docs.push(freq);
+        }
+        let avg_length = lengths.iter().sum::<usize>() as f64 / lengths.len().max(1) as f64;
+        Bm25 {
+            docs,
+            lengths,
+            avg_length,
+            doc_freq,
+        }
+    }
+
+    /// Indices of the `count` best matching documents, best first.
+    fn top(&self, query: &str, count: usize) -> Vec<usize> {
+        let terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let num_docs = self.docs.len() as f64;
+
+        let mut scored: Vec<(usize, f64)> = self
+            .docs
+            .iter()
+            .enumerate()
+            .map(|(i, doc)| {
+                let norm =
+                    BM25_K1 * (1.0 - BM25_B + BM25_B * self.lengths[i] as f64 / self.avg_length);
+                let score = terms
+                    .iter()
+                    .filter_map(|t| Some((doc.get(t)?, self.doc_freq[t])))
+                    .map(|(freq, df)| {
+                        let idf = ((num_docs - df as f64 + 0.5) / (df as f64 + 0.5) + 1.0).ln();
+                        let freq = *freq as f64;
+                        idf * freq * (BM25_K1 + 1.0) / (freq + norm)
+                    })
+                    .sum();
+                (i, score)
+            })
+            .filter(|(_, score)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(count).map(|(i, _)| i).collect()
+    }
+}
+
+/// Previously approved translations, sent to the AI as few-shot examples.
+pub struct Examples {
+    units: Vec<Unit>,
+    bm25: Bm25,
+    /// Nearest examples of each text to translate, found with embeddings (see `prepare`).
+    nearest: HashMap<String, Vec<usize>>,
+    /// How many examples are retrieved per entry.
+    per_entry: usize,
+}
+
+impl Examples {
+    /// Loads the examples from a TMX file, a translation memory, or the output (CSV or ODS)
+    /// of a previous run. Rows of a run with remarks, or that the AI gave up on, aren't used.
+    pub fn load(
+        path: &str,
+        src_lang: &str,
+        dst_lang: &str,
+        per_entry: usize,
+    ) -> Result<Examples, Box<dyn std::error::Error>> {
+        let units = convert::read_approved_units(path, src_lang, dst_lang)?;
+        let units: Vec<Unit> = units
+            .into_iter()
+            .filter(|u| !u.source.trim().is_empty() && !u.target.trim().is_empty())
+            .collect();
+
+        Ok(Examples {
+            bm25: Bm25::new(units.iter().map(|u| u.source.as_str())),
+            units,
+            nearest: HashMap::new(),
+            per_entry,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    /// Finds the nearest examples of each text with embeddings, instead of BM25.
+    /// `texts` must be the texts as they'll be sent to the AI.
+    pub async fn prepare(
+        &mut self,
+        ai_settings: &open_ai::AiSettings<'_>,
+        endpoint: &str,
+        model: &str,
+        texts: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let sources: Vec<&str> = self.units.iter().map(|u| u.source.as_str()).collect();
+        let mut unique: Vec<&str> = texts
+            .iter()
+            .map(String::as_str)
+            .filter(|t| !t.trim().is_empty())
+            .collect();
+        unique.sort_unstable();
+        unique.dedup();
+
+        let mut source_embeddings = Vec::with_capacity(sources.len());
+        for chunk in sources.chunks(open_ai::EMBEDDINGS_BATCH) {
+            source_embeddings
+                .append(&mut open_ai::embed(ai_settings, endpoint, model, chunk).await?);
+        }
+
+        for chunk in unique.chunks(open_ai::EMBEDDINGS_BATCH) {
+            let embeddings = open_ai::embed(ai_settings, endpoint, model, chunk).await?;
+            for (text, embedding) in chunk.iter().zip(embeddings) {
+                let mut scored: Vec<(usize, f64)> = source_embeddings
+                    .iter()
+                    .enumerate()
+                    .map(|(i, e)| (i, similarity::cosine(&embedding, e)))
+                    .collect();
+                scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let nearest = scored.into_iter().take(self.per_entry).map(|(i, _)| i);
+                self.nearest.insert(text.to_string(), nearest.collect());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The examples most similar to any of the given texts.
+    pub fn matching<'b>(&self, texts: impl Iterator<Item = &'b str>) -> Vec<&Unit> {
+        let mut found: Vec<usize> = Vec::new();
+        for text in texts {
+            if text.trim().is_empty() {
+                continue;
+            }
+            let nearest = match self.nearest.get(text) {
+                Some(nearest) => nearest.clone(),
+                None => self.bm25.top(text, self.per_entry),
+            };
+            for i in nearest {
+                if !found.contains(&i) {
+                    found.push(i);
+                }
+            }
+        }
+        found.into_iter().map(|i| &self.units[i]).collect()
+    }
+
+    /// Appends the examples to the prompt. Nothing is written if there are no matches.
+    pub fn write_prompt(prompt: &mut String, matches: &[&Unit]) {
+        if matches.is_empty() {
+            return;
+        }
+
+        writeln!(prompt, "# EXAMPLES BEGIN").unwrap();
+        writeln!(
+            prompt,
+            "Approved translations of similar lines. Follow their tone and terminology:"
+        )
+        .unwrap();
+        for u in matches {
+            if !u.speaker.is_empty() {
+                writeln!(prompt, "## {}", u.speaker).unwrap();
+            }
+            writeln!(prompt, "{}", u.source).unwrap();
+            writeln!(prompt, "=> {}", u.target).unwrap();
+        }
+        writeln!(prompt, "# EXAMPLES END").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn examples(sources: &[&str], per_entry: usize) -> Examples {
+        let units: Vec<Unit> = sources
+            .iter()
+            .map(|s| Unit {
+                source: s.to_string(),
+                target: s.to_uppercase(),
+                ..Default::default()
+            })
+            .collect();
+        Examples {
+            bm25: Bm25::new(units.iter().map(|u| u.source.as_str())),
+            units,
+            nearest: HashMap::new(),
+            per_entry,
+        }
+    }
+
+    #[test]
+    fn tokenizes_words_and_unspaced_scripts() {
+        assert_eq!(
+            tokenize("Hello, World! It's 42."),
+            ["hello", "world", "it", "s", "42"]
+        );
+        assert_eq!(tokenize("東京へ行く"), ["東", "京", "へ", "行", "く"]);
+        assert_eq!(tokenize("Go to 東京"), ["go", "to", "東", "京"]);
+        assert!(tokenize(" ... ").is_empty());
+    }
+
+    #[test]
+    fn bm25_ranks_rare_terms_higher() {
+        let bm25 = Bm25::new(
+            [
+                "the sword of the king",
+                "the king is dead",
+                "the dragon sleeps",
+                "a dragon and a sword",
+            ]
+            .into_iter(),
+        );
+        assert_eq!(bm25.top("the dragon", 2), [2, 3]);
+        assert_eq!(bm25.top("king sword", 1), [0]);
+        // Documents without any of the terms are never returned.
+        assert_eq!(bm25.top("dead", 10), [1]);
+        assert!(bm25.top("castle", 10).is_empty());
+    }
+
+    #[test]
+    fn bm25_prefers_shorter_documents() {
+        let bm25 = Bm25::new(
+            [
+                "potion of healing with a long description of its many uses",
+                "potion of healing",
+            ]
+            .into_iter(),
+        );
+        assert_eq!(bm25.top("healing potion", 2), [1, 0]);
+    }
+
+    #[test]
+    fn matching_merges_the_examples_of_all_texts() {
+        let examples = examples(&["open the door", "close the door", "light the torch"], 1);
+        let matches = examples.matching(["open it", "", "the torch", "open wide"].into_iter());
+        let sources: Vec<&str> = matches.iter().map(|u| u.source.as_str()).collect();
+        assert_eq!(sources, ["open the door", "light the torch"]);
+
+        let mut prompt = String::new();
+        Examples::write_prompt(&mut prompt, &matches);
+        assert!(prompt.contains("open the door\n=> OPEN THE DOOR\n"));
+        let mut prompt = String::new();
+        Examples::write_prompt(&mut prompt, &[]);
+        assert!(prompt.is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use serde::Deserialize;
+use std::{fmt::Write, fs::File, io::Read};
+
+/// How the speaker addresses the listener (T-V distinction).
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Address {
+    /// usted, vous, Sie, Lei...
+    Formal,
+    /// tú, tu, du...
+    Informal,
+    /// Informal with "vos" instead of "tú", as in Argentina or Uruguay.
+    Voseo,
+}
+
+impl Address {
+    fn describe(self) -> &'static str {
+        match self {
+            Address::Formal => "formal (e.g. \"usted\", \"vous\", \"Sie\", \"Lei\")",
+            Address::Informal => "informal (e.g. \"tú\", \"tu\", \"du\")",
+            Address::Voseo => "informal with voseo (\"vos tenés\", \"vos sos\"), never \"tú\"",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Address::Formal => "formal",
+            Address::Informal => "informal",
+            Address::Voseo => "voseo",
+        }
+    }
+
+    /// Words that give away the wrong address in `language`.
+    /// Words with uppercase letters are matched case-sensitively (e.g. German "Ihnen").
+    fn forbidden_words(self, language: &str) -> &'static [&'static str] {
+        match (language, self) {
+            ("es", Address::Formal) => &["tú", "contigo", "vos"],
+            ("es", Address::Informal) => &["usted", "vos"],
+            ("es", Address::Voseo) => &["tú", "contigo", "usted"],
+            ("fr", Address::Formal) => &["tu", "toi", "ton", "ta", "tes", "te"],
+            ("de", Address::Formal) => &[
+                "du", "dich", "dir", "dein", "deine", "deinen", "deinem", "deiner",
+            ],
+            ("de", Address::Informal) => &["Ihnen"],
+            ("it", Address::Formal) => &["tu", "ti", "tuo", "tua", "tuoi", "tue"],
+            ("pt", Address::Formal) => &["tu", "contigo", "teu", "tua", "teus", "tuas"],
+            _ => &[],
+        }
+    }
+}
+
+/// What to do with Japanese honorifics (-san, -kun, -chan...).
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Honorifics {
+    /// Keep them, romanized, after the name ("Anna-san").
+    Keep,
+    /// Drop them and convey the relationship through wording.
+    Drop,
+    /// Replace them with natural equivalents of the target language (Mr./Ms., first name...).
+    Adapt,
+}
+
+impl Honorifics {
+    fn describe(self) -> &'static str {
+        match self {
+            Honorifics::Keep => {
+                "keep Japanese honorifics romanized after the name (e.g. \"Anna-san\", \"John-kun\")"
+            }
+            Honorifics::Drop => {
+                "drop Japanese honorifics (-san, -kun, -chan...) and convey the relationship through wording"
+            }
+            Honorifics::Adapt => {
+                "replace Japanese honorifics with natural equivalents of the target language (e.g. Mr./Ms., first name vs. surname)"
+            }
+        }
+    }
+}
+
+const ROMANIZED_HONORIFICS: &[&str] = &["san", "kun", "chan", "sama", "senpai", "sensei", "dono"];
+
+const JAPANESE_HONORIFICS: &[&str] = &["さん", "くん", "ちゃん", "さま", "先輩", "殿"];
+
+/// Kanji words that take honorifics without being names (お母さん, 赤ちゃん, 皆さま...).
+const NOT_NAMES: &[&str] = &[
+    "母", "父", "兄", "姉", "弟", "妹", "祖母", "祖父", "叔母", "伯母", "叔父", "伯父", "婆", "爺",
+    "嫁", "嬢", "坊", "奥", "皆", "赤", "客", "神", "姫", "王", "医者", "大家", "店員", "苦労",
+    "陰",
+];
+
+/// A formality policy. Unset fields fall back to the more general policy.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    pub address: Option<Address>,
+    pub honorifics: Option<Honorifics>,
+    pub notes: Option<String>,
+}
+
+impl Rule {
+    fn is_empty(&self) -> bool {
+        self.address.is_none() && self.honorifics.is_none() && self.notes.is_none()
+    }
+
+    fn write_prompt(&self, prompt: &mut String) {
+        if let Some(address) = self.address {
+            writeln!(prompt, "Address: {}", address.describe()).unwrap();
+        }
+        if let Some(honorifics) = self.honorifics {
+            writeln!(prompt, "Honorifics: {}", honorifics.describe()).unwrap();
+        }
+        if let Some(notes) = &self.notes {
+            writeln!(prompt, "Notes: {}", notes).unwrap();
+        }
+    }
+}
+
+/// Policy of a speaker, towards a listener or (without one) towards everyone.
+#[derive(Debug, Deserialize)]
+struct Pair {
+    speaker: String,
+    #[serde(default)]
+    listener: Option<String>,
+    #[serde(flatten)]
+    rule: Rule,
+}
+
+/// Formality, honorific and register policies: global and per speaker (pair).
+///
+/// ```json
+/// {
+///     "address": "formal",
+///     "honorifics": "keep",
+///     "pairs": [
+///         { "speaker": "Anna", "address": "informal" },
+///         { "speaker": "Anna", "listener": "John", "address": "voseo", "notes": "Childhood friends" }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Formality {
+    #[serde(flatten)]
+    global: Rule,
+    pairs: Vec<Pair>,
+    /// Code of the language being translated to (e.g. "es"), used by the checks.
+    #[serde(skip)]
+    language: Option<String>,
+}
+
+impl Formality {
+    pub fn load(
+        path: &str,
+        language: Option<String>,
+    ) -> Result<Formality, Box<dyn std::error::Error>> {
+        let mut json_str = String::new();
+        File::open(path)?.read_to_string(&mut json_str)?;
+        Ok(Formality {
+            language,
+            ..serde_json::from_str(&json_str)?
+        })
+    }
+
+    /// Appends the global policy and those of the given speakers to the prompt.
+    /// Nothing is written if no policy applies.
+    pub fn write_prompt<'a>(&self, prompt: &mut String, speakers: impl Iterator<Item = &'a str>) {
+        let mut seen: Vec<&str> = Vec::new();
+        for speaker in speakers {
+            if !seen.contains(&speaker) {
+                seen.push(speaker);
+            }
+        }
+        let pairs: Vec<&Pair> = self
+            .pairs
+            .iter()
+            .filter(|p| seen.contains(&p.speaker.as_str()) && !p.rule.is_empty())
+            .collect();
+
+        if self.global.is_empty() && pairs.is_empty() {
+            return;
+        }
+
+        writeln!(prompt, "# FORMALITY BEGIN").unwrap();
+        self.global.write_prompt(prompt);
+        for pair in pairs {
+            match &pair.listener {
+                Some(listener) => writeln!(prompt, "## {} to {}", pair.speaker, listener).unwrap(),
+                None => writeln!(prompt, "## {}", pair.speaker).unwrap(),
+            }
+            pair.rule.write_prompt(prompt);
+        }
+        writeln!(prompt, "# FORMALITY END").unwrap();
+    }
+
+    /// The field of the policy that applies to everything `speaker` says.
+    /// None if it depends on the listener, since the checks don't know who that is.
+    fn resolve<T: Copy>(&self, speaker: &str, field: impl Fn(&Rule) -> Option<T>) -> Option<T> {
+        let of_speaker = || self.pairs.iter().filter(|p| p.speaker == speaker);
+        if let Some(value) = of_speaker()
+            .filter(|p| p.listener.is_none())
+            .find_map(|p| field(&p.rule))
+        {
+            return Some(value);
+        }
+        if of_speaker().any(|p| field(&p.rule).is_some()) {
+            return None;
+        }
+        field(&self.global)
+    }
+
+    /// Obvious violations of the policy in the translation of a line said by `speaker`.
+    pub fn check(&self, speaker: &str, source: &str, translation: &str) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        if let Some(address) = self.resolve(speaker, |r| r.address)
+            && let Some(language) = &self.language
+        {
+            let forbidden = address.forbidden_words(language);
+            let word = translation.split(|c: char| !c.is_alphanumeric()).find(|w| {
+                forbidden
+                    .iter()
+                    .any(|f| match f.chars().any(char::is_uppercase) {
+                        true => w == f,
+                        false => w.to_lowercase() == *f,
+                    })
+            });
+            if let Some(word) = word {
+                issues.push(format!(
+                    "Uses \"{}\", but the address must be {}.",
+                    word,
+                    address.name()
+                ));
+            }
+        }
+
+        let honorifics = self.resolve(speaker, |r| r.honorifics);
+        if honorifics == Some(Honorifics::Keep)
+            && has_japanese_honorific(source)
+            && romanized_honorific(translation).is_none()
+        {
+            issues.push("Honorifics of the original must be kept (e.g. \"-san\").".into());
+        }
+        if let Some(policy @ (Honorifics::Drop | Honorifics::Adapt)) = honorifics
+            && let Some(honorific) = romanized_honorific(translation)
+        {
+            issues.push(format!(
+                "Keeps the honorific \"-{}\", but honorifics must be {}.",
+                honorific,
+                match policy {
+                    Honorifics::Drop => "dropped",
+                    _ => "adapted",
+                }
+            ));
+        }
+
+        issues
+    }
+}
+
+/// First romanized honorific attached to a name, e.g. "san" in "Anna-san".
+fn romanized_honorific(text: &str) -> Option<&'static str> {
+    text.match_indices('-').find_map(|(i, _)| {
+        let suffix: String = text[i + 1..]
+            .chars()
+            .take_while(|c| c.is_alphabetic())
+            .collect();
+        let preceded_by_name = text[..i]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphabetic);
+        ROMANIZED_HONORIFICS
+            .iter()
+            .find(|h| preceded_by_name && suffix.to_lowercase() == **h)
+            .copied()
+    })
+}
+
+fn is_katakana(c: char) -> bool {
+    matches!(c, '\u{30A1}'..='\u{30FA}' | 'ー')
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '々')
+}
+
+/// Whether the text ending at the honorific is a name: katakana (アンナさん) or kanji that
+/// aren't a common noun (田中さん, but not お母さん or 本屋さん). Names in hiragana are
+/// indistinguishable from words like たくさん, so they're not detected.
+fn is_name_before(text: &str) -> bool {
+    match text.chars().next_back() {
+        Some(c) if is_katakana(c) => true,
+        Some(c) if is_kanji(c) => {
+            let start = text
+                .char_indices()
+                .rev()
+                .take_while(|(_, c)| is_kanji(*c))
+                .last()
+                .map_or(0, |(i, _)| i);
+            let word = &text[start..];
+            !NOT_NAMES.contains(&word) && !word.ends_with('屋')
+        }
+        _ => false,
+    }
+}
+
+/// Whether `text` has a Japanese or romanized honorific after a name.
+fn has_japanese_honorific(text: &str) -> bool {
+    JAPANESE_HONORIFICS.iter().any(|h| {
+        text.match_indices(h)
+            .any(|(i, _)| is_name_before(&text[..i]))
+    }) || romanized_honorific(text).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formality(json: &str, language: &str) -> Formality {
+        Formality {
+            language: Some(language.to_string()),
+            ..serde_json::from_str(json).unwrap()
+        }
+    }
+
+    #[test]
+    fn honorifics_after_names() {
+        assert!(has_japanese_honorific("アンナさん、おはよう"));
+        assert!(has_japanese_honorific("田中くんは？"));
+        assert!(has_japanese_honorific("Anna-san, good morning"));
+    }
+
+    #[test]
+    fn words_that_are_not_honorifics() {
+        for text in [
+            "お母さん",
+            "お父さん",
+            "お兄さん",
+            "お姉さん",
+            "赤ちゃん",
+            "皆さま",
+            "皆さん",
+            "みなさん",
+            "たくさんある",
+            "本屋さん",
+            "お疲れさま",
+        ] {
+            assert!(!has_japanese_honorific(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn keep_honorifics() {
+        let f = formality(r#"{ "honorifics": "keep" }"#, "en");
+        assert_eq!(
+            f.check("John", "アンナさん、おはよう", "Morning, Anna.")
+                .len(),
+            1
+        );
+        assert!(
+            f.check("John", "アンナさん、おはよう", "Morning, Anna-san.")
+                .is_empty()
+        );
+        assert!(
+            f.check("John", "お母さん、おはよう", "Morning, Mom.")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn drop_honorifics() {
+        let f = formality(r#"{ "honorifics": "drop" }"#, "en");
+        assert_eq!(f.check("John", "アンナさん", "Anna-san").len(), 1);
+        assert!(f.check("John", "アンナさん", "Anna").is_empty());
+        // A hyphenated word isn't an honorific.
+        assert!(f.check("John", "サンタ", "Pre-sanitized").is_empty());
+    }
+
+    #[test]
+    fn address() {
+        let f = formality(r#"{ "address": "formal" }"#, "es");
+        assert_eq!(f.check("John", "", "¿Tú vienes?").len(), 1);
+        assert!(f.check("John", "", "¿Usted viene?").is_empty());
+
+        // Uppercase words are matched case-sensitively: "ihnen" is "them".
+        let f = formality(r#"{ "address": "informal" }"#, "de");
+        assert_eq!(f.check("John", "", "Ich danke Ihnen.").len(), 1);
+        assert!(f.check("John", "", "Ich gebe ihnen das Buch.").is_empty());
+    }
+
+    #[test]
+    fn speaker_policies() {
+        let f = formality(
+            r#"{
+                "address": "formal",
+                "pairs": [
+                    { "speaker": "Anna", "address": "informal" },
+                    { "speaker": "John", "listener": "Anna", "address": "informal" }
+                ]
+            }"#,
+            "es",
+        );
+        assert!(f.check("Anna", "", "¿Tú vienes?").is_empty());
+        assert_eq!(f.check("Anna", "", "¿Usted viene?").len(), 1);
+        assert_eq!(f.check("Bob", "", "¿Tú vienes?").len(), 1);
+        // Depends on the listener, which the checks don't know.
+        assert!(f.check("John", "", "¿Tú vienes?").is_empty());
+    }
+}
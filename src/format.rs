@@ -0,0 +1,419 @@
+//! Formats of the files to translate and of the output. Every format reads its entries as
+//! [`BlenderTextRow`]s, so any of them can go through either pipeline (see [`Pipeline`]).
+//! More formats can be added with [`crate::translator::Translator::register_format`].
+
+use icu_locale_core::locale;
+use spreadsheet_ods::{CompressionMethod, OdsWriteOptions, Sheet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::BlenderTextRow;
+use crate::locale::Language;
+use crate::subtitles::{self, SubtitleFile};
+
+/// How the entries are translated.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pipeline {
+    /// Lines of dialogue: speakers, scenes and the lines around each batch as context.
+    Dialogue,
+    /// Independent keys: each entry is sent with its context fields (e.g. other languages).
+    KeyValue,
+}
+
+/// Settings some formats need to read their entries.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Frame rate used to convert the "From" and "Length" columns of a CSV to seconds.
+    pub fps: f64,
+    /// Comma-separated 0-based columns of the "all" sheet of an ODS: the text to translate,
+    /// then its context.
+    pub ods_columns: String,
+    /// 0-based column of the "all" sheet with the max chars per line of each entry.
+    pub ods_max_chars_column: Option<u32>,
+    /// 0-based column of the "all" sheet with the max display width per line of each entry.
+    pub ods_max_width_column: Option<u32>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            fps: 24.0,
+            ods_columns: "1".to_string(),
+            ods_max_chars_column: None,
+            ods_max_width_column: None,
+        }
+    }
+}
+
+/// The entries of a file, and what's needed to write their translation.
+#[derive(Debug, Default)]
+pub struct Document {
+    /// The file that was read.
+    pub path: String,
+    /// Language of the texts, if the file tells (e.g. the header of an ODS column).
+    pub language: Option<String>,
+    /// Names of the context fields of each entry (see `BlenderTextRow::context`).
+    pub context_names: Vec<String>,
+    pub entries: Vec<BlenderTextRow>,
+}
+
+impl Document {
+    /// The entries with some text.
+    pub fn texts(&self) -> impl Iterator<Item = &BlenderTextRow> {
+        self.entries.iter().filter(|e| !e.text.is_empty())
+    }
+}
+
+/// A file format the entries can be read from and the translations written to.
+pub trait Format: Send + Sync {
+    /// Whether `path` is in this format, usually by its extension.
+    fn matches(&self, path: &str) -> bool;
+
+    /// The pipeline used when --pipeline isn't given.
+    fn pipeline(&self) -> Pipeline {
+        Pipeline::Dialogue
+    }
+
+    fn read(
+        &self,
+        path: &str,
+        options: &ReadOptions,
+    ) -> Result<Document, Box<dyn std::error::Error>>;
+
+    /// Writes the translation of `document` to `language`. `rows` are in the shape of the CSV
+    /// output: the translation in `text`, the source text in `original`.
+    fn write(
+        &self,
+        path: &str,
+        document: &Document,
+        language: &Language,
+        rows: &[BlenderTextRow],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    let lower = path.to_lowercase();
+    let extension = lower.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+    extensions.contains(&extension)
+}
+
+/// Semicolon-separated CSV, as exported by the Blender plugin.
+pub struct Csv;
+
+impl Format for Csv {
+    fn matches(&self, path: &str) -> bool {
+        has_extension(path, &["csv"])
+    }
+
+    fn read(
+        &self,
+        path: &str,
+        options: &ReadOptions,
+    ) -> Result<Document, Box<dyn std::error::Error>> {
+        let mut entries = crate::read_csv(path)?;
+        for entry in &mut entries {
+            if let Some(frames) = entry.length_frames {
+                entry.duration = Some((frames / options.fps) as f32);
+            }
+            if let Some(frame) = entry.from_frame {
+                entry.start = Some(frame / options.fps);
+            }
+        }
+        Ok(Document {
+            path: path.to_string(),
+            entries,
+            ..Default::default()
+        })
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        _: &Document,
+        _: &Language,
+        rows: &[BlenderTextRow],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(crate::write_csv(path, rows)?)
+    }
+}
+
+/// Subtitle files (SRT, VTT, ASS). Only the texts change when writing them, so the
+/// translation can only be written when the input was a subtitle file too.
+pub struct Subtitles;
+
+impl Format for Subtitles {
+    fn matches(&self, path: &str) -> bool {
+        subtitles::Format::from_path(path).is_some()
+    }
+
+    fn read(&self, path: &str, _: &ReadOptions) -> Result<Document, Box<dyn std::error::Error>> {
+        let subs = SubtitleFile::load(path)?;
+        let entries = subs
+            .cues
+            .iter()
+            .enumerate()
+            .map(|(i, cue)| BlenderTextRow {
+                datablock_name: (i + 1).to_string(),
+                speaker: cue.speaker.clone(),
+                text: cue.text.clone(),
+                start: Some(cue.start),
+                duration: Some(cue.duration() as f32),
+                ..Default::default()
+            })
+            .collect();
+        Ok(Document {
+            path: path.to_string(),
+            entries,
+            ..Default::default()
+        })
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        document: &Document,
+        _: &Language,
+        rows: &[BlenderTextRow],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.matches(&document.path) {
+            return Err(format!(
+                "Subtitles can only be written from a subtitle file (.srt, .vtt, .ass), not {}",
+                document.path
+            )
+            .into());
+        }
+        let texts: Vec<String> = rows.iter().map(|r| r.text.clone()).collect();
+        SubtitleFile::load(&document.path)?.write(path, &texts)
+    }
+}
+
+/// ODS spreadsheets. The input is the "all" sheet: the key in the first column, and the
+/// columns of --ods-columns. The output is written to the "output" sheet (and the "review"
+/// sheet, if there are reviews), which can also be read, e.g. as --previous.
+pub struct Ods;
+
+/// Reads an optional numeric cell, e.g. a max chars column.
+fn cell_usize(sheet: &Sheet, row: u32, col: Option<u32>) -> Option<usize> {
+    let col = col?;
+    let value = sheet.value(row, col);
+    match value.as_f64_opt() {
+        Some(v) if v > 0.0 => Some(v as usize),
+        _ => value.as_str_or_default().trim().parse().ok(),
+    }
+}
+
+fn parse_columns(columns: &str) -> Result<Vec<u32>, String> {
+    columns
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| {
+            format!(
+                "--ods-columns must contain numbers and commas only: {}",
+                columns
+            )
+        })
+}
+
+/// Reads the "output" sheet of a previous run.
+fn read_ods_output(sheet: &Sheet) -> Vec<BlenderTextRow> {
+    let (num_rows, _) = sheet.used_grid_size();
+    (1..num_rows)
+        .map(|row| {
+            let cell = |col| sheet.value(row, col).as_cow_str_or("").to_string();
+            let optional = |col| Some(cell(col)).filter(|v| !v.is_empty());
+            BlenderTextRow {
+                datablock_name: cell(0),
+                original: Some(cell(1)),
+                text: cell(2),
+                original_back: optional(3),
+                remarks: optional(4),
+                back_score: sheet.value(row, 5).as_f64_opt(),
+                back_similarity: sheet.value(row, 6).as_f64_opt(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+impl Format for Ods {
+    fn matches(&self, path: &str) -> bool {
+        crate::is_ods(path)
+    }
+
+    fn pipeline(&self) -> Pipeline {
+        Pipeline::KeyValue
+    }
+
+    fn read(
+        &self,
+        path: &str,
+        options: &ReadOptions,
+    ) -> Result<Document, Box<dyn std::error::Error>> {
+        let book = spreadsheet_ods::read_ods(path)
+            .map_err(|e| format!("Error opening ODS {}: {}", path, e))?;
+        let Some(all) = book.sheet_idx("all") else {
+            let output = book
+                .sheet_idx("output")
+                .ok_or_else(|| format!("{} has neither an 'all' nor an 'output' sheet", path))?;
+            return Ok(Document {
+                path: path.to_string(),
+                entries: read_ods_output(book.sheet(output)),
+                ..Default::default()
+            });
+        };
+        let all = book.sheet(all);
+        let columns = parse_columns(&options.ods_columns)?;
+        let (text_column, context_columns) =
+            columns.split_first().ok_or("--ods-columns is empty")?;
+        let header = |col| all.value(0, col).as_str_or_default().to_string();
+
+        let (num_rows, _) = all.used_grid_size();
+        let entries = (1..num_rows)
+            .map(|row| {
+                let cell = |col| match all.value(row, col).as_cow_str_or("") {
+                    value if value == "#N/A" => String::new(),
+                    value => value.to_string(),
+                };
+                BlenderTextRow {
+                    datablock_name: all.value(row, 0).as_str_or_default().to_string(),
+                    text: cell(*text_column),
+                    context: context_columns.iter().map(|col| cell(*col)).collect(),
+                    max_chars: cell_usize(all, row, options.ods_max_chars_column),
+                    max_width: cell_usize(all, row, options.ods_max_width_column),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        Ok(Document {
+            path: path.to_string(),
+            language: Some(header(*text_column)),
+            context_names: context_columns.iter().map(|col| header(*col)).collect(),
+            entries,
+        })
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        document: &Document,
+        language: &Language,
+        rows: &[BlenderTextRow],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sheet = Sheet::new("output");
+        // The destination locale, if --dst-lang is a BCP-47 tag, is stamped in the workbook and header.
+        let mut wb =
+            spreadsheet_ods::WorkBook::new(language.locale.clone().unwrap_or(locale!("en-US")));
+        let headers = [
+            "Key",
+            document.language.as_deref().unwrap_or("Source"),
+            &language.tag().unwrap_or(language.name.clone()),
+            "Back",
+            "Remarks",
+            "Back Score",
+            "Back Similarity",
+        ];
+        for (col, header) in headers.iter().enumerate() {
+            sheet.set_value(0, col as u32, *header);
+        }
+
+        for (i, e) in rows.iter().enumerate() {
+            let row = i as u32 + 1;
+            sheet.set_value(row, 0, &e.datablock_name);
+            sheet.set_value(row, 1, e.original.as_deref().unwrap_or_default());
+            sheet.set_value(row, 2, &e.text);
+            if let Some(back) = &e.original_back {
+                sheet.set_value(row, 3, back);
+            }
+            if let Some(remarks) = e.remarks.as_ref().filter(|r| !r.is_empty()) {
+                sheet.set_value(row, 4, remarks);
+            }
+            if let Some(score) = e.back_score {
+                sheet.set_value(row, 5, score);
+            }
+            if let Some(similarity) = e.back_similarity {
+                sheet.set_value(row, 6, similarity);
+            }
+        }
+
+        wb.push_sheet(sheet);
+
+        if rows.iter().any(|e| e.review_errors.is_some()) {
+            let mut sheet = Sheet::new("review");
+            let headers = [
+                "Key",
+                "Source",
+                "Translation",
+                "Accuracy",
+                "Fluency",
+                "Errors",
+                "Suggestion",
+            ];
+            for (col, header) in headers.iter().enumerate() {
+                sheet.set_value(0, col as u32, *header);
+            }
+
+            for (i, e) in rows.iter().enumerate() {
+                let Some(errors) = &e.review_errors else {
+                    continue;
+                };
+                let row = i as u32 + 1;
+                sheet.set_value(row, 0, &e.datablock_name);
+                sheet.set_value(row, 1, e.original.as_deref().unwrap_or_default());
+                sheet.set_value(row, 2, &e.text);
+                if let Some(accuracy) = e.review_accuracy {
+                    sheet.set_value(row, 3, accuracy);
+                }
+                if let Some(fluency) = e.review_fluency {
+                    sheet.set_value(row, 4, fluency);
+                }
+                sheet.set_value(row, 5, errors);
+                sheet.set_value(row, 6, e.review_suggestion.as_deref().unwrap_or_default());
+            }
+
+            wb.push_sheet(sheet);
+        }
+
+        let mut write = BufWriter::new(File::create(path)?);
+        OdsWriteOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .write_ods(&mut wb, &mut write)?;
+        write.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The formats known out of the box.
+pub fn builtin() -> Vec<Box<dyn Format>> {
+    vec![Box::new(Ods), Box::new(Subtitles), Box::new(Csv)]
+}
+
+/// The first of `formats` that matches `path`. Anything unknown is read and written as CSV.
+pub fn find<'a>(formats: &'a [Box<dyn Format>], path: &str) -> &'a dyn Format {
+    formats
+        .iter()
+        .find(|f| f.matches(path))
+        .map_or(&Csv as &dyn Format, |f| f.as_ref())
+}
+
+/// Reads `path` with the built-in formats.
+pub fn read(path: &str, options: &ReadOptions) -> Result<Document, Box<dyn std::error::Error>> {
+    find(&builtin(), path).read(path, options)
+}
+
+/// Where the translation of a document is written.
+pub struct Output<'a> {
+    pub path: &'a str,
+    pub format: &'a dyn Format,
+    pub document: &'a Document,
+    pub language: &'a Language,
+}
+
+impl Output<'_> {
+    pub fn write(&self, rows: &[BlenderTextRow]) -> Result<(), Box<dyn std::error::Error>> {
+        self.format
+            .write(self.path, self.document, self.language, rows)
+    }
+}
@@ -0,0 +1,484 @@
+use log::warn;
+use quick_xml::{Reader, events::Event};
+use serde::{Deserialize, Serialize};
+use std::io::Write as _;
+use std::{fmt::Write, fs::File};
+
+/// A single glossary term. When `do_not_translate` is set, the source term must
+/// appear verbatim in the translation and `target` is ignored.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GlossaryEntry {
+    #[serde(rename = "Source")]
+    pub source: String,
+    #[serde(rename = "Target", default)]
+    pub target: String,
+    #[serde(rename = "DNT", default, with = "yes_no")]
+    pub do_not_translate: bool,
+    #[serde(rename = "Notes", default)]
+    pub notes: String,
+}
+
+impl GlossaryEntry {
+    /// Whether the entry can be used: it has a target, or it's do-not-translate.
+    pub fn is_complete(&self) -> bool {
+        self.do_not_translate || !self.target.trim().is_empty()
+    }
+}
+
+/// Accepts "x", "yes", "true", "1" (case insensitive) as true. Anything else is false.
+mod yes_no {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &bool, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(if *value { "yes" } else { "" })
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<bool, D::Error> {
+        let s = String::deserialize(d)?;
+        Ok(matches!(
+            s.trim().to_lowercase().as_str(),
+            "x" | "yes" | "y" | "true" | "1"
+        ))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Glossary {
+    pub entries: Vec<GlossaryEntry>,
+}
+
+/// Searches `term` inside `haystack` ignoring case.
+/// ASCII alphanumeric terms must not be glued to other ASCII alphanumeric characters
+/// (i.e. "Ann" does not match "Anna"). CJK text has no word separators so there is
+/// no boundary check there.
+pub fn contains_term(haystack: &str, term: &str) -> bool {
+    let term = term.trim();
+    if term.is_empty() {
+        return false;
+    }
+
+    let haystack = haystack.to_lowercase();
+    let term = term.to_lowercase();
+
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let term_starts_word = is_word(term.chars().next());
+    let term_ends_word = is_word(term.chars().last());
+
+    let mut start = 0;
+    while let Some(idx) = haystack[start..].find(&term) {
+        let begin = start + idx;
+        let end = begin + term.len();
+        let before = haystack[..begin].chars().last();
+        let after = haystack[end..].chars().next();
+        let glued = (term_starts_word && is_word(before)) || (term_ends_word && is_word(after));
+        if !glued {
+            return true;
+        }
+        start = begin + term.chars().next().unwrap().len_utf8();
+    }
+    false
+}
+
+/// The contents of a glossary file, before picking the languages. Lets a file be read once
+/// and used for several destination languages.
+pub enum GlossaryFile {
+    Csv(Vec<GlossaryEntry>),
+    /// Each concept and its notes.
+    Tbx(Vec<(TbxConcept, String)>),
+}
+
+impl GlossaryFile {
+    /// Reads either a semicolon-separated CSV (Source;Target;DNT;Notes) or a TBX file,
+    /// based on the file extension.
+    pub fn read(path: &str) -> Result<GlossaryFile, Box<dyn std::error::Error>> {
+        if path.to_lowercase().ends_with(".tbx") {
+            Ok(GlossaryFile::Tbx(read_tbx(path)?))
+        } else {
+            Ok(GlossaryFile::Csv(read_glossary_csv(path)?))
+        }
+    }
+
+    /// The glossary from `src_lang` to `dst_lang`.
+    ///
+    /// For TBX, `src_lang` and `dst_lang` are matched against each langSet's xml:lang.
+    /// If they can't be matched, the first langSet is the source, and the other langSet of
+    /// a concept with two languages is the target. Concepts without the target language are
+    /// skipped, unless their source term is do-not-translate.
+    pub fn glossary(&self, src_lang: Option<&str>, dst_lang: &str) -> Glossary {
+        let entries = match self {
+            GlossaryFile::Csv(entries) => entries.clone(),
+            GlossaryFile::Tbx(concepts) => tbx_entries(concepts, src_lang, dst_lang),
+        };
+
+        Glossary {
+            entries: entries
+                .into_iter()
+                .filter(|e| !e.source.trim().is_empty() && e.is_complete())
+                .collect(),
+        }
+    }
+}
+
+impl Glossary {
+    /// Loads the glossary from `src_lang` to `dst_lang` of a file (see `GlossaryFile`).
+    pub fn load(
+        path: &str,
+        src_lang: Option<&str>,
+        dst_lang: &str,
+    ) -> Result<Glossary, Box<dyn std::error::Error>> {
+        Ok(GlossaryFile::read(path)?.glossary(src_lang, dst_lang))
+    }
+
+    /// Returns all entries whose source term appears in any of the given texts.
+    pub fn matching<'a, 'b>(
+        &'a self,
+        texts: impl Iterator<Item = &'b str> + Clone,
+    ) -> Vec<&'a GlossaryEntry> {
+        self.entries
+            .iter()
+            .filter(|e| texts.clone().any(|t| contains_term(t, &e.source)))
+            .collect()
+    }
+
+    /// Appends the glossary section to the prompt. Nothing is written if there are no matches.
+    pub fn write_prompt(prompt: &mut String, matches: &[&GlossaryEntry]) {
+        if matches.is_empty() {
+            return;
+        }
+
+        writeln!(prompt, "# GLOSSARY BEGIN").unwrap();
+        for e in matches {
+            if e.do_not_translate {
+                write!(
+                    prompt,
+                    "- {} => keep as \"{}\" (do not translate)",
+                    e.source, e.source
+                )
+                .unwrap();
+            } else {
+                write!(prompt, "- {} => {}", e.source, e.target).unwrap();
+            }
+            if !e.notes.is_empty() {
+                write!(prompt, " ({})", e.notes).unwrap();
+            }
+            writeln!(prompt).unwrap();
+        }
+        writeln!(prompt, "# GLOSSARY END").unwrap();
+    }
+
+    /// Verifies the translation honours the glossary. Returns a human-readable message
+    /// for each violation.
+    pub fn check(&self, source: &str, translation: &str) -> Vec<String> {
+        let mut issues = Vec::new();
+        for e in &self.entries {
+            if !contains_term(source, &e.source) {
+                continue;
+            }
+
+            if e.do_not_translate {
+                if !contains_term(translation, &e.source) {
+                    issues.push(format!(
+                        "Do-not-translate term \"{}\" was altered or removed.",
+                        e.source
+                    ));
+                }
+            } else if !e.target.is_empty() && !contains_term(translation, &e.target) {
+                issues.push(format!(
+                    "Glossary term \"{}\" must be translated as \"{}\".",
+                    e.source, e.target
+                ));
+            }
+        }
+        issues
+    }
+}
+
+fn read_glossary_csv(path: &str) -> Result<Vec<GlossaryEntry>, csv::Error> {
+    let file = File::open(path)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .comment(Some(b'#'))
+        .from_reader(file);
+
+    let mut entries = Vec::new();
+    for result in rdr.deserialize() {
+        let rec: GlossaryEntry = result?;
+        entries.push(rec);
+    }
+
+    Ok(entries)
+}
+
+/// Writes the entries as a semicolon-separated CSV. Entries without a target that aren't
+/// do-not-translate are commented out with "#", for a human to fill in.
+pub fn write_glossary_csv(path: &str, entries: &[GlossaryEntry]) -> Result<(), csv::Error> {
+    let file = File::create(path)?;
+    let mut wr = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .has_headers(false)
+        .from_writer(&file);
+    wr.write_record(["Source", "Target", "DNT", "Notes"])?;
+    for e in entries {
+        if !e.is_complete() {
+            wr.flush()?;
+            (&file).write_all(b"#")?;
+        }
+        wr.serialize(e)?;
+    }
+    wr.flush()?;
+    Ok(())
+}
+
+/// Returns true if the TBX language code (e.g. "es-AR") refers to `lang` (e.g. "es" or "es-AR").
+/// Human-readable names like "Spanish" never match.
+pub fn lang_matches(code: &str, lang: &str) -> bool {
+    let code = code.to_lowercase().replace('_', "-");
+    let lang = lang.to_lowercase().replace('_', "-");
+    code == lang || code.split('-').next() == Some(lang.as_str())
+}
+
+/// One TBX termEntry (TBX 2) / conceptEntry (TBX 3): a list of (language, terms, is_dnt).
+pub type TbxConcept = Vec<(String, Vec<String>, bool)>;
+
+fn read_tbx(path: &str) -> Result<Vec<(TbxConcept, String)>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut reader = Reader::from_str(&content);
+
+    let mut concepts: Vec<(TbxConcept, String)> = Vec::new();
+    let mut concept: TbxConcept = Vec::new();
+    let mut notes = String::new();
+
+    // Name of the element whose text we're currently collecting.
+    let mut collecting: Option<&'static str> = None;
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"termEntry" | b"conceptEntry" => {
+                    concept.clear();
+                    notes.clear();
+                }
+                b"langSet" | b"langSec" => {
+                    let lang = e
+                        .attributes()
+                        .flatten()
+                        .find(|a| a.key.local_name().as_ref() == b"lang")
+                        .map(|a| {
+                            a.decode_and_unescape_value(reader.decoder())
+                                .unwrap_or_default()
+                                .to_string()
+                        })
+                        .unwrap_or_default();
+                    concept.push((lang, Vec::new(), false));
+                }
+                b"term" => {
+                    collecting = Some("term");
+                    text.clear();
+                }
+                b"termNote" | b"descrip" | b"note" => {
+                    let is_status = e.attributes().flatten().any(|a| {
+                        a.decode_and_unescape_value(reader.decoder())
+                            .unwrap_or_default()
+                            .contains("Status")
+                    });
+                    collecting = Some(if is_status { "status" } else { "note" });
+                    text.clear();
+                }
+                _ => {}
+            },
+            Event::Text(t) if collecting.is_some() => {
+                text += &t.decode()?;
+            }
+            Event::GeneralRef(r) if collecting.is_some() => {
+                if let Some(c) = r.resolve_char_ref()? {
+                    text.push(c);
+                } else if let Some(s) = quick_xml::escape::resolve_predefined_entity(&r.decode()?) {
+                    text += s;
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"term" | b"termNote" | b"descrip" | b"note" => match collecting.take() {
+                    Some("term") => {
+                        if let Some(last) = concept.last_mut() {
+                            last.1.push(text.trim().to_string());
+                        }
+                    }
+                    Some("status") => {
+                        let status = text.to_lowercase();
+                        if (status.contains("donottranslate") || status.contains("dnt"))
+                            && let Some(last) = concept.last_mut()
+                        {
+                            last.2 = true;
+                        }
+                    }
+                    Some(_) => {
+                        if !notes.is_empty() {
+                            notes += " ";
+                        }
+                        notes += text.trim();
+                    }
+                    None => {}
+                },
+                b"termEntry" | b"conceptEntry" => {
+                    concepts.push((std::mem::take(&mut concept), std::mem::take(&mut notes)));
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(concepts)
+}
+
+fn tbx_entries(
+    concepts: &[(TbxConcept, String)],
+    src_lang: Option<&str>,
+    dst_lang: &str,
+) -> Vec<GlossaryEntry> {
+    let mut entries = Vec::new();
+    let mut num_missing = 0;
+    for (concept, notes) in concepts {
+        if concept.is_empty() {
+            continue;
+        }
+
+        let src_idx = src_lang
+            .and_then(|l| concept.iter().position(|c| lang_matches(&c.0, l)))
+            .unwrap_or(0);
+        let dst_idx = concept
+            .iter()
+            .position(|c| lang_matches(&c.0, dst_lang))
+            .or_else(|| (concept.len() == 2).then(|| 1 - src_idx))
+            .filter(|&i| i != src_idx);
+
+        let src = &concept[src_idx];
+        if dst_idx.is_none() && !src.2 {
+            num_missing += 1;
+            continue;
+        }
+        let target = dst_idx
+            .and_then(|i| concept[i].1.first().cloned())
+            .unwrap_or_default();
+        // A concept with an empty target term is treated as do-not-translate.
+        let do_not_translate = src.2 || target.is_empty();
+
+        for source in &src.1 {
+            entries.push(GlossaryEntry {
+                source: source.clone(),
+                target: target.clone(),
+                do_not_translate,
+                notes: notes.clone(),
+            });
+        }
+    }
+
+    if num_missing > 0 {
+        warn!(
+            "{} glossary concepts have no {} term. Skipping them.",
+            num_missing, dst_lang
+        );
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn concept(langs: &[(&str, &str)]) -> (TbxConcept, String) {
+        let concept = langs
+            .iter()
+            .map(|(lang, term)| (lang.to_string(), vec![term.to_string()], false))
+            .collect();
+        (concept, String::new())
+    }
+
+    fn pairs(entries: &[GlossaryEntry]) -> Vec<(&str, &str, bool)> {
+        entries
+            .iter()
+            .map(|e| (e.source.as_str(), e.target.as_str(), e.do_not_translate))
+            .collect()
+    }
+
+    #[test]
+    fn tbx_matches_languages() {
+        let concepts = [concept(&[
+            ("en", "sword"),
+            ("de", "Schwert"),
+            ("fr-FR", "épée"),
+        ])];
+        let entries = tbx_entries(&concepts, Some("en"), "fr");
+        assert_eq!(pairs(&entries), [("sword", "épée", false)]);
+
+        let entries = tbx_entries(&concepts, Some("fr"), "de");
+        assert_eq!(pairs(&entries), [("épée", "Schwert", false)]);
+    }
+
+    #[test]
+    fn tbx_without_target_language() {
+        // Another language's term must not be enforced on the translation.
+        let concepts = [concept(&[
+            ("en", "sword"),
+            ("de", "Schwert"),
+            ("es", "espada"),
+        ])];
+        assert!(tbx_entries(&concepts, Some("en"), "fr").is_empty());
+
+        // With two languages, the other one is the target...
+        let concepts = [concept(&[("en", "sword"), ("de", "Schwert")])];
+        let entries = tbx_entries(&concepts, Some("de"), "French");
+        assert_eq!(pairs(&entries), [("Schwert", "sword", false)]);
+
+        // ...but never the source itself.
+        let concepts = [concept(&[("en", "sword")])];
+        assert!(tbx_entries(&concepts, Some("en"), "fr").is_empty());
+    }
+
+    #[test]
+    fn tbx_do_not_translate() {
+        let mut dnt = concept(&[("en", "Excalibur")]);
+        dnt.0[0].2 = true;
+        let entries = tbx_entries(&[dnt], Some("en"), "fr");
+        assert_eq!(pairs(&entries), [("Excalibur", "", true)]);
+    }
+
+    #[test]
+    fn csv_comments_out_incomplete_entries() {
+        let path = std::env::temp_dir().join(format!("glossary_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let entry = |source: &str, target: &str, dnt: bool| GlossaryEntry {
+            source: source.to_string(),
+            target: target.to_string(),
+            do_not_translate: dnt,
+            notes: "term, 3 occurrences".to_string(),
+        };
+        write_glossary_csv(
+            path,
+            &[
+                entry("Anna", "", false),
+                entry("Tokyo", "Tokio", false),
+                entry("Excalibur", "", true),
+            ],
+        )
+        .unwrap();
+
+        let written = std::fs::read_to_string(path).unwrap();
+        assert!(written.starts_with("Source;Target;DNT;Notes\n#Anna;;"));
+
+        let glossary = Glossary::load(path, None, "es").unwrap();
+        let entries: Vec<&str> = glossary.entries.iter().map(|e| e.source.as_str()).collect();
+        assert_eq!(entries, ["Tokyo", "Excalibur"]);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn language_codes() {
+        assert!(lang_matches("es-AR", "es"));
+        assert!(lang_matches("es_AR", "es-ar"));
+        assert!(!lang_matches("es-AR", "Spanish"));
+        assert!(!lang_matches("en", "es"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::memory::{Memory, Unit};
+use crate::validation;
+use crate::{AI_GAVE_UP, BlenderTextRow};
+
+/// Attaches to each line the row of a previous output (--previous) translating the same
+/// source text, so it isn't translated again. Lines are matched by datablock_name, or by
+/// their source text when they don't have one. Rows the AI gave up on are never reused.
+/// Returns how many lines will be reused.
+pub fn attach_previous(lines: &mut [BlenderTextRow], previous: Vec<BlenderTextRow>) -> usize {
+    let mut by_key = HashMap::new();
+    let mut by_text: HashMap<String, VecDeque<BlenderTextRow>> = HashMap::new();
+    for row in previous {
+        // ODS outputs write AI_GAVE_UP as the text of the entries the AI gave up on.
+        if row.text.trim().is_empty() || row.text == crate::AI_GAVE_UP {
+            continue;
+        }
+        let Some(original) = row.original.clone() else {
+            continue;
+        };
+        if row.datablock_name.trim().is_empty() {
+            by_text.entry(original).or_default().push_back(row);
+        } else {
+            by_key.insert(row.datablock_name.clone(), row);
+        }
+    }
+
+    let mut num_reused = 0;
+    for line in lines.iter_mut() {
+        let found = if line.datablock_name.trim().is_empty() {
+            by_text
+                .get_mut(&line.text)
+                .and_then(|rows| rows.pop_front())
+        } else {
+            by_key
+                .remove(&line.datablock_name)
+                .filter(|row| row.original.as_deref() == Some(line.text.as_str()))
+        };
+        if let Some(row) = found {
+            line.previous = Some(Box::new(row));
+            num_reused += 1;
+        }
+    }
+    num_reused
+}
+
+/// The output row of `line` reused from `previous`. The previous back translation (if any)
+/// is attached to it, so the back translation pass reuses it too.
+pub fn reuse(line: &BlenderTextRow, previous: &BlenderTextRow) -> BlenderTextRow {
+    let back = previous
+        .original_back
+        .as_ref()
+        .filter(|back| !back.trim().is_empty())
+        .map(|back| {
+            Box::new(BlenderTextRow {
+                text: back.clone(),
+                original: Some(previous.text.clone()),
+                ..Default::default()
+            })
+        });
+    BlenderTextRow {
+        datablock_name: line.datablock_name.clone(),
+        speaker: line.speaker.clone(),
+        original: Some(line.text.clone()),
+        previous: back,
+        ..previous.clone()
+    }
+}
+
+/// Lines without a previous translation reuse exact matches from the translation memory.
+/// Returns how many lines will be reused.
+pub fn attach_memory(lines: &mut [BlenderTextRow], memory: &Memory) -> usize {
+    let mut num_reused = 0;
+    for line in lines.iter_mut().filter(|l| l.previous.is_none()) {
+        if let Some(unit) = memory.exact(&line.datablock_name, &line.speaker, &line.text) {
+            line.previous = Some(Box::new(BlenderTextRow {
+                text: unit.target.clone(),
+                original: Some(line.text.clone()),
+                ..Default::default()
+            }));
+            num_reused += 1;
+        }
+    }
+    num_reused
+}
+
+/// The translations to record in the translation memory: those that passed every check
+/// (validation and back translation score) and that the AI didn't give up on.
+/// Notes of the AI in the remarks don't prevent recording them.
+pub fn accepted_units(lines: &[BlenderTextRow], translated: &[BlenderTextRow]) -> Vec<Unit> {
+    lines
+        .iter()
+        .zip(translated)
+        .filter(|(_, t)| {
+            let remarks = t.remarks.as_deref().unwrap_or_default();
+            !t.text.trim().is_empty()
+                && !remarks.contains(AI_GAVE_UP)
+                && !validation::has_issues(remarks)
+        })
+        .map(|(l, t)| Unit {
+            key: l.datablock_name.clone(),
+            speaker: l.speaker.clone(),
+            source: l.text.clone(),
+            target: t.text.clone(),
+        })
+        .collect()
+}
@@ -0,0 +1,383 @@
+use unicode_width::UnicodeWidthStr;
+
+/// Number of lines in the text, ignoring leading and trailing blank lines.
+pub fn line_count(text: &str) -> usize {
+    text.trim_matches('\n').lines().count()
+}
+
+/// Display width of a piece of text, following East Asian Width rules
+/// (e.g. CJK ideographs and fullwidth forms take 2 columns).
+pub fn text_width(text: &str) -> usize {
+    text.width()
+}
+
+/// Per-line length limits for an entry (e.g. to fit in a speech bubble or UI button).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Constraints {
+    /// Maximum number of characters per line.
+    pub max_chars: Option<usize>,
+    /// Maximum display width per line (CJK characters count as 2).
+    pub max_width: Option<usize>,
+    /// Maximum number of lines.
+    pub max_lines: Option<usize>,
+    /// Maximum reading speed in characters per second. Needs `duration`.
+    pub max_cps: Option<f32>,
+    /// For how long the text is shown, in seconds (e.g. a subtitle cue).
+    pub duration: Option<f32>,
+}
+
+/// Average width of a (Latin) glyph relative to the font size.
+/// Full-width (CJK) glyphs are roughly twice as wide, hence they count as 2 columns.
+const GLYPH_WIDTH_EM: f32 = 0.55;
+/// Line height relative to the font size.
+const LINE_HEIGHT_EM: f32 = 1.2;
+
+impl Constraints {
+    /// Per-entry values take priority over the global ones.
+    pub fn or(self, global: Constraints) -> Constraints {
+        Constraints {
+            max_chars: self.max_chars.or(global.max_chars),
+            max_width: self.max_width.or(global.max_width),
+            max_lines: self.max_lines.or(global.max_lines),
+            max_cps: self.max_cps.or(global.max_cps),
+            duration: self.duration.or(global.duration),
+        }
+    }
+
+    /// Approximates how much text fits in a box (e.g. a speech bubble) of the given
+    /// dimensions. All values must be in the same units. Missing or non-positive
+    /// values leave the corresponding limit unset.
+    pub fn from_box(
+        width: Option<f32>,
+        height: Option<f32>,
+        font_size: Option<f32>,
+    ) -> Constraints {
+        let Some(font_size) = font_size.filter(|s| *s > 0.0) else {
+            return Constraints::default();
+        };
+        let fit = |size: Option<f32>, unit: f32| {
+            size.filter(|s| *s > 0.0)
+                .map(|s| ((s / (font_size * unit)).floor() as usize).max(1))
+        };
+        Constraints {
+            max_chars: None,
+            max_width: fit(width, GLYPH_WIDTH_EM),
+            max_lines: fit(height, LINE_HEIGHT_EM),
+            ..Default::default()
+        }
+    }
+
+    /// How many characters can be read in `duration` at `max_cps`.
+    fn max_total_chars(&self) -> Option<usize> {
+        let max_cps = self.max_cps?;
+        let duration = self.duration.filter(|d| *d > 0.0)?;
+        Some((max_cps * duration).floor() as usize)
+    }
+
+    /// Describes the limits for the AI, e.g. "at most 20 columns per line, at most 2 lines".
+    /// Returns None if there are no limits.
+    pub fn describe(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(max_chars) = self.max_chars {
+            parts.push(format!("at most {} characters per line", max_chars));
+        }
+        if let Some(max_width) = self.max_width {
+            parts.push(format!(
+                "at most {} columns per line (full-width characters count as 2)",
+                max_width
+            ));
+        }
+        if let Some(max_lines) = self.max_lines {
+            parts.push(format!("at most {} lines", max_lines));
+        }
+        if let Some(max_total) = self.max_total_chars() {
+            parts.push(format!(
+                "at most {} characters in total (it's shown for {:.1} seconds)",
+                max_total,
+                self.duration.unwrap_or_default()
+            ));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+
+    /// Returns a description of each line that exceeds the limits.
+    pub fn check(&self, text: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        if let Some(max_lines) = self.max_lines
+            && line_count(text) > max_lines
+        {
+            violations.push(format!(
+                "The translation has {} lines but only {} fit. Use a shorter rendering.",
+                line_count(text),
+                max_lines
+            ));
+        }
+        if let Some(max_total) = self.max_total_chars() {
+            // Line breaks don't count towards the reading speed.
+            let chars = text
+                .trim_matches('\n')
+                .chars()
+                .filter(|c| *c != '\n')
+                .count();
+            if chars > max_total {
+                violations.push(format!(
+                    "The translation has {} characters, too many to read in {:.1} seconds at {} characters per second (max {}). Condense it.",
+                    chars,
+                    self.duration.unwrap_or_default(),
+                    self.max_cps.unwrap_or_default(),
+                    max_total
+                ));
+            }
+        }
+        for (i, line) in text.trim_matches('\n').lines().enumerate() {
+            let chars = line.chars().count();
+            let width = text_width(line);
+            if let Some(max_chars) = self.max_chars
+                && chars > max_chars
+            {
+                violations.push(format!(
+                    "Line {} has {} characters but must have at most {}. Use a shorter rendering.",
+                    i + 1,
+                    chars,
+                    max_chars
+                ));
+            }
+            if let Some(max_width) = self.max_width
+                && width > max_width
+            {
+                violations.push(format!(
+                    "Line {} is {} columns wide but must be at most {} (full-width characters count as 2). Use a shorter rendering.",
+                    i + 1,
+                    width,
+                    max_width
+                ));
+            }
+        }
+        violations
+    }
+}
+
+/// True for CJK ideographs, kana, hangul and fullwidth forms; i.e. scripts
+/// that don't separate words with spaces.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{9FFF}' | '\u{AC00}'..='\u{D7AF}' | '\u{F900}'..='\u{FAFF}' | '\u{FF00}'..='\u{FFEF}')
+}
+
+/// Rebalances the line breaks of `text` so it has exactly `num_lines` lines of similar width.
+/// Text is broken between words. CJK text without enough spaces is broken between characters.
+/// Returns None if there aren't enough words/characters to fill all the lines.
+pub fn reflow(text: &str, num_lines: usize) -> Option<String> {
+    let joined = text.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+
+    let words: Vec<&str> = joined.split_whitespace().collect();
+    let (units, separator): (Vec<String>, &str) =
+        if words.len() >= num_lines || !joined.chars().any(is_cjk) {
+            (words.iter().map(|w| w.to_string()).collect(), " ")
+        } else {
+            (
+                joined
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .map(String::from)
+                    .collect(),
+                "",
+            )
+        };
+
+    if num_lines == 0 || units.len() < num_lines {
+        return None;
+    }
+
+    // prefix[i] = width of units[..i] (including separators between them).
+    let sep_width = text_width(separator);
+    let mut prefix = Vec::with_capacity(units.len() + 1);
+    prefix.push(0);
+    for (i, u) in units.iter().enumerate() {
+        let sep = if i > 0 { sep_width } else { 0 };
+        prefix.push(prefix[i] + sep + text_width(u));
+    }
+    let total = *prefix.last().unwrap() as f64;
+
+    // Place each break at the unit boundary closest to its ideal position,
+    // leaving at least one unit for each of the remaining lines.
+    let mut breaks = Vec::with_capacity(num_lines - 1);
+    let mut prev = 0;
+    for k in 1..num_lines {
+        let ideal = total * k as f64 / num_lines as f64;
+        let last_allowed = units.len() - (num_lines - k);
+        let best = (prev + 1..=last_allowed)
+            .min_by(|&a, &b| {
+                let da = (prefix[a] as f64 - ideal).abs();
+                let db = (prefix[b] as f64 - ideal).abs();
+                da.total_cmp(&db)
+            })
+            .unwrap();
+        breaks.push(best);
+        prev = best;
+    }
+    breaks.push(units.len());
+
+    let mut lines = Vec::with_capacity(num_lines);
+    let mut start = 0;
+    for end in breaks {
+        lines.push(units[start..end].join(separator));
+        start = end;
+    }
+
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_lines() {
+        assert_eq!(line_count("\nHi\nthere\n\n"), 2);
+        assert_eq!(line_count("Hi"), 1);
+        assert_eq!(line_count(""), 0);
+    }
+
+    #[test]
+    fn checks_line_lengths() {
+        let c = Constraints {
+            max_chars: Some(10),
+            max_width: Some(8),
+            max_lines: Some(2),
+            ..Default::default()
+        };
+        assert!(c.check("Hello\nthere").is_empty());
+        assert_eq!(
+            c.check("Hello world!\nOK"),
+            [
+                "Line 1 has 12 characters but must have at most 10. Use a shorter rendering.",
+                "Line 1 is 12 columns wide but must be at most 8 (full-width characters count as 2). Use a shorter rendering."
+            ]
+        );
+        // Five characters, but ten columns.
+        assert_eq!(c.check("日本語です").len(), 1);
+        assert_eq!(
+            c.check("a\nb\nc"),
+            ["The translation has 3 lines but only 2 fit. Use a shorter rendering."]
+        );
+    }
+
+    #[test]
+    fn fits_text_in_a_box() {
+        // 12 / 0.55 = 21.8 glyphs wide, 3 / 1.2 = 2.5 lines high.
+        let c = Constraints::from_box(Some(12.0), Some(3.0), Some(1.0));
+        assert_eq!(c.max_width, Some(21));
+        assert_eq!(c.max_lines, Some(2));
+        assert_eq!(c.max_chars, None);
+
+        // Same box, twice the font size.
+        let c = Constraints::from_box(Some(12.0), Some(3.0), Some(2.0));
+        assert_eq!((c.max_width, c.max_lines), (Some(10), Some(1)));
+
+        // At least one glyph and one line always fit.
+        let c = Constraints::from_box(Some(0.1), Some(0.1), Some(1.0));
+        assert_eq!((c.max_width, c.max_lines), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn box_without_dimensions() {
+        let c = Constraints::from_box(Some(12.0), None, Some(1.0));
+        assert_eq!((c.max_width, c.max_lines), (Some(21), None));
+        let c = Constraints::from_box(Some(-1.0), Some(0.0), Some(1.0));
+        assert_eq!(c, Constraints::default());
+        assert_eq!(
+            Constraints::from_box(Some(12.0), Some(3.0), None),
+            Constraints::default()
+        );
+        assert_eq!(
+            Constraints::from_box(Some(12.0), Some(3.0), Some(0.0)),
+            Constraints::default()
+        );
+    }
+
+    #[test]
+    fn checks_reading_speed() {
+        let c = Constraints {
+            max_cps: Some(10.0),
+            duration: Some(1.5),
+            ..Default::default()
+        };
+        // Line breaks don't count.
+        assert!(c.check("Fifteen\nchars!!!").is_empty());
+        assert_eq!(c.check("Sixteen\nchars!!!!").len(), 1);
+        // Without a duration there's no limit.
+        let c = Constraints {
+            duration: None,
+            ..c
+        };
+        assert!(c.check("Sixteen\nchars!!!!").is_empty());
+        assert_eq!(c.describe(), None);
+    }
+
+    #[test]
+    fn describes_limits() {
+        let c = Constraints {
+            max_width: Some(20),
+            max_lines: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            c.describe().as_deref(),
+            Some("at most 20 columns per line (full-width characters count as 2), at most 2 lines")
+        );
+        assert_eq!(Constraints::default().describe(), None);
+    }
+
+    #[test]
+    fn entry_limits_take_priority() {
+        let entry = Constraints {
+            max_chars: Some(10),
+            ..Default::default()
+        };
+        let global = Constraints {
+            max_chars: Some(30),
+            max_lines: Some(2),
+            ..Default::default()
+        };
+        let c = entry.or(global);
+        assert_eq!(c.max_chars, Some(10));
+        assert_eq!(c.max_lines, Some(2));
+    }
+
+    #[test]
+    fn reflows_words() {
+        assert_eq!(
+            reflow("I have a very long sentence here", 2).as_deref(),
+            Some("I have a very long\nsentence here")
+        );
+        assert_eq!(
+            reflow("Hello\n  there my\nfriend", 1).as_deref(),
+            Some("Hello there my friend")
+        );
+        // Every line gets at least one word.
+        assert_eq!(
+            reflow("Incomprehensibilities aside, ok", 3).as_deref(),
+            Some("Incomprehensibilities\naside,\nok")
+        );
+        assert_eq!(reflow("one two", 3), None);
+        assert_eq!(reflow("one two", 0), None);
+    }
+
+    #[test]
+    fn reflows_cjk_between_characters() {
+        assert_eq!(
+            reflow("今日はいい天気ですね", 2).as_deref(),
+            Some("今日はいい\n天気ですね")
+        );
+        // With enough spaces, CJK text is broken between words too.
+        assert_eq!(
+            reflow("今日は いい天気ですね", 2).as_deref(),
+            Some("今日は\nいい天気ですね")
+        );
+    }
+}
@@ -0,0 +1,926 @@
+//! Context-aware translation of dialogue (CSV, subtitles) and key/value spreadsheets (ODS)
+//! with an LLM. The `context_translate` binary is a thin wrapper around this library:
+//! see [`translator::Translator`] to run the pipeline from code.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::Read, io::Write as OtherWrite};
+
+use crate::error::Error;
+use crate::examples::Examples;
+use crate::format::Pipeline;
+use crate::placeholders::ProtectedText;
+use crate::target::Target;
+use crate::translator::Event;
+
+pub mod backend;
+pub mod candidates;
+pub mod characters;
+pub mod cli;
+pub mod config;
+pub mod convert;
+pub mod dedup;
+mod dialogue;
+pub mod error;
+pub mod estimate;
+pub mod examples;
+pub mod formality;
+pub mod format;
+pub mod glossary;
+mod incremental;
+pub mod layout;
+pub mod locale;
+pub mod memory;
+pub mod merge;
+mod ods_reader;
+pub mod open_ai;
+pub mod placeholders;
+pub mod review;
+pub mod scenes;
+pub mod similarity;
+pub mod subtitles;
+pub mod target;
+pub mod terms;
+pub mod translator;
+pub mod validation;
+
+pub use cli::{
+    AiArgs, Args, BackScoreArgs, BackTranslateArgs, BatchArgs, Cli, Command, ReadArgs, SceneArgs,
+};
+
+/// Text and remarks of the entries the AI failed to translate.
+pub const AI_GAVE_UP: &str = "AI ERROR. GIVEN UP.";
+
+/// An entry (line) to translate, and its translation. Also a row of the CSV input and output.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BlenderTextRow {
+    #[serde(alias = "UID")]
+    pub datablock_name: String,
+    #[serde(rename = "Collection", alias = "Speaker")]
+    pub speaker: String,
+    #[serde(rename = "Text Contents", alias = "Text")]
+    pub text: String,
+    #[serde(rename = "Original")]
+    pub original: Option<String>,
+    #[serde(rename = "Original Back")]
+    pub original_back: Option<String>,
+    /// chrF between Original and Original Back.
+    #[serde(rename = "Back Score", default)]
+    pub back_score: Option<f64>,
+    /// Embedding similarity between Original and Original Back.
+    #[serde(rename = "Back Similarity", default)]
+    pub back_similarity: Option<f64>,
+    /// Accuracy (1 to 5) given by the reviewer (see --review).
+    #[serde(rename = "Review Accuracy", default)]
+    pub review_accuracy: Option<f64>,
+    /// Fluency (1 to 5) given by the reviewer.
+    #[serde(rename = "Review Fluency", default)]
+    pub review_fluency: Option<f64>,
+    /// Errors detected by the reviewer, one per line.
+    #[serde(rename = "Review Errors", default)]
+    pub review_errors: Option<String>,
+    /// Improved translation suggested by the reviewer.
+    #[serde(rename = "Review Suggestion", default)]
+    pub review_suggestion: Option<String>,
+    /// Which candidate was chosen (see --candidates).
+    #[serde(rename = "Candidate", default)]
+    pub candidate: Option<String>,
+    /// The candidates that weren't chosen, one per line.
+    #[serde(rename = "Alternatives", default)]
+    pub alternatives: Option<String>,
+    #[serde(rename = "Remarks")]
+    pub remarks: Option<String>,
+    /// BCP-47 tag of the translation, if --dst-lang was given as one.
+    #[serde(rename = "Locale", default)]
+    pub locale: Option<String>,
+    /// Optional per-entry limit of characters per line.
+    #[serde(rename = "Max Chars", default, skip_serializing)]
+    pub max_chars: Option<usize>,
+    /// Optional per-entry limit of display width per line (CJK characters count as 2).
+    #[serde(rename = "Max Width", default, skip_serializing)]
+    pub max_width: Option<usize>,
+    /// Width of the text box (e.g. speech bubble), exported by the Blender plugin.
+    #[serde(rename = "Width", default, skip_serializing)]
+    pub box_width: Option<f32>,
+    /// Height of the text box, in the same units as Width.
+    #[serde(rename = "Height", default, skip_serializing)]
+    pub box_height: Option<f32>,
+    /// Font size, in the same units as Width.
+    #[serde(rename = "Font Size", default, skip_serializing)]
+    pub font_size: Option<f32>,
+    /// Length in frames of an animated subtitle. Converted to `duration` using --fps.
+    #[serde(rename = "Length", default, skip_serializing)]
+    pub length_frames: Option<f64>,
+    /// First frame of an animated subtitle. Converted to `start` using --fps.
+    #[serde(rename = "From", default, skip_serializing)]
+    pub from_frame: Option<f64>,
+    /// Optional scene name. Context never crosses scenes.
+    #[serde(rename = "Scene", default, skip_serializing)]
+    pub scene: Option<String>,
+    /// Other texts sent along with this one in the key/value pipeline, e.g. the same key in
+    /// other languages (see --ods-columns). Named by `Document::context_names`.
+    #[serde(skip)]
+    pub context: Vec<String>,
+    /// When the text is shown, in seconds. Comes from subtitle files or From.
+    #[serde(skip)]
+    pub start: Option<f64>,
+    /// For how long the text is shown, in seconds. Comes from subtitle files or Length.
+    #[serde(skip)]
+    pub duration: Option<f32>,
+    /// Output of a previous run for this line, reused instead of translating it again.
+    #[serde(skip)]
+    pub previous: Option<Box<BlenderTextRow>>,
+    /// Index of the first line with the same text. Its translation is copied instead of
+    /// translating this one again (see --dedup).
+    #[serde(skip)]
+    pub duplicate_of: Option<usize>,
+}
+
+impl BlenderTextRow {
+    /// Explicit limits take priority over the ones derived from the text box.
+    fn constraints(&self) -> layout::Constraints {
+        let explicit = layout::Constraints {
+            max_chars: self.max_chars,
+            max_width: self.max_width,
+            duration: self.duration,
+            ..Default::default()
+        };
+        explicit.or(layout::Constraints::from_box(
+            self.box_width,
+            self.box_height,
+            self.font_size,
+        ))
+    }
+}
+
+/// datablock_name is optional, so rows without one are named by their position (e.g. "#3").
+fn row_key(key: &str, idx: usize) -> String {
+    if key.trim().is_empty() {
+        format!("#{}", idx + 1)
+    } else {
+        key.to_string()
+    }
+}
+
+fn read_csv(path: &str) -> Result<Vec<BlenderTextRow>, csv::Error> {
+    let file = File::open(path)?;
+    let mut rdr = csv::ReaderBuilder::new().delimiter(b';').from_reader(file);
+
+    let mut entries = Vec::new();
+
+    for result in rdr.deserialize() {
+        let rec: BlenderTextRow = result?;
+        entries.push(rec);
+    }
+
+    Ok(entries)
+}
+
+/// Merges the translations with their back translations into the rows of the CSV output.
+fn output_rows(
+    entries: Vec<BlenderTextRow>,
+    original_back: Vec<BlenderTextRow>,
+    locale: Option<&str>,
+) -> Vec<BlenderTextRow> {
+    entries
+        .into_iter()
+        .zip(original_back)
+        .map(|(entry, back)| BlenderTextRow {
+            datablock_name: entry.datablock_name,
+            speaker: entry.speaker,
+            text: entry.text,
+            original: entry.original,
+            original_back: Some(back.text),
+            back_score: entry.back_score,
+            back_similarity: entry.back_similarity,
+            review_accuracy: entry.review_accuracy,
+            review_fluency: entry.review_fluency,
+            review_errors: entry.review_errors,
+            review_suggestion: entry.review_suggestion,
+            candidate: entry.candidate,
+            alternatives: entry.alternatives,
+            remarks: entry.remarks,
+            locale: locale.map(str::to_string),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn write_csv(path: &str, rows: &[BlenderTextRow]) -> Result<(), csv::Error> {
+    let file = File::create(path)?;
+    let mut wr = csv::WriterBuilder::new().delimiter(b';').from_writer(file);
+    for row in rows {
+        wr.serialize(row)?;
+    }
+    Ok(())
+}
+
+/// How the entries are written in the prompts and read back from the answers (see --pipeline).
+#[derive(Clone)]
+enum Prompts<'a> {
+    /// Lines of dialogue with their speakers, sent with the lines around them as context.
+    Dialogue(scenes::ContextWindow<'a>),
+    /// Independent keys, each sent with its context fields.
+    KeyValue(ods_reader::KeyValuePrompts<'a>),
+}
+
+impl Prompts<'_> {
+    /// The prompt to translate `entries[batch]`.
+    fn generate(
+        &self,
+        entries: &[BlenderTextRow],
+        batch: std::ops::Range<usize>,
+        target: &Target<'_>,
+    ) -> String {
+        match self {
+            Prompts::Dialogue(window) => {
+                // Context never crosses scene boundaries.
+                let (pre_range, pos_range) = window.around(batch.start, batch.end);
+                dialogue::generate_blender_prompt(
+                    &entries[pre_range],
+                    &entries[batch],
+                    &entries[pos_range],
+                    target,
+                )
+            }
+            Prompts::KeyValue(prompts) => prompts.generate(&entries[batch], target),
+        }
+    }
+
+    /// The translation of each of `entries` in `response`.
+    fn parse(
+        &self,
+        response: &str,
+        entries: &[BlenderTextRow],
+    ) -> Result<Vec<BlenderTextRow>, Error> {
+        match self {
+            Prompts::Dialogue(_) => dialogue::process_ai_response_impl(response, entries),
+            Prompts::KeyValue(_) => ods_reader::process_ai_response_impl(response, entries),
+        }
+    }
+}
+
+fn process_ai_response(
+    prompts: &Prompts<'_>,
+    response: &str,
+    entries: &[BlenderTextRow],
+    orig_prompt: &str,
+    error_log: &mut File,
+) -> Result<Vec<BlenderTextRow>, Error> {
+    let r = prompts.parse(response, entries);
+    match &r {
+        Ok(_) => {}
+        Err(_) => {
+            writeln!(error_log, "# ERROR LOG Invalid response:").ok();
+            writeln!(error_log, "==============================").ok();
+            writeln!(error_log, "{}", response).ok();
+            writeln!(error_log, "==============================").ok();
+            writeln!(error_log, "# ERROR LOG Original Prompt:").ok();
+            writeln!(error_log, "==============================").ok();
+            writeln!(error_log, "{}", orig_prompt).ok();
+            writeln!(error_log, "==============================").ok();
+        }
+    }
+    r
+}
+
+/// Translates a single entry again, explaining what was wrong with the previous attempt.
+/// Returns None if the AI response was invalid.
+async fn retranslate_entry(
+    prompts: &Prompts<'_>,
+    entries: &[BlenderTextRow],
+    idx: usize,
+    issues: &[validation::Issue],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<Option<BlenderTextRow>, Box<dyn std::error::Error>> {
+    let to_translate = &entries[idx..idx + 1];
+    let mut prompt = prompts.generate(entries, idx..idx + 1, target);
+    if !issues.is_empty() {
+        validation::write_retry_notes(&mut prompt, issues);
+    }
+
+    let response = open_ai::run_prompt(ai_settings, &prompt).await?;
+    Ok(
+        process_ai_response(prompts, &response, to_translate, &prompt, error_log)
+            .ok()
+            .and_then(|mut r| r.pop()),
+    )
+}
+
+/// Runs the Validator on each translated entry and re-translates individually
+/// those that fail a check with CheckPolicy::Retry.
+/// Issues that remain are added to the Remarks.
+///
+/// `entries` are the lines that were sent to the AI (with placeholders swapped for tokens)
+/// and `protected` the information to restore them.
+async fn validate_blender_lines(
+    prompts: &Prompts<'_>,
+    entries: &[BlenderTextRow],
+    protected: &[ProtectedText],
+    translated: &mut [BlenderTextRow],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (idx, row) in translated.iter_mut().enumerate() {
+        if entries[idx].previous.is_some() {
+            // Reused as is from the previous output.
+            continue;
+        }
+        row.original = Some(protected[idx].original.clone());
+        if row.text.is_empty() {
+            // Either nothing to translate, or the AI gave up on it.
+            continue;
+        }
+
+        let (text, mut issues) = target.validator.check(
+            &protected[idx],
+            &row.text,
+            entries[idx].constraints(),
+            &entries[idx].speaker,
+        );
+        row.text = text;
+
+        let mut attempt = 0;
+        while validation::needs_retry(&issues) && attempt < validation::ENTRY_RETRIES {
+            attempt += 1;
+            warn!(
+                "Entry {} failed validation. Attempt {}. Retrying...",
+                idx, attempt
+            );
+
+            if let Some(retried) = retranslate_entry(
+                prompts,
+                entries,
+                idx,
+                &issues,
+                ai_settings,
+                target,
+                error_log,
+            )
+            .await?
+            {
+                (row.text, issues) = target.validator.check(
+                    &protected[idx],
+                    &retried.text,
+                    entries[idx].constraints(),
+                    &entries[idx].speaker,
+                );
+                row.remarks = retried.remarks;
+            }
+        }
+
+        validation::add_remarks(row.remarks.get_or_insert_default(), &issues);
+    }
+
+    Ok(())
+}
+
+/// Marks the lines that repeat an earlier one under `policy`, so they're translated only once.
+/// The context of a line of dialogue are the lines around it, and that of a key its context
+/// fields. Reused lines are left alone. Returns how many lines were marked.
+fn mark_duplicates(
+    policy: dedup::DedupPolicy,
+    lines: &mut [BlenderTextRow],
+    pipeline: Pipeline,
+) -> usize {
+    let neighbour = |j: usize| {
+        lines
+            .get(j)
+            .map(|l| format!("{}: {}", l.speaker, l.text))
+            .unwrap_or_default()
+    };
+    let keys: Vec<Option<String>> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            if l.previous.is_some() {
+                return None;
+            }
+            match pipeline {
+                Pipeline::Dialogue => {
+                    let prev = i.checked_sub(1).map(neighbour).unwrap_or_default();
+                    let next = neighbour(i + 1);
+                    let context = [prev.as_str(), next.as_str()];
+                    dedup::key(policy, &l.speaker, &l.text, &context, &l.constraints())
+                }
+                Pipeline::KeyValue => {
+                    let context: Vec<&str> = l.context.iter().map(String::as_str).collect();
+                    dedup::key(policy, "", &l.text, &context, &l.constraints())
+                }
+            }
+        })
+        .collect();
+
+    let mut num_duplicates = 0;
+    for (line, duplicate_of) in lines
+        .iter_mut()
+        .zip(dedup::find_duplicates(keys.into_iter()))
+    {
+        line.duplicate_of = duplicate_of;
+        num_duplicates += duplicate_of.is_some() as usize;
+    }
+    num_duplicates
+}
+
+/// Protects the placeholders of each line (if enabled). Returns the protected texts,
+/// and a copy of the lines with their text replaced by the protected text.
+fn protect_lines(
+    lines: &[BlenderTextRow],
+    target: &Target<'_>,
+) -> (Vec<ProtectedText>, Vec<BlenderTextRow>) {
+    let protected: Vec<ProtectedText> = lines
+        .iter()
+        .map(|e| match target.protect_placeholders {
+            true => placeholders::protect(&e.text),
+            false => placeholders::unprotected(&e.text),
+        })
+        .collect();
+    let entries = lines
+        .iter()
+        .zip(&protected)
+        .map(|(e, p)| BlenderTextRow {
+            text: p.text.clone(),
+            ..e.clone()
+        })
+        .collect();
+    (protected, entries)
+}
+
+/// Translates the lines in batches of `batch_size`, retrying invalid answers, then validates
+/// each translation. Reused and repeated lines aren't sent.
+async fn translate_blender_lines(
+    batch_size: usize,
+    prompts: &Prompts<'_>,
+    entries: &[BlenderTextRow],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<Vec<BlenderTextRow>, Box<dyn std::error::Error>> {
+    // Keys don't form scenes: they're batched in order.
+    let all = 0..entries.len();
+    let scenes = match prompts {
+        Prompts::Dialogue(window) => window.scenes,
+        Prompts::KeyValue(_) => std::slice::from_ref(&all),
+    };
+
+    let reused: Vec<Option<BlenderTextRow>> = entries
+        .iter()
+        .map(|e| e.previous.as_ref().map(|p| incremental::reuse(e, p)))
+        .collect();
+    let (protected, entries) = protect_lines(entries, target);
+
+    // Reused lines aren't sent, but they're still part of the context.
+    let mut output: Vec<BlenderTextRow> =
+        reused.into_iter().map(Option::unwrap_or_default).collect();
+    let pending = scenes::pending(scenes, |i| {
+        entries[i].previous.is_some() || entries[i].duplicate_of.is_some()
+    });
+    let batches = scenes::batches(&pending, batch_size);
+    let num_batches = batches.len();
+    for (batch_id, batch) in batches.into_iter().enumerate() {
+        info!("Batch ID {} / {}", batch_id, num_batches);
+        target.emit(Event::Batch {
+            language: target.language.to_string(),
+            index: batch_id,
+            total: num_batches,
+        });
+        let from = batch.start;
+        let to = batch.end;
+
+        let entries_to_translate = &entries[from..to];
+        let prompt = prompts.generate(&entries, batch, target);
+
+        let mut response = open_ai::run_prompt(ai_settings, &prompt).await?;
+
+        let translated = {
+            let mut translated_result = Vec::new();
+            for j in 0..validation::BATCH_ATTEMPTS {
+                let translated = process_ai_response(
+                    prompts,
+                    &response,
+                    entries_to_translate,
+                    &prompt,
+                    error_log,
+                );
+                match translated {
+                    Ok(t) => {
+                        translated_result = t;
+                        break;
+                    }
+                    Err(_) => {
+                        if j + 1 == validation::BATCH_ATTEMPTS {
+                            warn!("Invalid Translation Output. Attempt {}. Giving up.", j);
+                            for entry in entries_to_translate {
+                                translated_result.push(BlenderTextRow {
+                                    datablock_name: entry.datablock_name.clone(),
+                                    speaker: entry.speaker.clone(),
+                                    text: "".to_string(),
+                                    original: Some(entry.text.clone()),
+                                    original_back: None,
+                                    remarks: Some(AI_GAVE_UP.to_string()),
+                                    ..Default::default()
+                                });
+                            }
+                        } else {
+                            warn!("Invalid Translation Output. Attempt {}. Retrying...", j);
+                            response = open_ai::run_prompt(ai_settings, &prompt).await?;
+                        }
+                    }
+                }
+            }
+            translated_result
+        };
+
+        output.splice(from..to, translated);
+
+        if let Prompts::KeyValue(prompts) = prompts {
+            prompts.write_partial(&protected, &output)?;
+        }
+    }
+
+    validate_blender_lines(
+        prompts,
+        &entries,
+        &protected,
+        &mut output,
+        ai_settings,
+        target,
+        error_log,
+    )
+    .await?;
+
+    // Repeated lines get the translation of their first occurrence.
+    for idx in 0..output.len() {
+        if let Some(leader) = entries[idx].duplicate_of {
+            output[idx] = BlenderTextRow {
+                datablock_name: entries[idx].datablock_name.clone(),
+                speaker: entries[idx].speaker.clone(),
+                original: Some(protected[idx].original.clone()),
+                duplicate_of: Some(leader),
+                ..output[leader].clone()
+            };
+        }
+    }
+
+    Ok(output)
+}
+
+/// What scoring the back translation of the lines needs (see score_blender_lines).
+struct BackScoring<'a> {
+    scorer: similarity::Scorer,
+    /// How a line is translated again with CheckPolicy::Retry.
+    prompts: Prompts<'a>,
+    /// How the lines are translated back.
+    back_prompts: Prompts<'a>,
+    /// The translation back to the source language.
+    target: Target<'a>,
+}
+
+/// Compares each line against its back translation and writes the scores.
+/// Lines below the thresholds are flagged in the Remarks or, with CheckPolicy::Retry,
+/// translated again (and back) keeping the best scoring attempt.
+async fn score_blender_lines(
+    scoring: &BackScoring<'_>,
+    lines: &[BlenderTextRow],
+    translated: &mut [BlenderTextRow],
+    back: &mut [BlenderTextRow],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scorer = &scoring.scorer;
+    let originals: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+    let backs: Vec<&str> = back.iter().map(|l| l.text.as_str()).collect();
+    // Candidates chosen by back translation (see --select) and reused lines were already
+    // scored against this same back translation.
+    let mut scores: Vec<Option<similarity::Score>> = translated
+        .iter()
+        .map(|row| {
+            row.back_score
+                .filter(|_| scorer.embeddings_endpoint.is_none() || row.back_similarity.is_some())
+                .map(|chrf| similarity::Score {
+                    chrf,
+                    embedding: row.back_similarity,
+                })
+        })
+        .collect();
+    let missing: Vec<usize> = (0..scores.len()).filter(|i| scores[*i].is_none()).collect();
+    if !missing.is_empty() {
+        let missing_originals: Vec<&str> = missing.iter().map(|i| originals[*i]).collect();
+        let missing_backs: Vec<&str> = missing.iter().map(|i| backs[*i]).collect();
+        match scorer
+            .score(ai_settings, &missing_originals, &missing_backs)
+            .await
+        {
+            Ok(missing_scores) => {
+                for (i, score) in missing.into_iter().zip(missing_scores) {
+                    scores[i] = Some(score);
+                }
+            }
+            Err(e) => warn!("Could not score the back translation: {}", e),
+        }
+    }
+
+    let back_target = &scoring.target;
+    let prompts = &scoring.prompts;
+    let (protected, entries) = protect_lines(lines, target);
+
+    for idx in 0..lines.len() {
+        // Repeated lines follow their first occurrence, which may have been retried.
+        if let Some(leader) = lines[idx].duplicate_of {
+            scores[idx] = scores[leader];
+            translated[idx].text = translated[leader].text.clone();
+            back[idx].text = back[leader].text.clone();
+        }
+        let Some(mut score) = scores[idx] else {
+            validation::add_remarks(
+                translated[idx].remarks.get_or_insert_default(),
+                &[validation::Issue {
+                    policy: validation::CheckPolicy::Warn,
+                    message: "The back translation could not be scored.".to_string(),
+                }],
+            );
+            continue;
+        };
+
+        let mut attempt = 0;
+        // Reused and repeated lines are never sent again (see --previous and --dedup).
+        while scorer.policy == validation::CheckPolicy::Retry
+            && lines[idx].previous.is_none()
+            && lines[idx].duplicate_of.is_none()
+            && attempt < validation::ENTRY_RETRIES
+            && scorer.check(&score).is_some()
+        {
+            attempt += 1;
+            warn!(
+                "Entry {} back translation differs. Attempt {}. Retrying...",
+                idx, attempt
+            );
+
+            let issues = [validation::Issue {
+                policy: validation::CheckPolicy::Retry,
+                message: format!(
+                    "The meaning seems to have changed. Translated back it reads: \"{}\"",
+                    back[idx].text.replace('\n', " ")
+                ),
+            }];
+            let retried = match retranslate_entry(
+                prompts,
+                &entries,
+                idx,
+                &issues,
+                ai_settings,
+                target,
+                error_log,
+            )
+            .await
+            {
+                Ok(Some(retried)) => retried,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Could not retry entry {}: {}", idx, e);
+                    break;
+                }
+            };
+            let (text, issues) = target.validator.check(
+                &protected[idx],
+                &retried.text,
+                lines[idx].constraints(),
+                &lines[idx].speaker,
+            );
+            if validation::needs_retry(&issues) {
+                continue;
+            }
+
+            // Translate the new attempt back, with the other lines as context.
+            let mut candidate: Vec<BlenderTextRow> = translated.to_vec();
+            candidate[idx].text = text.clone();
+            let (back_protected, back_entries) = protect_lines(&candidate, back_target);
+            let retried_back = match retranslate_entry(
+                &scoring.back_prompts,
+                &back_entries,
+                idx,
+                &[],
+                ai_settings,
+                back_target,
+                error_log,
+            )
+            .await
+            {
+                Ok(Some(retried_back)) => retried_back,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Could not translate back entry {}: {}", idx, e);
+                    break;
+                }
+            };
+            let back_text = back_protected[idx].restore(&retried_back.text).0;
+
+            let retried_score = match scorer
+                .score(ai_settings, &originals[idx..idx + 1], &[back_text.as_str()])
+                .await
+            {
+                Ok(scores) => scores[0],
+                Err(e) => {
+                    warn!("Could not score the back translation: {}", e);
+                    break;
+                }
+            };
+            if similarity::Scorer::is_better(&retried_score, &score) {
+                score = retried_score;
+                // The review (see --select judge) was of the previous translation.
+                translated[idx].review_accuracy = None;
+                translated[idx].review_fluency = None;
+                translated[idx].review_errors = None;
+                translated[idx].review_suggestion = None;
+                translated[idx].text = text;
+                translated[idx].remarks = retried.remarks;
+                validation::add_remarks(translated[idx].remarks.get_or_insert_default(), &issues);
+                back[idx].text = back_text;
+            }
+        }
+
+        scores[idx] = Some(score);
+        let row = &mut translated[idx];
+        row.back_score = Some(score.chrf);
+        row.back_similarity = score.embedding;
+        if let Some(message) = scorer.check(&score) {
+            validation::add_remarks(
+                row.remarks.get_or_insert_default(),
+                &[validation::Issue {
+                    policy: scorer.policy,
+                    message,
+                }],
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Settings for the review pass, which may use a different model and endpoint.
+pub fn review_ai_settings<'a>(
+    args: &Args,
+    ai_settings: &open_ai::AiSettings<'a>,
+) -> open_ai::AiSettings<'a> {
+    open_ai::AiSettings {
+        endpoint: args
+            .review_endpoint
+            .clone()
+            .unwrap_or_else(|| ai_settings.endpoint.clone()),
+        model: args
+            .review_model
+            .clone()
+            .unwrap_or_else(|| ai_settings.model.clone()),
+        system_prompt: review::REVIEW_SYSTEM_PROMPT.to_string(),
+        ..ai_settings.clone()
+    }
+}
+
+/// Asks the reviewer to evaluate every translation and stores its verdicts. Lines of dialogue
+/// are sent with the lines around them, as they were translated. Keys are sent on their own.
+async fn review_blender_lines(
+    args: &Args,
+    prompts: &Prompts<'_>,
+    lines: &[BlenderTextRow],
+    translated: &mut [BlenderTextRow],
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let all = 0..lines.len();
+    let (window, src_lang) = match prompts {
+        Prompts::Dialogue(window) => (window.clone(), args.src_lang.as_deref()),
+        Prompts::KeyValue(prompts) => {
+            let window = scenes::ContextWindow {
+                scenes: std::slice::from_ref(&all),
+                pre: 0,
+                pos: 0,
+            };
+            (window, Some(prompts.src_lang))
+        }
+    };
+    let items: Vec<review::ReviewItem> = lines
+        .iter()
+        .zip(translated.iter())
+        .map(|(l, t)| review::ReviewItem {
+            speaker: match prompts {
+                Prompts::Dialogue(_) => &l.speaker,
+                Prompts::KeyValue(_) => &l.datablock_name,
+            },
+            source: &l.text,
+            translation: &t.text,
+        })
+        .collect();
+    // Translations already reviewed to pick a candidate (see --select judge) aren't sent again.
+    let batches = review::batches(&window, args.batch.batch_size as usize, |i| {
+        translated[i].review_errors.is_some()
+    });
+    let reviews = review::review(
+        &items,
+        &window,
+        batches,
+        src_lang,
+        &review_ai_settings(args, ai_settings),
+        target,
+        error_log,
+    )
+    .await?;
+
+    for (row, review) in translated.iter_mut().zip(reviews) {
+        if let Some(review) = review {
+            row.review_accuracy = review.accuracy;
+            row.review_fluency = review.fluency;
+            row.review_errors = Some(review.errors_text());
+            row.review_suggestion = Some(review.suggestion);
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads --examples. With an embeddings endpoint, the nearest examples of each text to
+/// translate are found in advance. If that fails, BM25 is used instead.
+async fn load_examples(
+    args: &Args,
+    document: &format::Document,
+    ai_settings: &open_ai::AiSettings<'_>,
+) -> Result<Option<Examples>, Box<dyn std::error::Error>> {
+    let Some(path) = &args.examples else {
+        return Ok(None);
+    };
+    info!("Opening Examples {}", path);
+    let mut examples = Examples::load(
+        path,
+        args.src_lang.as_deref().unwrap_or_default(),
+        &args.dst_lang,
+        args.num_examples,
+    )?;
+    info!("{} examples", examples.len());
+
+    if let Some(endpoint) = &args.back_score.embeddings_endpoint
+        && !examples.is_empty()
+    {
+        // The texts must be the same ones sent to the AI.
+        let texts: Vec<String> = document
+            .texts()
+            .map(|e| match args.protect_placeholders {
+                true => placeholders::protect(&e.text).text,
+                false => e.text.clone(),
+            })
+            .collect();
+        let model = args
+            .back_score
+            .embeddings_model
+            .as_deref()
+            .unwrap_or(&args.ai.model);
+        if let Err(e) = examples.prepare(ai_settings, endpoint, model, &texts).await {
+            warn!(
+                "Could not compute the embeddings of the examples: {}. Using BM25 instead.",
+                e
+            );
+        }
+    }
+
+    Ok(Some(examples))
+}
+
+/// Length limits from the command line, applied to entries that don't specify their own.
+fn global_constraints(args: &Args) -> layout::Constraints {
+    let mut constraints = layout::Constraints {
+        max_chars: args.max_chars,
+        max_width: args.max_width,
+        max_cps: args.max_cps,
+        ..Default::default()
+    };
+    if args.subtitle_defaults {
+        let (cps, cpl) = subtitles::default_limits(&args.dst_lang);
+        constraints.max_cps = constraints.max_cps.or(Some(cps));
+        constraints.max_chars = constraints.max_chars.or(Some(cpl));
+    }
+    constraints
+}
+
+fn read_system_prompt(path: &str) -> Result<String, std::io::Error> {
+    info!("Opening System Prompt {}", path);
+    let mut system_prompt = String::new();
+    File::open(path)?.read_to_string(&mut system_prompt)?;
+    Ok(system_prompt)
+}
+
+/// Whether `path` is an ODS spreadsheet, translated in key/value mode.
+fn is_ods(path: &str) -> bool {
+    path.to_lowercase().ends_with(".ods")
+}
+
+/// Languages of --dst-lang, which may be a comma-separated list.
+fn dst_languages(dst_lang: &str) -> Vec<String> {
+    dst_lang
+        .split(',')
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
@@ -0,0 +1,264 @@
+use icu_locale_core::Locale;
+use std::fmt::Write;
+
+/// English name and CLDR cardinal plural categories of each language.
+const LANGUAGES: &[(&str, &str, &[&str])] = &[
+    ("af", "Afrikaans", &["one", "other"]),
+    (
+        "ar",
+        "Arabic",
+        &["zero", "one", "two", "few", "many", "other"],
+    ),
+    ("bg", "Bulgarian", &["one", "other"]),
+    ("bn", "Bengali", &["one", "other"]),
+    ("ca", "Catalan", &["one", "many", "other"]),
+    ("cs", "Czech", &["one", "few", "many", "other"]),
+    ("da", "Danish", &["one", "other"]),
+    ("de", "German", &["one", "other"]),
+    ("el", "Greek", &["one", "other"]),
+    ("en", "English", &["one", "other"]),
+    ("es", "Spanish", &["one", "many", "other"]),
+    ("et", "Estonian", &["one", "other"]),
+    ("eu", "Basque", &["one", "other"]),
+    ("fa", "Persian", &["one", "other"]),
+    ("fi", "Finnish", &["one", "other"]),
+    ("fil", "Filipino", &["one", "other"]),
+    ("fr", "French", &["one", "many", "other"]),
+    ("gl", "Galician", &["one", "other"]),
+    ("he", "Hebrew", &["one", "two", "other"]),
+    ("hi", "Hindi", &["one", "other"]),
+    ("hr", "Croatian", &["one", "few", "other"]),
+    ("hu", "Hungarian", &["one", "other"]),
+    ("id", "Indonesian", &["other"]),
+    ("it", "Italian", &["one", "many", "other"]),
+    ("ja", "Japanese", &["other"]),
+    ("ko", "Korean", &["other"]),
+    ("lt", "Lithuanian", &["one", "few", "many", "other"]),
+    ("lv", "Latvian", &["zero", "one", "other"]),
+    ("ms", "Malay", &["other"]),
+    ("nb", "Norwegian Bokmål", &["one", "other"]),
+    ("nl", "Dutch", &["one", "other"]),
+    ("no", "Norwegian", &["one", "other"]),
+    ("pl", "Polish", &["one", "few", "many", "other"]),
+    ("pt", "Portuguese", &["one", "many", "other"]),
+    ("ro", "Romanian", &["one", "few", "other"]),
+    ("ru", "Russian", &["one", "few", "many", "other"]),
+    ("sk", "Slovak", &["one", "few", "many", "other"]),
+    ("sl", "Slovenian", &["one", "two", "few", "other"]),
+    ("sr", "Serbian", &["one", "few", "other"]),
+    ("sv", "Swedish", &["one", "other"]),
+    ("sw", "Swahili", &["one", "other"]),
+    ("ta", "Tamil", &["one", "other"]),
+    ("th", "Thai", &["other"]),
+    ("tr", "Turkish", &["one", "other"]),
+    ("uk", "Ukrainian", &["one", "few", "many", "other"]),
+    ("ur", "Urdu", &["one", "other"]),
+    ("vi", "Vietnamese", &["other"]),
+    ("zh", "Chinese", &["other"]),
+];
+
+const SCRIPTS: &[(&str, &str)] = &[
+    ("Arab", "Arabic"),
+    ("Cyrl", "Cyrillic"),
+    ("Hans", "Simplified"),
+    ("Hant", "Traditional"),
+    ("Latn", "Latin"),
+];
+
+const REGIONS: &[(&str, &str)] = &[
+    ("419", "Latin America"),
+    ("AR", "Argentina"),
+    ("AT", "Austria"),
+    ("AU", "Australia"),
+    ("BE", "Belgium"),
+    ("BR", "Brazil"),
+    ("CA", "Canada"),
+    ("CH", "Switzerland"),
+    ("CL", "Chile"),
+    ("CN", "China"),
+    ("CO", "Colombia"),
+    ("DE", "Germany"),
+    ("ES", "Spain"),
+    ("FR", "France"),
+    ("GB", "United Kingdom"),
+    ("HK", "Hong Kong"),
+    ("IE", "Ireland"),
+    ("IN", "India"),
+    ("IT", "Italy"),
+    ("JP", "Japan"),
+    ("KR", "South Korea"),
+    ("MX", "Mexico"),
+    ("NZ", "New Zealand"),
+    ("PE", "Peru"),
+    ("PT", "Portugal"),
+    ("SG", "Singapore"),
+    ("TW", "Taiwan"),
+    ("US", "United States"),
+    ("UY", "Uruguay"),
+    ("VE", "Venezuela"),
+];
+
+fn lookup<'a>(table: &[(&str, &'a str)], code: &str) -> Option<&'a str> {
+    table
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| *name)
+}
+
+/// A language given in the command line: either a BCP-47 tag (e.g. "es-AR", "zh-Hant-TW")
+/// or a free-form name (e.g. "Spanish").
+#[derive(Debug, Clone)]
+pub struct Language {
+    /// The locale, if it was given as a tag.
+    pub locale: Option<Locale>,
+    /// Human-readable name sent to the AI, e.g. "Spanish (Argentina)".
+    pub name: String,
+}
+
+impl Language {
+    /// Free-form names are accepted as is. Anything that looks like a tag must be a valid one.
+    pub fn parse(input: &str) -> Result<Language, String> {
+        let input = input.trim();
+        let looks_like_name = input.len() > 3 && !input.contains(['-', '_']);
+        if looks_like_name || input.is_empty() {
+            return Ok(Language {
+                locale: None,
+                name: input.to_string(),
+            });
+        }
+
+        let locale: Locale = input
+            .replace('_', "-")
+            .parse()
+            .map_err(|e| format!("Invalid language tag '{}': {:?}", input, e))?;
+        let id = &locale.id;
+        let language = LANGUAGES
+            .iter()
+            .find(|l| l.0 == id.language.as_str())
+            .map(|l| l.1.to_string())
+            .unwrap_or_else(|| id.language.to_string());
+
+        let mut details = Vec::new();
+        if let Some(script) = &id.script {
+            details.push(lookup(SCRIPTS, script.as_str()).unwrap_or(script.as_str()));
+        }
+        if let Some(region) = &id.region {
+            details.push(lookup(REGIONS, region.as_str()).unwrap_or(region.as_str()));
+        }
+        let name = match details.is_empty() {
+            true => language,
+            false => format!("{} ({})", language, details.join(", ")),
+        };
+
+        Ok(Language {
+            locale: Some(locale),
+            name,
+        })
+    }
+
+    /// The BCP-47 tag, if the language was given as one.
+    pub fn tag(&self) -> Option<String> {
+        self.locale.as_ref().map(|l| l.to_string())
+    }
+
+    /// The language code (e.g. "es" for "es-AR"). Known names (e.g. "Spanish" or
+    /// "Spanish (Argentina)") are mapped too.
+    pub fn code(&self) -> Option<String> {
+        match &self.locale {
+            Some(locale) => Some(locale.id.language.to_string()),
+            None => {
+                let language = self.name.split(" (").next().unwrap_or_default().trim();
+                LANGUAGES
+                    .iter()
+                    .find(|l| l.1.eq_ignore_ascii_case(language))
+                    .map(|l| l.0.to_string())
+            }
+        }
+    }
+}
+
+/// Typographic conventions of a locale.
+#[derive(Debug, Default)]
+struct Conventions {
+    quotes: Option<&'static str>,
+    punctuation: Option<&'static str>,
+    plurals: &'static [&'static str],
+}
+
+fn conventions(locale: &Locale) -> Conventions {
+    let id = &locale.id;
+    let language = id.language.as_str();
+    let region = id.region.as_ref().map(|r| r.as_str()).unwrap_or_default();
+    let script = id.script.as_ref().map(|s| s.as_str()).unwrap_or_default();
+
+    let quotes = match (language, region) {
+        ("en", "GB" | "AU" | "NZ" | "IE") => Some("‘…’ (and “…” for quotes within quotes)"),
+        ("en" | "nl" | "ko" | "tr", _) => Some("“…”"),
+        ("es", "ES") | ("es", "") => Some("«…» (and “…” for quotes within quotes)"),
+        ("es", _) => Some("“…”"),
+        ("fr", _) => Some("« … » with non-breaking spaces inside"),
+        ("de", "CH") => Some("«…»"),
+        ("de" | "cs" | "sk" | "sl" | "lt" | "bg", _) => Some("„…“"),
+        ("pl" | "ro" | "hu" | "hr", _) => Some("„…”"),
+        ("it" | "ru" | "uk" | "ca" | "el" | "no" | "nb" | "fa", _) => Some("«…»"),
+        ("pt", "BR") => Some("“…”"),
+        ("pt", _) => Some("«…»"),
+        ("sv" | "fi", _) => Some("”…”"),
+        ("ja", _) => Some("「…」 (and 『…』 for quotes within quotes)"),
+        ("zh", _) if script == "Hant" || matches!(region, "TW" | "HK") => {
+            Some("「…」 (and 『…』 for quotes within quotes)")
+        }
+        ("zh", _) => Some("“…”"),
+        _ => None,
+    };
+
+    let punctuation = match language {
+        "es" => Some("Open questions and exclamations with ¿ and ¡."),
+        "fr" => Some("Put a non-breaking space before ; : ! ? and inside « »."),
+        "ja" => Some("Use full-width punctuation (、。！？)."),
+        "zh" => Some("Use full-width punctuation (，。！？：；)."),
+        "ar" | "fa" | "ur" => Some("Use the Arabic comma (،) and question mark (؟)."),
+        _ => None,
+    };
+
+    let plurals = LANGUAGES
+        .iter()
+        .find(|l| l.0 == language)
+        .map(|l| l.2)
+        .unwrap_or_default();
+
+    Conventions {
+        quotes,
+        punctuation,
+        plurals,
+    }
+}
+
+/// Appends the conventions of the locale to the prompt. Nothing is written if none are known.
+pub fn write_prompt(prompt: &mut String, locale: &Locale) {
+    let conventions = conventions(locale);
+    if conventions.quotes.is_none()
+        && conventions.punctuation.is_none()
+        && conventions.plurals.is_empty()
+    {
+        return;
+    }
+
+    writeln!(prompt, "# LOCALE BEGIN").unwrap();
+    writeln!(prompt, "Follow the conventions of the {} locale:", locale).unwrap();
+    if let Some(quotes) = conventions.quotes {
+        writeln!(prompt, "- Quotation marks: {}", quotes).unwrap();
+    }
+    if let Some(punctuation) = conventions.punctuation {
+        writeln!(prompt, "- {}", punctuation).unwrap();
+    }
+    if !conventions.plurals.is_empty() {
+        writeln!(
+            prompt,
+            "- Plural categories: {}",
+            conventions.plurals.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(prompt, "# LOCALE END").unwrap();
+}
@@ -0,0 +1,523 @@
+use quick_xml::{Reader, escape::escape, events::Event};
+use rusqlite::{Connection, params};
+use std::{collections::HashMap, fmt::Write, fs::File, io::Write as iowrite};
+
+use crate::glossary::lang_matches;
+use crate::locale::Language;
+use crate::similarity;
+
+/// How many similar units are sent to the AI per entry.
+const MAX_FUZZY_MATCHES: usize = 3;
+/// How many of the units sharing the most trigrams with a text are scored with chrF.
+const MAX_FUZZY_CANDIDATES: usize = 50;
+
+/// A source -> target pair of the translation memory.
+#[derive(Debug, Default, Clone)]
+pub struct Unit {
+    pub key: String,
+    pub speaker: String,
+    pub source: String,
+    pub target: String,
+}
+
+/// Local translation memory, stored in a SQLite file.
+/// The units of the language pair being translated are also kept in memory for lookups.
+pub struct Memory {
+    conn: Connection,
+    src_lang: String,
+    dst_lang: String,
+    units: Vec<Unit>,
+    /// Indexes of the units whose source contains each character trigram.
+    trigrams: HashMap<[char; 3], Vec<usize>>,
+    /// Minimum chrF (0 to 100) for a unit to be sent to the AI as a reference.
+    fuzzy_threshold: f64,
+}
+
+impl Memory {
+    /// Opens (or creates) the memory at `path` for translating from `src_lang` to `dst_lang`.
+    /// Languages are stored by code, so "es-AR", "es_AR" and "Spanish (Argentina)" are the same.
+    pub fn open(
+        path: &str,
+        src_lang: &str,
+        dst_lang: &str,
+        fuzzy_threshold: f64,
+    ) -> Result<Memory, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS units (
+                src_lang TEXT NOT NULL,
+                dst_lang TEXT NOT NULL,
+                key TEXT NOT NULL,
+                speaker TEXT NOT NULL,
+                source TEXT NOT NULL,
+                target TEXT NOT NULL,
+                PRIMARY KEY (src_lang, dst_lang, key, speaker, source)
+            )",
+            (),
+        )?;
+
+        normalize_languages(&conn)?;
+
+        let src_lang = lang_key(src_lang);
+        let dst_lang = lang_key(dst_lang);
+        let units = load_units(&conn, &src_lang, &dst_lang)?;
+        let mut trigrams: HashMap<[char; 3], Vec<usize>> = HashMap::new();
+        for (i, u) in units.iter().enumerate() {
+            for trigram in distinct_trigrams(&u.source) {
+                trigrams.entry(trigram).or_default().push(i);
+            }
+        }
+
+        Ok(Memory {
+            conn,
+            src_lang,
+            dst_lang,
+            units,
+            trigrams,
+            fuzzy_threshold,
+        })
+    }
+
+    /// How many units of the language pair there were when the memory was opened.
+    pub fn num_units(&self) -> usize {
+        self.units.len()
+    }
+
+    /// Previous translation of exactly the same text.
+    /// Units with the same key, then with the same speaker, are preferred.
+    pub fn exact(&self, key: &str, speaker: &str, source: &str) -> Option<&Unit> {
+        let same_source = || self.units.iter().filter(|u| u.source == source);
+        same_source()
+            .find(|u| !key.is_empty() && u.key == key)
+            .or_else(|| same_source().find(|u| u.speaker == speaker))
+            .or_else(|| same_source().next())
+    }
+
+    /// Indexes of the units worth scoring against `text`: the ones sharing the most trigrams
+    /// with it. Texts too short to have trigrams are compared with every unit.
+    fn candidates(&self, text: &str) -> Vec<usize> {
+        let trigrams = distinct_trigrams(text);
+        if trigrams.is_empty() {
+            return (0..self.units.len()).collect();
+        }
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for trigram in &trigrams {
+            for &i in self.trigrams.get(trigram).into_iter().flatten() {
+                *shared.entry(i).or_insert(0) += 1;
+            }
+        }
+        let mut candidates: Vec<(usize, usize)> = shared.into_iter().collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates.truncate(MAX_FUZZY_CANDIDATES);
+        candidates.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// Units similar (but not identical) to any of the given texts, best first.
+    pub fn matching<'b>(&self, texts: impl Iterator<Item = &'b str>) -> Vec<&Unit> {
+        let mut matches: Vec<(&Unit, f64)> = Vec::new();
+        for text in texts {
+            if text.trim().is_empty() {
+                continue;
+            }
+            let mut scored: Vec<(&Unit, f64)> = self
+                .candidates(text)
+                .into_iter()
+                .map(|i| &self.units[i])
+                .filter(|u| u.source != text)
+                // chrF can't reach the threshold if the lengths are too different.
+                .filter(|u| {
+                    let (a, b) = (u.source.len(), text.len());
+                    a.min(b) * 2 >= a.max(b)
+                })
+                .map(|u| (u, similarity::chrf(&u.source, text)))
+                .filter(|(_, score)| *score >= self.fuzzy_threshold)
+                .collect();
+            scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+            scored.truncate(MAX_FUZZY_MATCHES);
+            for (unit, score) in scored {
+                if !matches.iter().any(|(u, _)| std::ptr::eq(*u, unit)) {
+                    matches.push((unit, score));
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1));
+        matches.into_iter().map(|(u, _)| u).collect()
+    }
+
+    /// Appends the similar translations to the prompt. Nothing is written if there are no matches.
+    pub fn write_prompt(prompt: &mut String, matches: &[&Unit]) {
+        if matches.is_empty() {
+            return;
+        }
+
+        writeln!(prompt, "# TRANSLATION MEMORY BEGIN").unwrap();
+        writeln!(
+            prompt,
+            "Similar texts that were translated before. Use them as reference for terminology and style:"
+        )
+        .unwrap();
+        for u in matches {
+            writeln!(prompt, "- {} => {}", u.source, u.target).unwrap();
+        }
+        writeln!(prompt, "# TRANSLATION MEMORY END").unwrap();
+    }
+
+    /// Stores the accepted translations, replacing previous ones with the same key, speaker
+    /// and source text. They're used from the next time the memory is opened.
+    /// Returns how many were stored.
+    pub fn record(&self, units: &[Unit]) -> Result<usize, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut count = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO units (src_lang, dst_lang, key, speaker, source, target)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for u in units {
+                if u.source.trim().is_empty() || u.target.trim().is_empty() {
+                    continue;
+                }
+                stmt.execute(params![
+                    self.src_lang,
+                    self.dst_lang,
+                    u.key,
+                    u.speaker,
+                    u.source,
+                    u.target
+                ])?;
+                count += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// The units of the language pair, as they were when the memory was opened.
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+}
+
+/// The language as stored in the memory: its code if it's known, otherwise as given.
+fn lang_key(lang: &str) -> String {
+    Language::parse(lang)
+        .ok()
+        .and_then(|l| l.code())
+        .unwrap_or_else(|| lang.trim().to_lowercase())
+}
+
+/// The language as written in xml:lang: its tag, or its code if it was given by name.
+fn tmx_lang(lang: &str) -> String {
+    Language::parse(lang)
+        .ok()
+        .and_then(|l| l.tag().or_else(|| l.code()))
+        .unwrap_or_else(|| lang.trim().to_string())
+}
+
+/// Rewrites the languages stored by memories of previous versions, which kept them as given.
+fn normalize_languages(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let pairs: Vec<(String, String)> = conn
+        .prepare("SELECT DISTINCT src_lang, dst_lang FROM units")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    for (src_lang, dst_lang) in pairs {
+        let (src_key, dst_key) = (lang_key(&src_lang), lang_key(&dst_lang));
+        if src_key != src_lang || dst_key != dst_lang {
+            conn.execute(
+                "UPDATE OR REPLACE units SET src_lang = ?1, dst_lang = ?2
+                WHERE src_lang = ?3 AND dst_lang = ?4",
+                params![src_key, dst_key, src_lang, dst_lang],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The distinct character trigrams of a text, ignoring whitespace as chrF does.
+fn distinct_trigrams(text: &str) -> Vec<[char; 3]> {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut trigrams: Vec<[char; 3]> = chars.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+/// Writes the units translating from `src_lang` to `dst_lang` to a TMX 1.4 file.
+pub fn write_tmx(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+    units: &[Unit],
+) -> Result<(), std::io::Error> {
+    let (src_lang, dst_lang) = (tmx_lang(src_lang), tmx_lang(dst_lang));
+    let mut out = String::new();
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(out, r#"<tmx version="1.4">"#).unwrap();
+    writeln!(
+        out,
+        r#"  <header creationtool="context_translate" creationtoolversion="{}" segtype="block" o-tmf="sqlite" adminlang="en" srclang="{}" datatype="plaintext"/>"#,
+        env!("CARGO_PKG_VERSION"),
+        escape(&src_lang)
+    )
+    .unwrap();
+    writeln!(out, "  <body>").unwrap();
+    for u in units {
+        if u.key.is_empty() {
+            writeln!(out, "    <tu>").unwrap();
+        } else {
+            writeln!(out, r#"    <tu tuid="{}">"#, escape(&u.key)).unwrap();
+        }
+        if !u.speaker.is_empty() {
+            writeln!(
+                out,
+                r#"      <prop type="x-speaker">{}</prop>"#,
+                escape(&u.speaker)
+            )
+            .unwrap();
+        }
+        for (lang, text) in [(&src_lang, &u.source), (&dst_lang, &u.target)] {
+            writeln!(
+                out,
+                r#"      <tuv xml:lang="{}"><seg>{}</seg></tuv>"#,
+                escape(lang),
+                escape(text)
+            )
+            .unwrap();
+        }
+        writeln!(out, "    </tu>").unwrap();
+    }
+    writeln!(out, "  </body>").unwrap();
+    writeln!(out, "</tmx>").unwrap();
+
+    File::create(path)?.write_all(out.as_bytes())
+}
+
+fn load_units(
+    conn: &Connection,
+    src_lang: &str,
+    dst_lang: &str,
+) -> Result<Vec<Unit>, rusqlite::Error> {
+    conn.prepare(
+        "SELECT key, speaker, source, target FROM units WHERE src_lang = ?1 AND dst_lang = ?2",
+    )?
+    .query_map(params![src_lang, dst_lang], |row| {
+        Ok(Unit {
+            key: row.get(0)?,
+            speaker: row.get(1)?,
+            source: row.get(2)?,
+            target: row.get(3)?,
+        })
+    })?
+    .collect()
+}
+
+/// Reads the units of a TMX file translating from `src_lang` to `dst_lang`.
+/// Languages are matched by code (e.g. "es" matches "es-AR"), and may be given by name.
+pub fn read_tmx(
+    path: &str,
+    src_lang: &str,
+    dst_lang: &str,
+) -> Result<Vec<Unit>, Box<dyn std::error::Error>> {
+    // A tag given in the command line also matches a file that only has the language code.
+    let src_langs = [tmx_lang(src_lang), lang_key(src_lang)];
+    let dst_langs = [tmx_lang(dst_lang), lang_key(dst_lang)];
+    let content = std::fs::read_to_string(path)?;
+    let mut reader = Reader::from_str(&content);
+
+    let mut units = Vec::new();
+    let mut unit = Unit::default();
+    // (language, segment) of each tuv of the current tu.
+    let mut variants: Vec<(String, String)> = Vec::new();
+    let mut lang = String::new();
+    let mut in_speaker = false;
+    let mut in_seg = false;
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"tu" => {
+                    variants.clear();
+                    unit = Unit::default();
+                    for a in e.attributes().flatten() {
+                        if a.key.local_name().as_ref() == b"tuid" {
+                            unit.key = a.decode_and_unescape_value(reader.decoder())?.to_string();
+                        }
+                    }
+                }
+                b"prop" => {
+                    in_speaker = e.attributes().flatten().any(|a| {
+                        a.key.local_name().as_ref() == b"type" && a.value.as_ref() == b"x-speaker"
+                    });
+                    text.clear();
+                }
+                b"tuv" => {
+                    lang = e
+                        .attributes()
+                        .flatten()
+                        .find(|a| a.key.local_name().as_ref() == b"lang")
+                        .map(|a| {
+                            a.decode_and_unescape_value(reader.decoder())
+                                .unwrap_or_default()
+                                .to_string()
+                        })
+                        .unwrap_or_default();
+                }
+                b"seg" => {
+                    in_seg = true;
+                    text.clear();
+                }
+                _ => {}
+            },
+            Event::Text(t) if in_seg || in_speaker => {
+                text += &t.decode()?;
+            }
+            Event::GeneralRef(r) if in_seg || in_speaker => {
+                if let Some(c) = r.resolve_char_ref()? {
+                    text.push(c);
+                } else if let Some(s) = quick_xml::escape::resolve_predefined_entity(&r.decode()?) {
+                    text += s;
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"prop" if in_speaker => {
+                    unit.speaker = std::mem::take(&mut text);
+                    in_speaker = false;
+                }
+                b"seg" => {
+                    variants.push((lang.clone(), std::mem::take(&mut text)));
+                    in_seg = false;
+                }
+                b"tu" => {
+                    let find = |langs: &[String]| {
+                        langs
+                            .iter()
+                            .find_map(|l| variants.iter().find(|v| lang_matches(&v.0, l)))
+                    };
+                    if let (Some(source), Some(target)) = (find(&src_langs), find(&dst_langs)) {
+                        unit.source = source.1.clone();
+                        unit.target = target.1.clone();
+                        units.push(std::mem::take(&mut unit));
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(key: &str, source: &str, target: &str) -> Unit {
+        Unit {
+            key: key.to_string(),
+            speaker: String::new(),
+            source: source.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("context_translate_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn tmx_round_trip() {
+        let path = temp_path("round_trip.tmx");
+        let mut units = vec![
+            unit(
+                "greeting",
+                "Hello <friend> & \"you\"",
+                "Hola <amigo> & «tú»",
+            ),
+            unit("", "Goodbye", "Adiós"),
+        ];
+        units[1].speaker = "Anna".to_string();
+        write_tmx(&path, "en", "Spanish (Argentina)", &units).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains(r#"xml:lang="es""#));
+
+        let read = read_tmx(&path, "en-US", "es-AR").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.len(), 2);
+        for (a, b) in read.iter().zip(&units) {
+            assert_eq!(
+                (&a.key, &a.speaker, &a.source, &a.target),
+                (&b.key, &b.speaker, &b.source, &b.target)
+            );
+        }
+    }
+
+    #[test]
+    fn tmx_without_the_language() {
+        let path = temp_path("other_language.tmx");
+        write_tmx(&path, "en", "es-ES", &[unit("", "Hi", "Hola")]).unwrap();
+        assert_eq!(read_tmx(&path, "en", "es").unwrap().len(), 1);
+        assert!(read_tmx(&path, "en", "fr").unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn languages_are_stored_by_code() {
+        let path = temp_path("languages.sqlite");
+        let memory = Memory::open(&path, "en", "es-AR", 0.0).unwrap();
+        memory.record(&[unit("a", "Hello", "Hola")]).unwrap();
+        drop(memory);
+
+        for dst_lang in ["es_AR", "Spanish (Argentina)", "spanish", "es"] {
+            let memory = Memory::open(&path, "English", dst_lang, 0.0).unwrap();
+            assert_eq!(memory.num_units(), 1, "{}", dst_lang);
+        }
+        assert_eq!(Memory::open(&path, "en", "fr", 0.0).unwrap().num_units(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrates_languages_as_given() {
+        let path = temp_path("migrate.sqlite");
+        drop(Memory::open(&path, "en", "es", 0.0).unwrap());
+        Connection::open(&path)
+            .unwrap()
+            .execute(
+                "INSERT INTO units VALUES ('en-US', 'Spanish', 'a', '', 'Hello', 'Hola')",
+                (),
+            )
+            .unwrap();
+
+        let memory = Memory::open(&path, "en", "es-MX", 0.0).unwrap();
+        assert_eq!(memory.units()[0].target, "Hola");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fuzzy_matches() {
+        let path = temp_path("fuzzy.sqlite");
+        let memory = Memory::open(&path, "en", "es", 30.0).unwrap();
+        memory
+            .record(&[
+                unit("a", "Open the red door", "Abre la puerta roja"),
+                unit("b", "Open the blue door", "Abre la puerta azul"),
+                unit("c", "Where is the key?", "¿Dónde está la llave?"),
+            ])
+            .unwrap();
+        let memory = Memory::open(&path, "en", "es", 30.0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let matches = memory.matching(["Open the red doors"].into_iter());
+        let keys: Vec<&str> = matches.iter().map(|u| u.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b"]);
+        // Identical texts are exact matches, not fuzzy ones.
+        let matches = memory.matching(["Where is the key?"].into_iter());
+        assert!(matches.is_empty());
+        assert_eq!(memory.exact("c", "", "Where is the key?").unwrap().key, "c");
+    }
+}
@@ -0,0 +1,452 @@
+use icu_locale_core::locale;
+use log::{info, warn};
+use serde::Deserialize;
+use spreadsheet_ods::color::Rgb;
+use spreadsheet_ods::defaultstyles::DefaultFormat;
+use spreadsheet_ods::{CellStyle, CompressionMethod, OdsWriteOptions, Sheet, Value, WorkBook};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{BlenderTextRow, is_ods, row_key};
+
+/// Number of columns written by format::Ods.
+const ODS_OUTPUT_COLUMNS: u32 = 7;
+
+/// A row of the output of a previous run.
+struct Row {
+    key: String,
+    speaker: String,
+    original: String,
+    text: String,
+    remarks: String,
+}
+
+/// The rows as they were read, so the winning ones can be written back unchanged.
+enum Records {
+    Csv(Vec<BlenderTextRow>),
+    /// The header row and the rows below it.
+    Ods {
+        header: Vec<Value>,
+        rows: Vec<Vec<Value>>,
+    },
+}
+
+struct Run {
+    path: String,
+    rows: Vec<Row>,
+    records: Records,
+    /// Row index of each key.
+    index: HashMap<String, usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelectionRow {
+    #[serde(alias = "Key")]
+    datablock_name: String,
+    #[serde(rename = "Run")]
+    run: usize,
+}
+
+fn load_csv_run(path: &str) -> Result<Run, Box<dyn std::error::Error>> {
+    let records = crate::read_csv(path)?;
+    let rows = records
+        .iter()
+        .enumerate()
+        .map(|(i, r)| Row {
+            key: row_key(&r.datablock_name, i),
+            speaker: r.speaker.clone(),
+            original: r.original.clone().unwrap_or_default(),
+            text: r.text.clone(),
+            remarks: r.remarks.clone().unwrap_or_default(),
+        })
+        .collect();
+    Ok(Run {
+        path: path.to_string(),
+        rows,
+        records: Records::Csv(records),
+        index: HashMap::new(),
+    })
+}
+
+/// Loads the "output" sheet written in ODS key mode.
+fn load_ods_run(path: &str) -> Result<Run, Box<dyn std::error::Error>> {
+    let book = spreadsheet_ods::read_ods(path)?;
+    let sheet = book.sheet(
+        book.sheet_idx("output")
+            .ok_or_else(|| format!("{} has no 'output' sheet", path))?,
+    );
+    let (num_rows, _) = sheet.used_grid_size();
+
+    let record = |row| {
+        (0..ODS_OUTPUT_COLUMNS)
+            .map(|col| sheet.value(row, col).clone())
+            .collect()
+    };
+    let mut rows = Vec::new();
+    let mut records = Vec::new();
+    for row in 1..num_rows {
+        let cell = |col| sheet.value(row, col).as_cow_str_or("").to_string();
+        rows.push(Row {
+            key: row_key(&cell(0), row as usize - 1),
+            speaker: String::new(),
+            original: cell(1),
+            text: cell(2),
+            remarks: cell(4),
+        });
+        records.push(record(row));
+    }
+
+    Ok(Run {
+        path: path.to_string(),
+        rows,
+        records: Records::Ods {
+            header: record(0),
+            rows: records,
+        },
+        index: HashMap::new(),
+    })
+}
+
+fn load_runs(paths: &[String]) -> Result<Vec<Run>, Box<dyn std::error::Error>> {
+    let ods = is_ods(&paths[0]);
+    if paths.iter().any(|p| is_ods(p) != ods) {
+        return Err("All the files to merge must be either CSV or ODS".into());
+    }
+
+    let mut runs = Vec::with_capacity(paths.len());
+    for path in paths {
+        info!("Opening file {}", path);
+        let mut run = if ods {
+            load_ods_run(path)?
+        } else {
+            load_csv_run(path)?
+        };
+        run.index = run
+            .rows
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.key.clone(), i))
+            .collect();
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+/// All the keys, in the order of the first run. Keys missing in it are appended at the end.
+fn aligned_keys(runs: &[Run]) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for run in runs {
+        for row in &run.rows {
+            if seen.insert(row.key.clone()) {
+                keys.push(row.key.clone());
+            }
+        }
+    }
+    keys
+}
+
+/// Writes a workbook with the translation of every run side by side.
+/// Rows where the runs disagree are highlighted.
+fn write_comparison(
+    runs: &[Run],
+    keys: &[String],
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wb = WorkBook::new(locale!("en-US"));
+    let mut style = CellStyle::new("disagree", &DefaultFormat::default());
+    style.set_background_color(Rgb::new(255, 230, 150));
+    let disagree_style = wb.add_cellstyle(style);
+
+    let mut sheet = Sheet::new("compare");
+    let first_run_col = 3;
+    let agree_col = first_run_col + runs.len() as u32;
+    sheet.set_value(0, 0, "Key");
+    sheet.set_value(0, 1, "Speaker");
+    sheet.set_value(0, 2, "Original");
+    for (i, run) in runs.iter().enumerate() {
+        sheet.set_value(
+            0,
+            first_run_col + i as u32,
+            format!("Run {} ({})", i + 1, run.path),
+        );
+    }
+    sheet.set_value(0, agree_col, "Agree");
+    sheet.set_value(0, agree_col + 1, "Remarks");
+
+    let mut num_disagreements = 0;
+    for (i, key) in keys.iter().enumerate() {
+        let row = i as u32 + 1;
+        let found: Vec<Option<&Row>> = runs
+            .iter()
+            .map(|run| run.index.get(key).map(|idx| &run.rows[*idx]))
+            .collect();
+        let first = found.iter().flatten().next();
+
+        sheet.set_value(row, 0, key);
+        if let Some(first) = first {
+            sheet.set_value(row, 1, &first.speaker);
+            sheet.set_value(row, 2, &first.original);
+        }
+
+        let agree = found
+            .iter()
+            .all(|r| r.map(|r| r.text.trim()) == first.map(|f| f.text.trim()));
+        if !agree {
+            num_disagreements += 1;
+        }
+
+        let mut remarks = Vec::new();
+        for (j, r) in found.iter().enumerate() {
+            let col = first_run_col + j as u32;
+            let text = r.map(|r| r.text.as_str()).unwrap_or("(missing)");
+            if agree {
+                sheet.set_value(row, col, text);
+            } else {
+                sheet.set_styled_value(row, col, text, &disagree_style);
+            }
+            if let Some(r) = r
+                && !r.remarks.is_empty()
+            {
+                remarks.push(format!("[{}] {}", j + 1, r.remarks));
+            }
+        }
+        sheet.set_value(row, agree_col, if agree { "yes" } else { "no" });
+        sheet.set_value(row, agree_col + 1, remarks.join("\n"));
+    }
+
+    info!(
+        "{} of {} rows differ between runs",
+        num_disagreements,
+        keys.len()
+    );
+
+    wb.push_sheet(sheet);
+    let mut write = BufWriter::new(File::create(path)?);
+    OdsWriteOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .write_ods(&mut wb, &mut write)?;
+    write.flush()?;
+
+    Ok(())
+}
+
+fn load_selection(path: &str) -> Result<HashMap<String, usize>, Box<dyn std::error::Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .from_reader(File::open(path)?);
+    let mut selection = HashMap::new();
+    for result in rdr.deserialize() {
+        let row: SelectionRow = result?;
+        selection.insert(row.datablock_name, row.run);
+    }
+    Ok(selection)
+}
+
+/// The (run, row) chosen in the selection for each key. Keys that are missing in their
+/// chosen run are taken from the first run that has them.
+fn winners(
+    runs: &[Run],
+    keys: &[String],
+    selection: &HashMap<String, usize>,
+) -> Result<Vec<(usize, usize)>, String> {
+    let mut winners = Vec::with_capacity(keys.len());
+    for key in keys {
+        let chosen = selection.get(key).copied().unwrap_or(1);
+        if chosen == 0 || chosen > runs.len() {
+            return Err(format!("Invalid run {} for {}", chosen, key));
+        }
+        let run = chosen - 1;
+        match runs[run].index.get(key) {
+            Some(idx) => winners.push((run, *idx)),
+            None => {
+                let (fallback, idx) = runs
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, r)| r.index.get(key).map(|idx| (i, *idx)))
+                    .unwrap();
+                warn!(
+                    "{} is missing in run {}. Using run {} instead.",
+                    key,
+                    chosen,
+                    fallback + 1
+                );
+                winners.push((fallback, idx));
+            }
+        }
+    }
+    Ok(winners)
+}
+
+/// Writes the final file, taking each row from the run chosen in the selection.
+fn write_selection(
+    runs: &[Run],
+    keys: &[String],
+    selection: &HashMap<String, usize>,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let winners = winners(runs, keys, selection)?;
+
+    if let Records::Ods { header, .. } = &runs[0].records {
+        let mut wb = WorkBook::new(locale!("en-US"));
+        let mut sheet = Sheet::new("output");
+        for (col, value) in header.iter().enumerate() {
+            sheet.set_value(0, col as u32, value.clone());
+        }
+        for (i, (run, idx)) in winners.into_iter().enumerate() {
+            if let Records::Ods { rows: records, .. } = &runs[run].records {
+                for (col, value) in records[idx].iter().enumerate() {
+                    sheet.set_value(i as u32 + 1, col as u32, value.clone());
+                }
+            }
+        }
+        wb.push_sheet(sheet);
+        let mut write = BufWriter::new(File::create(path)?);
+        OdsWriteOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .write_ods(&mut wb, &mut write)?;
+        write.flush()?;
+    } else {
+        let mut wr = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .from_writer(File::create(path)?);
+        for (run, idx) in winners {
+            if let Records::Csv(records) = &runs[run].records {
+                wr.serialize(&records[idx])?;
+            }
+        }
+        wr.flush()?;
+    }
+
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    /// Output CSVs (or ODS files) of several runs.
+    /// Rows are aligned by datablock_name (or key in ODS files).
+    #[arg(required = true, num_args = 2..)]
+    pub runs: Vec<String>,
+
+    /// CSV with the run (1-based, in the order given) that wins for each row.
+    /// Columns: datablock_name;Run. Rows not listed use run 1.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub selection: Option<String>,
+
+    /// Without --selection, a side-by-side comparison workbook (.ods) highlighting the rows
+    /// where runs disagree. With --selection, the winning run of each row.
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    pub output: String,
+}
+
+/// Merges the outputs of several runs. Writes a comparison workbook, or the final
+/// file if a selection was given.
+pub fn merge(args: &MergeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let runs = load_runs(&args.runs)?;
+    let keys = aligned_keys(&runs);
+
+    match &args.selection {
+        Some(selection_path) => {
+            info!("Opening selection {}", selection_path);
+            let selection = load_selection(selection_path)?;
+            info!("Writing merged results to {}", args.output);
+            write_selection(&runs, &keys, &selection, &args.output)
+        }
+        None => {
+            if !is_ods(&args.output) {
+                return Err("The comparison workbook must be an .ods file (see --output)".into());
+            }
+            info!("Writing comparison to {}", args.output);
+            write_comparison(&runs, &keys, &args.output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("merge_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    fn write_run(name: &str, rows: &[(&str, &str)]) -> String {
+        let path = temp_path(name);
+        let mut contents = "datablock_name;Collection;Text Contents;Original\n".to_string();
+        for (key, text) in rows {
+            contents += &format!("{};Anna;{};Hi\n", key, text);
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn aligns_rows_by_key() {
+        let runs = load_runs(&[
+            write_run("align1.csv", &[("k1", "Hola"), ("", "Adiós"), ("k3", "Sí")]),
+            write_run("align2.csv", &[("k3", "Si"), ("k4", "No"), ("k1", "Hola")]),
+        ])
+        .unwrap();
+        // Rows without a key are aligned by their position.
+        assert_eq!(aligned_keys(&runs), ["k1", "#2", "k3", "k4"]);
+        assert_eq!(runs[1].index["k1"], 2);
+    }
+
+    #[test]
+    fn picks_the_selected_runs() {
+        let runs = load_runs(&[
+            write_run(
+                "pick1.csv",
+                &[("k1", "Hola"), ("k2", "Adiós"), ("k3", "Sí")],
+            ),
+            write_run("pick2.csv", &[("k1", "Buenas"), ("k3", "Claro")]),
+        ])
+        .unwrap();
+        let keys = aligned_keys(&runs);
+
+        let selection = HashMap::from([("k1".to_string(), 2), ("k2".to_string(), 2)]);
+        // k2 is missing in run 2, k3 isn't listed: both come from run 1.
+        assert_eq!(
+            winners(&runs, &keys, &selection).unwrap(),
+            [(1, 0), (0, 1), (0, 2)]
+        );
+
+        let output = temp_path("picked.csv");
+        write_selection(&runs, &keys, &selection, &output).unwrap();
+        let texts: Vec<String> = crate::read_csv(&output)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.text)
+            .collect();
+        assert_eq!(texts, ["Buenas", "Adiós", "Sí"]);
+    }
+
+    #[test]
+    fn rejects_unknown_runs() {
+        let runs = load_runs(&[
+            write_run("invalid1.csv", &[("k1", "Hola")]),
+            write_run("invalid2.csv", &[("k1", "Buenas")]),
+        ])
+        .unwrap();
+        let keys = aligned_keys(&runs);
+        for run in [0, 3] {
+            let selection = HashMap::from([("k1".to_string(), run)]);
+            assert_eq!(
+                winners(&runs, &keys, &selection),
+                Err(format!("Invalid run {} for k1", run))
+            );
+        }
+    }
+
+    #[test]
+    fn reads_selections() {
+        let path = temp_path("selection.csv");
+        std::fs::write(&path, "Key;Run\nk1;2\nk2;1\n").unwrap();
+        let selection = load_selection(&path).unwrap();
+        assert_eq!(selection["k1"], 2);
+        assert_eq!(selection["k2"], 1);
+    }
+}
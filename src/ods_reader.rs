@@ -1,85 +1,128 @@
 use icu_locale_core::locale;
+use log::info;
 use spreadsheet_ods::{CompressionMethod, OdsWriteOptions, Sheet};
 use std::io::BufWriter;
 use std::{fs::File, io::Write as iowrite};
 
+use crate::dedup;
 use crate::error::Error;
-use crate::{Args, open_ai};
-struct Entry {
-    key_name: String,
-    text: String,
+use crate::examples::Examples;
+use crate::format::{self, Output, Pipeline};
+use crate::glossary::Glossary;
+use crate::incremental;
+use crate::locale::{self, Language};
+use crate::memory::Memory;
+use crate::placeholders::{self, ProtectedText};
+use crate::target::Target;
+use crate::{
+    AI_GAVE_UP, Args, BackScoring, BlenderTextRow, Prompts, mark_duplicates, open_ai,
+    review_blender_lines, row_key, score_blender_lines, translate_blender_lines,
+};
+
+/// How the key/value pipeline writes its prompts (see Prompts::KeyValue).
+#[derive(Clone)]
+pub struct KeyValuePrompts<'a> {
+    /// Language of the texts, as told to the AI.
+    pub src_lang: &'a str,
+    /// Names of the context fields of the entries (see Document::context_names).
+    pub context_names: &'a [String],
+    /// Where the translations done so far are written after each batch, if anywhere.
+    pub partial_output: Option<&'a Output<'a>>,
 }
 
-struct LangSet {
-    lang: String,
-    entries: Vec<Entry>,
-}
-
-fn load_ods(path: &str, columns_to_use: &Vec<u32>) -> Vec<LangSet> {
-    let book = spreadsheet_ods::read_ods(path).expect(&format!("Error opening ODS {}", path));
-
-    let all = book.sheet(book.sheet_idx("all").unwrap());
-    let (num_rows, __) = all.used_grid_size();
-
-    let mut retval = Vec::with_capacity(columns_to_use.len());
-
-    for col in columns_to_use {
-        let col = *col;
+impl KeyValuePrompts<'_> {
+    pub fn generate(&self, entries_to_translate: &[BlenderTextRow], target: &Target<'_>) -> String {
+        let mut prompt = String::new();
+        prompt += &format!("Translate from {} to: {}", self.src_lang, target.language);
+        if let Some(locale) = target.locale {
+            prompt += "\n\n";
+            locale::write_prompt(&mut prompt, locale);
+        }
 
-        let lang = all.value(0, col).as_str_or_default();
+        if let Some(glossary) = target.glossary {
+            let matches = glossary.matching(entries_to_translate.iter().map(|e| e.text.as_str()));
+            if !matches.is_empty() {
+                prompt += "\n\n";
+                Glossary::write_prompt(&mut prompt, &matches);
+            }
+        }
+        if let Some(memory) = target.memory {
+            let matches = memory.matching(entries_to_translate.iter().map(|e| e.text.as_str()));
+            if !matches.is_empty() {
+                prompt += "\n\n";
+                Memory::write_prompt(&mut prompt, &matches);
+            }
+        }
+        if let Some(examples) = target.examples {
+            let matches = examples.matching(entries_to_translate.iter().map(|e| e.text.as_str()));
+            if !matches.is_empty() {
+                prompt += "\n\n";
+                Examples::write_prompt(&mut prompt, &matches);
+            }
+        }
+        if let Some(formality) = target.formality {
+            // There are no speakers in ODS mode, so only the global policy applies.
+            let mut section = String::new();
+            formality.write_prompt(&mut section, std::iter::empty());
+            if !section.is_empty() {
+                prompt += "\n\n";
+                prompt += &section;
+            }
+        }
+        if entries_to_translate.iter().any(|e| e.text.contains("⟦P")) {
+            prompt += "\n\n";
+            placeholders::write_prompt(
+                &mut prompt,
+                entries_to_translate.iter().map(|e| e.text.as_str()),
+            );
+        }
 
-        let mut lang_set = LangSet {
-            lang: lang.to_string(),
-            entries: Vec::with_capacity((num_rows - 1) as usize),
-        };
+        // Entries without a key are named by their position in the prompt.
+        for (i, e) in entries_to_translate.iter().enumerate() {
+            prompt += &format!("\n\n# {}\n{}", row_key(&e.datablock_name, i), e.text);
 
-        for row in 1..num_rows {
-            let key = all.value(row, 0).as_str_or_default();
-
-            let value = all.value(row, col).as_cow_str_or("");
-            if !value.is_empty() && value != "#N/A" {
-                lang_set.entries.push(Entry {
-                    key_name: key.to_string(),
-                    text: value.to_string(),
-                });
-            } else {
-                lang_set.entries.push(Entry {
-                    key_name: key.to_string(),
-                    text: String::new(),
-                });
+            let context: Vec<(&String, &String)> = self
+                .context_names
+                .iter()
+                .zip(&e.context)
+                .filter(|(_, text)| !text.is_empty())
+                .collect();
+            if !context.is_empty() {
+                prompt += "\n\n## Additional Context";
+                for (name, text) in context {
+                    prompt += &format!("\n\n### {}\n{}", name, text);
+                }
             }
         }
 
-        retval.push(lang_set);
+        prompt
     }
 
-    retval
-}
-
-fn process_ai_response(
-    response: &String,
-    entries: &[Entry],
-    orig_prompt: &String,
-    error_log: &mut File,
-) -> Result<Vec<Entry>, Error> {
-    let r = process_ai_response_impl(response, entries);
-    match &r {
-        Ok(_) => {}
-        Err(_) => {
-            writeln!(error_log, "# ERROR LOG Invalid response:").ok();
-            writeln!(error_log, "==============================").ok();
-            writeln!(error_log, "{}", response).ok();
-            writeln!(error_log, "==============================").ok();
-            writeln!(error_log, "# ERROR LOG Original Prompt:").ok();
-            writeln!(error_log, "==============================").ok();
-            writeln!(error_log, "{}", orig_prompt).ok();
-            writeln!(error_log, "==============================").ok();
-        }
+    /// Writes the translations done so far (see `partial_output`), with their placeholders restored.
+    pub fn write_partial(
+        &self,
+        protected: &[ProtectedText],
+        translated: &[BlenderTextRow],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(output) = self.partial_output else {
+            return Ok(());
+        };
+        let restored: Vec<BlenderTextRow> = translated
+            .iter()
+            .zip(protected)
+            .map(|(t, p)| BlenderTextRow {
+                text: p.restore(&t.text).0,
+                ..t.clone()
+            })
+            .collect();
+        output.write(&output_rows(output, &restored, None))
     }
-    r
 }
 
-fn process_ai_response_impl(response: &String, entries: &[Entry]) -> Result<Vec<Entry>, Error> {
+pub fn process_ai_response_impl(
+    response: &str,
+    entries: &[BlenderTextRow],
+) -> Result<Vec<BlenderTextRow>, Error> {
     if response.is_empty() {
         return Err(Error::InvalidTranslation);
     }
@@ -97,7 +140,7 @@ fn process_ai_response_impl(response: &String, entries: &[Entry]) -> Result<Vec<
             return Err(Error::InvalidTranslation);
         }
 
-        let pattern = format!("# {}\n", entry.key_name);
+        let pattern = format!("# {}\n", row_key(&entry.datablock_name, i));
         let haystack = response[start_idx..]
             .find(&pattern)
             .ok_or(Error::InvalidTranslation)?;
@@ -105,7 +148,8 @@ fn process_ai_response_impl(response: &String, entries: &[Entry]) -> Result<Vec<
         let end_idx = if i + 1 == entries.len() {
             response.len()
         } else {
-            match response[start_idx..].find(&format!("# {}\n", entries[i + 1].key_name)) {
+            let next = format!("# {}\n", row_key(&entries[i + 1].datablock_name, i + 1));
+            match response[start_idx..].find(&next) {
                 Some(idx) => start_idx + idx,
                 None => return Err(Error::InvalidTranslation),
             }
@@ -113,9 +157,11 @@ fn process_ai_response_impl(response: &String, entries: &[Entry]) -> Result<Vec<
 
         let text = response[start_idx..end_idx].trim_start().trim_end();
 
-        translated.push(Entry {
-            key_name: entry.key_name.clone(),
+        translated.push(BlenderTextRow {
+            datablock_name: entry.datablock_name.clone(),
             text: text.to_string(),
+            original: Some(entry.text.clone()),
+            ..Default::default()
         });
 
         start_idx = end_idx
@@ -124,121 +170,38 @@ fn process_ai_response_impl(response: &String, entries: &[Entry]) -> Result<Vec<
     Ok(translated)
 }
 
-async fn translate_lang_set(
-    args: &Args,
-    dst_lang: &str,
-    error_log: &mut File,
-    ai_settings: &open_ai::AiSettings<'_>,
-    lang_sets: &[LangSet],
-    partial_output: bool,
-) -> Result<LangSet, Box<dyn std::error::Error>> {
-    let (main_lang, context) = lang_sets.split_at(1);
-
-    let src_lang: &LangSet = &main_lang[0];
-    let mut dst_lang_set = LangSet {
-        lang: dst_lang.to_string(),
-        entries: Vec::with_capacity(src_lang.entries.len()),
-    };
-
-    let entries_per_query = args.batch_size as usize;
-    let num_batches = (src_lang.entries.len() + entries_per_query - 1) / entries_per_query;
-
-    let mut prompt = String::new();
-    for i in (0..src_lang.entries.len()).step_by(entries_per_query) {
-        println!("Batch ID {} / {}", i / entries_per_query, num_batches);
-
-        let from = i;
-        let to = std::cmp::min(i + entries_per_query, src_lang.entries.len());
-
-        prompt.clear();
-        prompt += &format!("Translate from {} to: {}", src_lang.lang, dst_lang);
-
-        let entries_to_translate = &src_lang.entries[from..to];
-
-        for (j, e) in entries_to_translate.iter().enumerate() {
-            prompt += &format!("\n\n# {}\n{}", e.key_name, e.text);
-
-            if !context.is_empty() {
-                let mut had_context = false;
-                let old_len = prompt.len();
-                prompt += "\n\n## Additional Context";
-
-                for c in context {
-                    let ce = &c.entries[from + j];
-                    if !ce.text.is_empty() {
-                        prompt += &format!("\n\n### {}\n{}", c.lang, ce.text);
-                        had_context = true;
-                    }
-                }
-                if !had_context {
-                    prompt.truncate(old_len)
-                }
-            }
-        }
-
-        let mut response = open_ai::run_prompt(ai_settings, &prompt).await?;
-
-        let num_retries = 9;
-        let mut translated = {
-            let mut translated_result = Vec::new();
-            for j in 0..num_retries {
-                let translated =
-                    process_ai_response(&response, entries_to_translate, &prompt, error_log);
-                match translated {
-                    Ok(t) => translated_result = t,
-                    Err(_) => {
-                        if j + 1 == num_retries {
-                            eprintln!("Invalid Translation Output. Attempt {}. Giving up.", j);
-                            for entry in entries_to_translate {
-                                translated_result.push(Entry {
-                                    key_name: entry.key_name.to_string(),
-                                    text: "AI ERROR. GIVEN UP.".to_string(),
-                                });
-                            }
-                        } else {
-                            eprintln!("Invalid Translation Output. Attempt {}. Retrying...", j);
-                            response = open_ai::run_prompt(ai_settings, &prompt).await?;
-                        }
-                    }
-                }
-            }
-            translated_result
-        };
-
-        dst_lang_set.entries.append(&mut translated);
-
-        if partial_output {
-            write_ods(args, &dst_lang_set, main_lang, None)?;
-        }
-    }
-
-    Ok(dst_lang_set)
-}
-
-fn write_ods(
-    args: &Args,
-    dst_lang: &LangSet,
-    lang_sets: &[LangSet],
-    original_back: Option<LangSet>,
+/// Writes a workbook with the key, the source text and one column per language, taken from
+/// the output of each language (`outputs` are (language, path) pairs).
+pub fn write_languages(
+    path: &str,
+    outputs: &[(&str, &str)],
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut sheet = Sheet::new("output");
-    let mut wb = spreadsheet_ods::WorkBook::new(locale!("en-US"));
-
-    for (i, e) in dst_lang.entries.iter().enumerate() {
-        let row = i as u32 + 1;
-        sheet.set_value(row, 0, &e.key_name);
-        sheet.set_value(row, 1, &lang_sets[0].entries[i].text);
-        sheet.set_value(row, 2, &e.text);
-
-        if let Some(original_back) = &original_back {
-            sheet.set_value(row, 3, &original_back.entries[i].text);
+    sheet.set_value(0, 0, "Key");
+    sheet.set_value(0, 1, "Source");
+
+    for (i, (language, output_path)) in outputs.iter().enumerate() {
+        let col = i as u32 + 2;
+        sheet.set_value(0, col, *language);
+
+        let book = spreadsheet_ods::read_ods(output_path)?;
+        let output = book.sheet(
+            book.sheet_idx("output")
+                .ok_or_else(|| format!("{} has no 'output' sheet", output_path))?,
+        );
+        let (num_rows, _) = output.used_grid_size();
+        for row in 1..num_rows {
+            if i == 0 {
+                sheet.set_value(row, 0, output.value(row, 0).clone());
+                sheet.set_value(row, 1, output.value(row, 1).clone());
+            }
+            sheet.set_value(row, col, output.value(row, 2).clone());
         }
     }
 
+    let mut wb = spreadsheet_ods::WorkBook::new(locale!("en-US"));
     wb.push_sheet(sheet);
-
-    let file = File::create(&args.dst_csv)?;
-    let mut write = BufWriter::new(file);
+    let mut write = BufWriter::new(File::create(path)?);
     OdsWriteOptions::default()
         .compression_method(CompressionMethod::Deflated)
         .write_ods(&mut wb, &mut write)?;
@@ -247,66 +210,194 @@ fn write_ods(
     Ok(())
 }
 
-pub async fn translate_key_mode_ods(
+/// Translates the entries of `document` as independent keys, each one with its context fields,
+/// and writes them to `output` (also after each batch). Returns the translated entries.
+pub async fn translate_key_value(
     args: &Args,
+    output: &Output<'_>,
     error_log: &mut File,
     ai_settings: &open_ai::AiSettings<'_>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let columns_to_use: Vec<u32> = args
-        .ods_key_mode_columns
-        .split(",")
-        .map(|v| {
-            v.parse()
-                .expect("ods_key_mode_columns must contain numbers and commas only!")
-        })
-        .collect();
-
-    // Process main translations.
-    let lang_sets = load_ods(&args.src_csv, &columns_to_use);
-
-    if lang_sets.is_empty() {
-        panic!("No languages found in ODS file?!");
+    target: &Target<'_>,
+) -> Result<Vec<BlenderTextRow>, Box<dyn std::error::Error>> {
+    let mut lines = output.document.entries.clone();
+    let src_lang = output
+        .document
+        .language
+        .as_deref()
+        .or(args.src_lang.as_deref())
+        .unwrap_or("the original language");
+
+    if let Some(path) = &args.previous {
+        info!("Opening previous output {}", path);
+        let previous = format::read(path, &args.read.read_options())?;
+        let num_reused = incremental::attach_previous(&mut lines, previous.entries);
+        info!("Reusing {} of {} entries", num_reused, lines.len());
+    }
+    if let Some(memory) = target.memory {
+        let num_reused = incremental::attach_memory(&mut lines, memory);
+        info!("{} entries found in the translation memory", num_reused);
+    }
+    if args.dedup != dedup::DedupPolicy::Off {
+        let num_duplicates = mark_duplicates(args.dedup, &mut lines, Pipeline::KeyValue);
+        info!(
+            "{} repeated entries will copy the translation of their first occurrence",
+            num_duplicates
+        );
     }
 
-    let mut dst_lang = translate_lang_set(
-        args,
-        &args.dst_lang,
-        error_log,
-        ai_settings,
-        &lang_sets,
-        true,
-    )
-    .await?;
+    let batch_size = args.batch.batch_size as usize;
+    let prompts = Prompts::KeyValue(KeyValuePrompts {
+        src_lang,
+        context_names: &output.document.context_names,
+        partial_output: Some(output),
+    });
+    let mut translated =
+        translate_blender_lines(batch_size, &prompts, &lines, ai_settings, target, error_log)
+            .await?;
 
-    write_ods(args, &dst_lang, &lang_sets, None)?;
+    output.write(&output_rows(output, &translated, None))?;
 
     let original_back = match &args.src_lang {
         Some(src_lang) => {
-            println!("Main translation done. Beginning translation of original_back");
-
-            let mut tmp_dst_lang = [dst_lang];
-            let result = translate_lang_set(
-                args,
-                &src_lang,
+            info!("Main translation done. Beginning translation of original_back");
+            let src_language = Language::parse(src_lang)?;
+
+            // Entries are translated back without their context fields.
+            let scoring = BackScoring {
+                scorer: args.back_score.scorer(&args.ai.model),
+                prompts: prompts.clone(),
+                back_prompts: Prompts::KeyValue(KeyValuePrompts {
+                    src_lang: target.language,
+                    context_names: &[],
+                    partial_output: None,
+                }),
+                target: target.back(&src_language.name),
+            };
+            let mut back = translate_blender_lines(
+                batch_size,
+                &scoring.back_prompts,
+                &translated,
+                ai_settings,
+                &scoring.target,
                 error_log,
+            )
+            .await?;
+            score_blender_lines(
+                &scoring,
+                &lines,
+                &mut translated,
+                &mut back,
                 ai_settings,
-                &tmp_dst_lang,
-                false,
+                target,
+                error_log,
             )
             .await?;
-            dst_lang = std::mem::replace(
-                &mut tmp_dst_lang[0],
-                LangSet {
-                    lang: String::new(),
-                    entries: Vec::new(),
-                },
-            );
-            Some(result)
+            Some(back)
         }
         None => None,
     };
 
-    write_ods(args, &dst_lang, &lang_sets, original_back)?;
+    if args.review {
+        info!("Begin Review");
+        review_blender_lines(
+            args,
+            &prompts,
+            &lines,
+            &mut translated,
+            ai_settings,
+            target,
+            error_log,
+        )
+        .await?;
+    }
+
+    if let Some(memory) = target.memory {
+        let recorded = memory.record(&incremental::accepted_units(&lines, &translated))?;
+        info!(
+            "Recorded {} translations in the translation memory",
+            recorded
+        );
+    }
 
-    Ok(())
+    let rows = output_rows(output, &translated, original_back.as_deref());
+    output.write(&rows)?;
+
+    Ok(rows)
+}
+
+/// The translated entries in the shape of the CSV output. Entries the AI gave up on
+/// have it written as their text too, so they stand out in the spreadsheet.
+fn output_rows(
+    output: &Output<'_>,
+    translated: &[BlenderTextRow],
+    original_back: Option<&[BlenderTextRow]>,
+) -> Vec<BlenderTextRow> {
+    translated
+        .iter()
+        .zip(&output.document.entries)
+        .enumerate()
+        .map(|(i, (t, source))| {
+            let remarks = t.remarks.clone().unwrap_or_default();
+            let gave_up = t.text.is_empty() && remarks.contains(AI_GAVE_UP);
+            BlenderTextRow {
+                datablock_name: source.datablock_name.clone(),
+                speaker: source.speaker.clone(),
+                text: match gave_up {
+                    true => AI_GAVE_UP.to_string(),
+                    false => t.text.clone(),
+                },
+                original: Some(source.text.clone()),
+                original_back: original_back.map(|b| b[i].text.clone()),
+                back_score: t.back_score,
+                back_similarity: t.back_similarity,
+                review_accuracy: t.review_accuracy,
+                review_fluency: t.review_fluency,
+                review_errors: t.review_errors.clone(),
+                review_suggestion: t.review_suggestion.clone(),
+                remarks: Some(remarks),
+                locale: output.language.tag(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, text: &str) -> BlenderTextRow {
+        BlenderTextRow {
+            datablock_name: key.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entries_without_keys_are_numbered() {
+        let entries = [
+            entry("", "Start"),
+            entry("menu.quit", "Quit"),
+            entry(" ", "Back"),
+        ];
+        let prompts = KeyValuePrompts {
+            src_lang: "English",
+            context_names: &[],
+            partial_output: None,
+        };
+        let prompt = prompts.generate(&entries, &Target::new("Spanish"));
+        assert!(prompt.contains("\n\n# #1\nStart"), "{}", prompt);
+        assert!(prompt.contains("\n\n# menu.quit\nQuit"), "{}", prompt);
+        assert!(prompt.contains("\n\n# #3\nBack"), "{}", prompt);
+
+        let response = "# #1\nEmpezar\n\n# menu.quit\nSalir\n\n# #3\nVolver\n";
+        let translated = process_ai_response_impl(response, &entries).unwrap();
+        let texts: Vec<&str> = translated.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(texts, ["Empezar", "Salir", "Volver"]);
+        assert_eq!(translated[0].datablock_name, "");
+
+        let missing = "# #1\nEmpezar\n\n# #3\nVolver\n";
+        assert!(process_ai_response_impl(missing, &entries).is_err());
+    }
 }
@@ -1,10 +1,17 @@
+use std::ops::ControlFlow;
 use std::time::Duration;
 
+use futures::FutureExt;
+use log::{error, info, warn};
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 
+use crate::backend::{
+    self, Backend, Completion, CompletionFuture, EmbeddingsFuture, FinishReason, Message, Role,
+};
 use crate::error;
 
+#[derive(Clone)]
 pub struct AiSettings<'a> {
     pub endpoint: String,
     pub api_key: String,
@@ -13,133 +20,458 @@ pub struct AiSettings<'a> {
     pub timeout_secs: u64,
     pub extra_options: Option<&'a serde_json::Map<String, serde_json::Value>>,
     pub debug: bool,
+    /// Where the prompts are sent (see --backend).
+    pub backend: &'a dyn Backend,
+    /// Receive the answers as they're generated (see --stream).
+    pub stream: bool,
+}
+
+/// Log target of the pieces of the answers shown as they arrive (--stream with --debug).
+/// They aren't whole lines, so they must be printed as they are.
+pub const STREAM_LOG_TARGET: &str = "context_translate::stream";
+
+fn headers(api_key: &str) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+    );
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(headers)
 }
 
 #[derive(Serialize)]
 struct ChatRequest<'a> {
     model: &'a str,
-    messages: Vec<Message<'a>>,
+    messages: Vec<ChatMessage<'a>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
 }
 
 #[derive(Serialize)]
-struct Message<'a> {
+struct ChatMessage<'a> {
     role: &'static str,
     content: &'a str,
 }
 
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
 #[derive(Deserialize, Debug)]
 struct ChatResponse {
+    #[serde(default)]
     choices: Vec<Choice>,
+    usage: Option<ChatUsage>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Choice {
+    #[serde(alias = "delta")]
     message: MessageResponse,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct MessageResponse {
-    role: String,
-    content: String,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl From<ChatUsage> for backend::Usage {
+    fn from(usage: ChatUsage) -> Self {
+        backend::Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+/// Any OpenAI-compatible chat completions endpoint (OpenAI, llama.cpp, vLLM, Ollama, ...).
+#[derive(Default)]
+pub struct OpenAi {
+    /// Shared by all the requests, so connections are reused.
+    client: reqwest::Client,
+}
+
+impl OpenAi {
+    pub fn new() -> OpenAi {
+        OpenAi::default()
+    }
+
+    /// Posts the messages. The --llm-options are merged into the request.
+    async fn post(
+        &self,
+        settings: &AiSettings<'_>,
+        messages: &[Message<'_>],
+        stream: bool,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+        let request_body = ChatRequest {
+            model: &settings.model,
+            messages: messages
+                .iter()
+                .map(|m| ChatMessage {
+                    role: m.role.as_str(),
+                    content: m.content,
+                })
+                .collect(),
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+
+        let mut request_body = serde_json::to_value(&request_body)?;
+        if let Some(extra_opts) = settings.extra_options {
+            let obj = request_body.as_object_mut().unwrap();
+            for (k, v) in extra_opts {
+                obj.insert(k.clone(), v.clone());
+            }
+        }
+
+        let headers = headers(&settings.api_key)?;
+        let res = self
+            .client
+            .post(&settings.endpoint)
+            .headers(headers)
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            let status_code = res.status();
+            error!("Error: {}", res.text().await?);
+            return Err(Box::new(error::Error::HttpStatus(status_code.as_u16())));
+        }
+        Ok(res)
+    }
+
+    /// Requests the embeddings from an OpenAI-compatible embeddings endpoint
+    /// (e.g. http://127.0.0.1:8081/v1/embeddings).
+    async fn embeddings(
+        &self,
+        settings: &AiSettings<'_>,
+        endpoint: &str,
+        model: &str,
+        texts: &[&str],
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let headers = headers(&settings.api_key)?;
+        let res = self
+            .client
+            .post(endpoint)
+            .headers(headers)
+            .json(&EmbeddingsRequest {
+                model,
+                input: texts,
+            })
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            let status_code = res.status();
+            error!("Error: {}", res.text().await?);
+            return Err(Box::new(error::Error::HttpStatus(status_code.as_u16())));
+        }
+
+        let mut response: EmbeddingsResponse = res.json().await?;
+        if response.data.len() != texts.len() {
+            return Err(Box::new(error::Error::InvalidTranslation));
+        }
+        response.data.sort_by_key(|e| e.index);
+
+        Ok(response.data.into_iter().map(|e| e.embedding).collect())
+    }
+
+    async fn complete(
+        &self,
+        settings: &AiSettings<'_>,
+        messages: &[Message<'_>],
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let timeout = Duration::from_secs(settings.timeout_secs);
+        let res = tokio::time::timeout(timeout, async {
+            let res = self.post(settings, messages, false).await?;
+            Ok::<ChatResponse, Box<dyn std::error::Error>>(res.json().await?)
+        });
+        let chat_response = match res.await {
+            Ok(res) => res?,
+            Err(_) => return Err(Box::new(error::Error::Timeout)),
+        };
+
+        // Only the last choice is used.
+        let choice = chat_response.choices.into_iter().last();
+        Ok(Completion {
+            finish_reason: choice
+                .as_ref()
+                .and_then(|c| c.finish_reason.as_deref())
+                .map(FinishReason::parse),
+            text: choice.and_then(|c| c.message.content).unwrap_or_default(),
+            usage: chat_response.usage.map(Into::into),
+        })
+    }
+
+    /// Reads the server-sent events of a streamed answer. The timeout applies to the wait for
+    /// each event, not to the whole answer.
+    async fn complete_streamed(
+        &self,
+        settings: &AiSettings<'_>,
+        messages: &[Message<'_>],
+        on_text: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let timeout = Duration::from_secs(settings.timeout_secs);
+        let mut res = match tokio::time::timeout(timeout, self.post(settings, messages, true)).await
+        {
+            Ok(res) => res?,
+            Err(_) => return Err(Box::new(error::Error::Timeout)),
+        };
+
+        let mut completion = Completion::default();
+        let mut pending = Vec::new();
+        loop {
+            let chunk = match tokio::time::timeout(timeout, res.chunk()).await {
+                Ok(chunk) => chunk?,
+                Err(_) => return Err(Box::new(error::Error::Timeout)),
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            pending.extend_from_slice(&chunk);
+
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                if read_event_line(&line, &mut completion, on_text)?.is_break() {
+                    return Ok(completion);
+                }
+            }
+        }
+        Ok(completion)
+    }
 }
 
+/// Adds a line of a streamed answer to `completion`, calling `on_text` with its text.
+/// Breaks at the end of the answer ("data: [DONE]").
+fn read_event_line(
+    line: &str,
+    completion: &mut Completion,
+    on_text: &mut (dyn FnMut(&str) + Send),
+) -> Result<ControlFlow<()>, Box<dyn std::error::Error>> {
+    // Events are separated by a blank line. Only the "data:" lines matter.
+    let Some(data) = line.trim().strip_prefix("data:") else {
+        return Ok(ControlFlow::Continue(()));
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(ControlFlow::Break(()));
+    }
+    let event: ChatResponse = serde_json::from_str(data)?;
+    if let Some(usage) = event.usage {
+        completion.usage = Some(usage.into());
+    }
+    for choice in event.choices {
+        if let Some(text) = choice.message.content {
+            on_text(&text);
+            completion.text += &text;
+        }
+        if let Some(reason) = choice.finish_reason {
+            completion.finish_reason = Some(FinishReason::parse(&reason));
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+impl Backend for OpenAi {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn send<'a>(
+        &'a self,
+        settings: &'a AiSettings<'_>,
+        messages: &'a [Message<'a>],
+    ) -> CompletionFuture<'a> {
+        self.complete(settings, messages).boxed()
+    }
+
+    fn stream<'a>(
+        &'a self,
+        settings: &'a AiSettings<'_>,
+        messages: &'a [Message<'a>],
+        on_text: &'a mut (dyn FnMut(&str) + Send),
+    ) -> CompletionFuture<'a> {
+        self.complete_streamed(settings, messages, on_text).boxed()
+    }
+
+    fn embed<'a>(
+        &'a self,
+        settings: &'a AiSettings<'_>,
+        endpoint: &'a str,
+        model: &'a str,
+        texts: &'a [&'a str],
+    ) -> EmbeddingsFuture<'a> {
+        self.embeddings(settings, endpoint, model, texts).boxed()
+    }
+}
+
+/// Sends the system prompt and `prompt` to the backend of `ai_data` and returns its answer.
+/// If the backend takes too long, the answer is empty.
 pub async fn run_prompt(
     ai_data: &AiSettings<'_>,
     prompt: &str,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    // println!("Running Prompt:\n{}", prompt);
-
     if ai_data.debug {
-        println!(
+        info!(
             "==============\nSYSTEM PROMPT\n==============\n{}",
             &ai_data.system_prompt
         );
-        println!("==============\nNORMAL PROMPT\n==============\n{}", prompt);
+        info!("==============\nNORMAL PROMPT\n==============\n{}", prompt);
     }
 
-    let client = reqwest::Client::new();
+    let messages = [
+        Message {
+            role: Role::System,
+            content: &ai_data.system_prompt,
+        },
+        Message {
+            role: Role::User,
+            content: prompt,
+        },
+    ];
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", ai_data.api_key))?,
-    );
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-    // Build request body
-    let request_body = ChatRequest {
-        model: &ai_data.model,
-        messages: vec![
-            Message {
-                role: "system",
-                content: &ai_data.system_prompt,
-            },
-            Message {
-                role: "user",
-                content: &prompt,
-            },
-        ],
-    };
-
-    let request_body = {
-        match ai_data.extra_options {
-            Some(extra_opts) => {
-                let mut merged = serde_json::to_value(&request_body).unwrap();
-                let obj = merged.as_object_mut().unwrap();
-                for (k, v) in extra_opts {
-                    obj.insert(k.clone(), v.clone());
-                }
-                merged
+    let completion = if ai_data.stream {
+        let debug = ai_data.debug;
+        if debug {
+            info!("==============\nRESPONSE\n==============");
+        }
+        let mut on_text = |text: &str| {
+            if debug {
+                info!(target: STREAM_LOG_TARGET, "{}", text);
             }
-            None => serde_json::to_value(&request_body).unwrap(),
+        };
+        ai_data
+            .backend
+            .stream(ai_data, &messages, &mut on_text)
+            .await
+    } else {
+        ai_data.backend.send(ai_data, &messages).await
+    };
+    let completion = match completion {
+        Ok(completion) => completion,
+        Err(e) if matches!(e.downcast_ref(), Some(error::Error::Timeout)) => {
+            warn!("AI took too long. Aborting.");
+            return Ok(String::new());
         }
+        Err(e) => return Err(e),
     };
 
-    // println!("JSON:\n{}", request_body);
+    if completion.finish_reason == Some(FinishReason::Length) {
+        warn!("The AI reached its token limit. The answer is cut off.");
+    }
+    if ai_data.debug {
+        // Streamed answers were already shown as they arrived.
+        match ai_data.stream {
+            true => info!(target: STREAM_LOG_TARGET, "\n"),
+            false => info!(
+                "==============\nRESPONSE\n==============\n{}",
+                completion.text
+            ),
+        }
+        if let Some(usage) = completion.usage {
+            info!(
+                "{} input tokens, {} output tokens",
+                usage.input_tokens, usage.output_tokens
+            );
+        }
+    }
 
-    // Send POST request
-    let res = client
-        .post(&ai_data.endpoint)
-        .headers(headers)
-        .json(&request_body)
-        .send();
+    Ok(completion.text)
+}
 
-    let res = match tokio::time::timeout(Duration::from_secs(ai_data.timeout_secs), res).await {
-        Ok(res) => res?,
-        Err(_) => {
-            eprintln!("AI took too long. Aborting.");
-            return Ok("".to_string());
-        }
-    };
+#[derive(Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingsResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
 
-    if !res.status().is_success() {
-        let status_code = res.status();
-        eprintln!("Error: {}", res.text().await?);
-        return Err(Box::new(error::Error::HttpStatus(status_code.as_u16())));
-    }
-
-    // Parse response
-    let mut chat_response: ChatResponse = res.json().await?;
-    /*for choice in &chat_response.choices {
-        println!("AI Output:\n{}", choice.message.content);
-    }*/
-
-    let response = {
-        if !chat_response.choices.is_empty() {
-            let last_idx = chat_response.choices.len() - 1;
-            chat_response.choices.swap(0, last_idx);
-            chat_response.choices.pop().unwrap().message.content
-        } else {
-            String::new()
+/// How many texts are sent per embeddings request.
+pub const EMBEDDINGS_BATCH: usize = 64;
+
+/// Returns the embedding of each text, in the same order, from the backend of `ai_data`.
+pub async fn embed(
+    ai_data: &AiSettings<'_>,
+    endpoint: &str,
+    model: &str,
+    texts: &[&str],
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+    if texts.is_empty() {
+        return Ok(Vec::new());
+    }
+    ai_data.backend.embed(ai_data, endpoint, model, texts).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_event_lines() {
+        let lines = [
+            ": keep-alive\n",
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"},\"finish_reason\":null}]}\n",
+            "\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"# k1\\nHo\"}}]}\n",
+            "data:{\"choices\":[{\"delta\":{\"content\":\"la\"},\"finish_reason\":\"length\"}]}\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":3}}\n",
+            "data: [DONE]\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n",
+        ];
+
+        let mut completion = Completion::default();
+        let mut pieces = Vec::new();
+        let mut on_text = |text: &str| pieces.push(text.to_string());
+        let mut done = false;
+        for line in lines {
+            if read_event_line(line, &mut completion, &mut on_text)
+                .unwrap()
+                .is_break()
+            {
+                done = true;
+                break;
+            }
         }
-    };
 
-    if ai_data.debug {
-        println!(
-            "==============\nSYSTEM PROMPT\n==============\n{}",
-            response
-        );
+        assert!(done);
+        assert_eq!(completion.text, "# k1\nHola");
+        assert_eq!(completion.finish_reason, Some(FinishReason::Length));
+        let usage = completion.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (12, 3));
+        assert_eq!(pieces, ["# k1\nHo", "la"]);
     }
 
-    Ok(response)
+    #[test]
+    fn read_event_line_rejects_invalid_json() {
+        let mut completion = Completion::default();
+        let result = read_event_line("data: {\"choices\":", &mut completion, &mut |_| {});
+        assert!(result.is_err());
+    }
 }
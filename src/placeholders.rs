@@ -0,0 +1,492 @@
+use std::fmt::Write;
+
+/// A piece of the original text that was replaced by a token.
+#[derive(Debug, Clone)]
+struct Token {
+    /// The opaque token the AI sees, e.g. "⟦P1⟧".
+    id: String,
+    /// What the token stands for, e.g. "%s".
+    original: String,
+    /// Non-positional printf arguments (%s, %d) must keep their relative order,
+    /// otherwise the arguments would be swapped.
+    ordered: bool,
+    /// For closing tags (</b>, [/color]), the index of the opening tag.
+    opened_by: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProtectedText {
+    /// The text as it was before protecting it.
+    pub original: String,
+    /// The text with all placeholders replaced by tokens.
+    pub text: String,
+    tokens: Vec<Token>,
+}
+
+fn token_id(idx: usize) -> String {
+    format!("⟦P{}⟧", idx + 1)
+}
+
+impl Token {
+    /// Names the token as the AI saw it, and what it stands for, e.g. "⟦P1⟧ (%s)".
+    fn describe(&self) -> String {
+        format!("{} ({})", self.id, self.original)
+    }
+}
+
+/// Returns the byte length of the `{...}` placeholder at the start of `s`, if any.
+fn match_braces(s: &str) -> Option<usize> {
+    let end = s[1..].find(['{', '}', '\n'])? + 1;
+    if !s[end..].starts_with('}') || end > 40 {
+        return None;
+    }
+    if s[1..end].chars().any(|c| c.is_whitespace()) {
+        return None;
+    }
+    Some(end + 1)
+}
+
+/// Returns the byte length of the printf-style placeholder at the start of `s`
+/// and whether it is positional (%1$s), if any.
+fn match_printf(s: &str) -> Option<(usize, bool)> {
+    let bytes = s.as_bytes();
+    let mut i = 1;
+    if bytes.get(i) == Some(&b'%') {
+        return Some((2, true));
+    }
+
+    let digits_start = i;
+    while bytes.get(i).is_some_and(|b| b.is_ascii_digit()) {
+        i += 1;
+    }
+    let positional = i > digits_start && bytes.get(i) == Some(&b'$');
+    if positional {
+        i += 1;
+    } else {
+        i = digits_start;
+    }
+
+    while bytes
+        .get(i)
+        .is_some_and(|b| matches!(b, b'-' | b'+' | b'#' | b'0' | b'\''))
+    {
+        i += 1;
+    }
+    while bytes
+        .get(i)
+        .is_some_and(|b| b.is_ascii_digit() || *b == b'*')
+    {
+        i += 1;
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        while bytes
+            .get(i)
+            .is_some_and(|b| b.is_ascii_digit() || *b == b'*')
+        {
+            i += 1;
+        }
+    }
+    while bytes
+        .get(i)
+        .is_some_and(|b| matches!(b, b'h' | b'l' | b'L' | b'z' | b'j' | b't' | b'q'))
+    {
+        i += 1;
+    }
+
+    match bytes.get(i) {
+        Some(
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G'
+            | b'a' | b'A' | b'c' | b's' | b'p' | b'n' | b'@',
+        ) => Some((i + 1, positional)),
+        _ => None,
+    }
+}
+
+/// Returns the tag name of an XML/HTML-like tag (`<b>`, `</b>`, `<br/>`, `<color=#fff>`)
+/// or BBCode tag (`[b]`, `[/color]`, `[color=red]`) at the start of `s`, its byte length,
+/// and whether it's a closing tag.
+fn match_tag(s: &str, open: char, close: char) -> Option<(String, usize, bool)> {
+    let end = s.find(close)?;
+    let inner = &s[open.len_utf8()..end];
+    if inner.is_empty() || inner.len() > 100 || inner.contains(['\n', open]) {
+        return None;
+    }
+
+    let (is_closing, inner) = match inner.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, inner),
+    };
+    let name: String = inner
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect();
+    if !name.chars().next().is_some_and(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let rest = &inner[name.len()..];
+    let valid_rest =
+        rest.is_empty() || rest.starts_with(['=', ' ']) || (open == '<' && rest.trim() == "/");
+    if !valid_rest || (is_closing && !rest.trim().is_empty()) {
+        return None;
+    }
+
+    Some((name.to_lowercase(), end + close.len_utf8(), is_closing))
+}
+
+/// Returns the byte length of a `⟦...⟧` token at the start of `s`, if any. The source can't
+/// contain our tokens as text, or they would be mistaken for them, so they're protected too.
+fn match_token(s: &str) -> Option<usize> {
+    let end = s.find('⟧')?;
+    if end > 40 || s[..end].chars().any(|c| c.is_whitespace()) {
+        return None;
+    }
+    Some(end + '⟧'.len_utf8())
+}
+
+/// Returns the byte length of a `$VARIABLE$` placeholder at the start of `s`, if any.
+fn match_dollar_var(s: &str) -> Option<usize> {
+    let end = s[1..].find('$')? + 1;
+    let name = &s[1..end];
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid { Some(end + 1) } else { None }
+}
+
+/// Replaces placeholders and markup (`{0}`, `%s`, `%1$d`, `<b>`, `[color=red]`, `\n`, `$VARIABLE$`)
+/// in `text` with opaque tokens, so the AI can't translate or rewrite them. A percent sign
+/// followed by a space ("50% off") is prose, not a placeholder.
+pub fn protect(text: &str) -> ProtectedText {
+    let mut result = ProtectedText {
+        original: text.to_string(),
+        ..Default::default()
+    };
+    // Open tags waiting for their closing counterpart: (name, token index).
+    let mut open_tags: Vec<(String, usize)> = Vec::new();
+
+    let mut i = 0;
+    while i < text.len() {
+        let s = &text[i..];
+        let c = s.chars().next().unwrap();
+
+        let mut ordered = false;
+        let mut tag: Option<(String, bool)> = None;
+        let len = match c {
+            '{' => match_braces(s),
+            '%' => match_printf(s).map(|(len, positional)| {
+                ordered = !positional;
+                len
+            }),
+            '<' | '[' => {
+                let close = if c == '<' { '>' } else { ']' };
+                match_tag(s, c, close).and_then(|(name, len, is_closing)| {
+                    // A lone "[laughs]" is likely part of the text, not markup.
+                    // Only protect BBCode with a value, a closing tag, or a matching closing tag.
+                    let is_markup = c == '<'
+                        || is_closing
+                        || s[..len].contains('=')
+                        || s[len..].contains(&format!("[/{}]", name));
+                    if is_markup {
+                        tag = Some((name, is_closing));
+                        Some(len)
+                    } else {
+                        None
+                    }
+                })
+            }
+            '\\' => match s[1..].chars().next() {
+                Some('n' | 'r' | 't' | '"' | '\'' | '\\') => Some(2),
+                _ => None,
+            },
+            '$' => match_dollar_var(s),
+            '⟦' => match_token(s),
+            _ => None,
+        };
+
+        match len {
+            Some(len) => {
+                let idx = result.tokens.len();
+                let mut opened_by = None;
+                if let Some((name, is_closing)) = tag {
+                    if is_closing {
+                        if let Some(pos) = open_tags.iter().rposition(|(n, _)| *n == name) {
+                            opened_by = Some(open_tags.remove(pos).1);
+                        }
+                    } else if !s[..len].ends_with("/>") {
+                        open_tags.push((name, idx));
+                    }
+                }
+
+                let id = token_id(idx);
+                result.text += &id;
+                result.tokens.push(Token {
+                    id,
+                    original: s[..len].to_string(),
+                    ordered,
+                    opened_by,
+                });
+                i += len;
+            }
+            None => {
+                result.text.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+
+    result
+}
+
+/// Leaves the text as-is. Used when protection is disabled.
+pub fn unprotected(text: &str) -> ProtectedText {
+    ProtectedText {
+        original: text.to_string(),
+        text: text.to_string(),
+        tokens: Vec::new(),
+    }
+}
+
+/// Explains to the AI what the tokens are. Nothing is written if none of the texts has tokens.
+pub fn write_prompt<'a>(prompt: &mut String, mut texts: impl Iterator<Item = &'a str>) {
+    if texts.any(|t| t.contains("⟦P")) {
+        writeln!(
+            prompt,
+            "Tokens like ⟦P1⟧ are placeholders. Keep every one of them exactly once and unchanged. They may be moved if the grammar requires it."
+        )
+        .unwrap();
+    }
+}
+
+impl ProtectedText {
+    /// Replaces the tokens in `translation` with the original placeholders.
+    /// Returns the restored text and a description of each problem found
+    /// (lost, duplicated, unknown or illegally reordered placeholders).
+    pub fn restore(&self, translation: &str) -> (String, Vec<String>) {
+        let mut problems = Vec::new();
+
+        // Position of each token in the translation.
+        let mut positions: Vec<Option<usize>> = Vec::with_capacity(self.tokens.len());
+        for t in &self.tokens {
+            let count = translation.matches(&t.id).count();
+            if count == 0 {
+                problems.push(format!("Placeholder {} was lost.", t.describe()));
+            } else if count > 1 {
+                problems.push(format!("Placeholder {} was duplicated.", t.describe()));
+            }
+            positions.push(translation.find(&t.id));
+        }
+
+        let mut last_ordered: Option<(usize, &Token)> = None;
+        for (t, pos) in self.tokens.iter().zip(&positions) {
+            let Some(pos) = *pos else { continue };
+            if t.ordered {
+                if let Some((last_pos, last)) = last_ordered
+                    && pos < last_pos
+                {
+                    problems.push(format!(
+                        "Placeholders {} and {} can't be swapped.",
+                        last.describe(),
+                        t.describe()
+                    ));
+                }
+                last_ordered = Some((pos, t));
+            }
+            if let Some(open_idx) = t.opened_by
+                && let Some(open_pos) = positions[open_idx]
+                && open_pos > pos
+            {
+                problems.push(format!(
+                    "{} must come before {}.",
+                    self.tokens[open_idx].describe(),
+                    t.describe()
+                ));
+            }
+        }
+
+        // Replaced in one pass, as the original of a token may look like another token.
+        let mut restored = String::with_capacity(translation.len());
+        let mut rest = translation;
+        while let Some(start) = rest.find("⟦P") {
+            restored.push_str(&rest[..start]);
+            let after = &rest[start..];
+            let Some(end) = after.find('⟧') else {
+                rest = after;
+                break;
+            };
+            let id = &after[..end + '⟧'.len_utf8()];
+            match self.tokens.iter().find(|t| t.id == id) {
+                Some(t) => restored.push_str(&t.original),
+                None => {
+                    // Looks like a token, but we never issued it.
+                    problems.push(format!("Unknown placeholder {}.", id));
+                    restored.push_str(id);
+                }
+            }
+            rest = &after[id.len()..];
+        }
+        restored.push_str(rest);
+
+        (restored, problems)
+    }
+
+    /// The inverse of `restore`: replaces the placeholders of a finished `translation` with the
+    /// tokens of this text, so it can be checked as if it came from the AI. The k-th occurrence
+    /// of a placeholder is matched with its k-th occurrence in the original.
+    /// Returns the text and the placeholders that aren't in the original.
+    pub fn reprotect(&self, translation: &str) -> (String, Vec<String>) {
+        let found = protect(translation);
+        let mut problems = Vec::new();
+        let mut used = vec![false; self.tokens.len()];
+
+        // The tokens of `found` appear in its text in order, so the text is rebuilt in one pass.
+        let mut text = String::with_capacity(found.text.len());
+        let mut rest = found.text.as_str();
+        for t in &found.tokens {
+            let Some(start) = rest.find(&t.id) else {
+                problems.push(format!("Placeholder {} couldn't be located.", t.original));
+                break;
+            };
+            text.push_str(&rest[..start]);
+            rest = &rest[start + t.id.len()..];
+
+            let matched = self
+                .tokens
+                .iter()
+                .enumerate()
+                .find(|(i, s)| !used[*i] && s.original == t.original);
+            match matched {
+                Some((i, s)) => {
+                    used[i] = true;
+                    text.push_str(&s.id);
+                }
+                None => {
+                    problems.push(format!("Placeholder {} is not in the source.", t.original));
+                    text.push_str(&t.original);
+                }
+            }
+        }
+        text.push_str(rest);
+
+        (text, problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn originals(protected: &ProtectedText) -> Vec<&str> {
+        protected
+            .tokens
+            .iter()
+            .map(|t| t.original.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn percent_signs_in_prose() {
+        for text in [
+            "50% off",
+            "100% sure",
+            "Got 10% of it",
+            "5% discount, 20%-30% more",
+        ] {
+            let protected = protect(text);
+            assert_eq!(protected.text, text);
+            assert!(protected.tokens.is_empty(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn protects_placeholders_and_markup() {
+        let protected = protect("Hi %s, you have %1$d <b>new</b> items in {0}. 100%% done\\n");
+        assert_eq!(
+            protected.text,
+            "Hi ⟦P1⟧, you have ⟦P2⟧ ⟦P3⟧new⟦P4⟧ items in ⟦P5⟧. 100⟦P6⟧ done⟦P7⟧"
+        );
+        assert_eq!(
+            originals(&protected),
+            ["%s", "%1$d", "<b>", "</b>", "{0}", "%%", "\\n"]
+        );
+
+        // A lone BBCode-like tag is part of the text.
+        assert!(protect("[laughs] Sure").tokens.is_empty());
+        assert_eq!(
+            originals(&protect("[color=red]Hi[/color]")),
+            ["[color=red]", "[/color]"]
+        );
+        assert_eq!(originals(&protect("Gold: $GOLD$")), ["$GOLD$"]);
+    }
+
+    #[test]
+    fn restores_placeholders() {
+        let protected = protect("Hi %s, you have %1$d <b>new</b> items.");
+        let (text, problems) = protected.restore("Hola ⟦P1⟧, tienes ⟦P2⟧ objetos ⟦P3⟧nuevos⟦P4⟧.");
+        assert_eq!(text, "Hola %s, tienes %1$d objetos <b>nuevos</b>.");
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn restore_problems() {
+        let protected = protect("%s gave %d coins to <b>you</b>");
+        let (text, problems) = protected.restore("⟦P2⟧ monedas ⟦P4⟧ ⟦P3⟧ ⟦P9⟧ ⟦P1⟧ ⟦P1⟧");
+        assert_eq!(text, "%d monedas </b> <b> ⟦P9⟧ %s %s");
+        assert_eq!(
+            problems,
+            [
+                "Placeholder ⟦P1⟧ (%s) was duplicated.",
+                "Placeholders ⟦P1⟧ (%s) and ⟦P2⟧ (%d) can't be swapped.",
+                "⟦P3⟧ (<b>) must come before ⟦P4⟧ (</b>).",
+                "Unknown placeholder ⟦P9⟧.",
+            ]
+        );
+
+        let (_, problems) = protected.restore("⟦P1⟧ ⟦P2⟧ ⟦P3⟧");
+        assert_eq!(problems, ["Placeholder ⟦P4⟧ (</b>) was lost."]);
+
+        // Positional arguments may be reordered.
+        let protected = protect("%1$s has %2$d");
+        let (text, problems) = protected.restore("⟦P2⟧ tiene ⟦P1⟧");
+        assert_eq!(text, "%2$d tiene %1$s");
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn source_with_tokens() {
+        let protected = protect("⟦P2⟧ and %s");
+        assert_eq!(protected.text, "⟦P1⟧ and ⟦P2⟧");
+        let (text, problems) = protected.restore("⟦P2⟧ y ⟦P1⟧");
+        assert_eq!(text, "%s y ⟦P2⟧");
+        assert!(problems.is_empty());
+
+        let (text, problems) = protected.reprotect("%s y ⟦P2⟧");
+        assert_eq!(text, "⟦P2⟧ y ⟦P1⟧");
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn reprotects_translations() {
+        let protected = protect("{0} and {0} scored 50% of %d");
+        let (text, problems) = protected.reprotect("{0} y {0} marcaron el 50% de %d");
+        assert_eq!(text, "⟦P1⟧ y ⟦P2⟧ marcaron el 50% de ⟦P3⟧");
+        assert!(problems.is_empty());
+
+        let (text, problems) = protected.reprotect("{1} marcó el 50% de %d");
+        assert_eq!(text, "{1} marcó el 50% de ⟦P3⟧");
+        assert_eq!(problems, ["Placeholder {1} is not in the source."]);
+
+        // What was reprotected is checked like an answer of the AI.
+        let (_, problems) = protected.restore(&text);
+        assert_eq!(
+            problems,
+            [
+                "Placeholder ⟦P1⟧ ({0}) was lost.",
+                "Placeholder ⟦P2⟧ ({0}) was lost."
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use log::{info, warn};
+use serde::Deserialize;
+use std::ops::Range;
+use std::{fmt::Write, fs::File, io::Write as iowrite};
+
+use crate::open_ai;
+use crate::scenes::{self, ContextWindow};
+use crate::target::Target;
+
+/// System prompt used by the reviewer.
+/// The user's system prompt is meant for translating, so we don't use it here.
+pub const REVIEW_SYSTEM_PROMPT: &str = "You are a professional translation reviewer.
+You will receive numbered entries, each with its speaker, source text and translation, along with surrounding context.
+For each entry evaluate:
+- accuracy: from 1 (meaning lost) to 5 (perfectly faithful).
+- fluency: from 1 (unnatural or broken) to 5 (reads like it was written in the target language).
+- errors: a list of problems. Each one has a type (mistranslation, omission, addition, wrong_register, wrong_speaker_gender, terminology, grammar, other) and a short description.
+- suggestion: an improved translation if there are errors, otherwise an empty string.
+
+Answer ONLY with a JSON array containing one object per entry, like this:
+[{\"id\": 1, \"accuracy\": 5, \"fluency\": 4, \"errors\": [{\"type\": \"wrong_register\", \"description\": \"Too formal for a teenager talking to a friend\"}], \"suggestion\": \"...\"}]";
+
+/// How many times a batch is sent again when the response can't be parsed.
+const REVIEW_RETRIES: usize = 3;
+
+/// An entry to review.
+pub struct ReviewItem<'a> {
+    pub speaker: &'a str,
+    pub source: &'a str,
+    pub translation: &'a str,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ReviewError {
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// The reviewer's verdict on a single entry.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Review {
+    #[serde(default)]
+    id: usize,
+    pub accuracy: Option<f64>,
+    pub fluency: Option<f64>,
+    #[serde(default)]
+    pub errors: Vec<ReviewError>,
+    #[serde(default)]
+    pub suggestion: String,
+}
+
+impl Review {
+    /// The errors, one per line, e.g. "omission: the second sentence is missing".
+    pub fn errors_text(&self) -> String {
+        self.errors
+            .iter()
+            .map(|e| format!("{}: {}", e.kind, e.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn write_context(prompt: &mut String, items: &[ReviewItem]) {
+    for item in items {
+        writeln!(prompt, "## {}", item.speaker).unwrap();
+        writeln!(prompt, "{}", item.source).unwrap();
+        writeln!(prompt, "=> {}", item.translation).unwrap();
+    }
+}
+
+fn generate_review_prompt(
+    items: &[ReviewItem],
+    from: usize,
+    to: usize,
+    window: &ContextWindow,
+    src_language: Option<&str>,
+    target: &Target<'_>,
+) -> String {
+    let mut prompt = String::new();
+
+    match src_language {
+        Some(src_language) => writeln!(
+            prompt,
+            "Review the translation from {} to {}",
+            src_language, target.language
+        ),
+        None => writeln!(prompt, "Review the translation to {}", target.language),
+    }
+    .unwrap();
+
+    let (pre_range, pos_range) = window.around(from, to);
+    if let Some(characters) = target.characters {
+        let speakers = items[pre_range.start..pos_range.end].iter();
+        characters.write_prompt(&mut prompt, speakers.map(|i| i.speaker));
+    }
+
+    writeln!(prompt, "# CONTEXT PREVIOUS BEGIN").unwrap();
+    write_context(&mut prompt, &items[pre_range]);
+    writeln!(prompt, "# CONTEXT PREVIOUS END").unwrap();
+
+    writeln!(prompt, "# ENTRIES BEGIN").unwrap();
+    for (i, item) in items[from..to].iter().enumerate() {
+        if item.source.trim().is_empty() || item.translation.trim().is_empty() {
+            continue;
+        }
+        writeln!(prompt, "## {}. {}", i + 1, item.speaker).unwrap();
+        writeln!(prompt, "Source:\n{}", item.source).unwrap();
+        writeln!(prompt, "Translation:\n{}", item.translation).unwrap();
+    }
+    writeln!(prompt, "# ENTRIES END").unwrap();
+
+    writeln!(prompt, "# CONTEXT AFTER BEGIN").unwrap();
+    write_context(&mut prompt, &items[pos_range]);
+    writeln!(prompt, "# CONTEXT AFTER END").unwrap();
+
+    prompt
+}
+
+/// Parses the JSON array in the response. Models often wrap it in a code block,
+/// so everything outside the outermost brackets is ignored.
+fn process_review_response(response: &str, num_entries: usize) -> Option<Vec<Option<Review>>> {
+    let start = response.find('[')?;
+    let end = response.rfind(']')?;
+    if end < start {
+        return None;
+    }
+    let reviews: Vec<Review> = serde_json::from_str(&response[start..=end]).ok()?;
+
+    let mut result = vec![None; num_entries];
+    for review in reviews {
+        if review.id >= 1 && review.id <= num_entries {
+            let idx = review.id - 1;
+            result[idx] = Some(review);
+        }
+    }
+    Some(result)
+}
+
+/// Batches of at most `batch_size` items to review, which don't cross scenes. Items for which
+/// `is_reviewed` is true are left out (e.g. they were already reviewed to pick a candidate).
+pub fn batches(
+    window: &ContextWindow<'_>,
+    batch_size: usize,
+    is_reviewed: impl Fn(usize) -> bool,
+) -> Vec<Range<usize>> {
+    scenes::batches(&scenes::pending(window.scenes, is_reviewed), batch_size)
+}
+
+/// Asks the AI to review the translations of `batches` (see `batches`), with the context of
+/// `window`. Returns the review of each item. Items that are empty, outside the batches or
+/// that the AI skipped have none.
+pub async fn review(
+    items: &[ReviewItem<'_>],
+    window: &ContextWindow<'_>,
+    batches: Vec<Range<usize>>,
+    src_language: Option<&str>,
+    ai_settings: &open_ai::AiSettings<'_>,
+    target: &Target<'_>,
+    error_log: &mut File,
+) -> Result<Vec<Option<Review>>, Box<dyn std::error::Error>> {
+    let mut reviews = vec![None; items.len()];
+
+    let num_batches = batches.len();
+    for (batch_id, batch) in batches.into_iter().enumerate() {
+        info!("Review Batch ID {} / {}", batch_id, num_batches);
+        let prompt =
+            generate_review_prompt(items, batch.start, batch.end, window, src_language, target);
+
+        let mut batch_reviews = None;
+        for attempt in 0..REVIEW_RETRIES {
+            let response = open_ai::run_prompt(ai_settings, &prompt).await?;
+            batch_reviews = process_review_response(&response, batch.len());
+            if batch_reviews.is_some() {
+                break;
+            }
+
+            warn!("Invalid Review Output. Attempt {}. Retrying...", attempt);
+            writeln!(error_log, "# ERROR LOG Invalid review response:").ok();
+            writeln!(error_log, "==============================").ok();
+            writeln!(error_log, "{}", response).ok();
+            writeln!(error_log, "==============================").ok();
+        }
+
+        match batch_reviews {
+            Some(r) => {
+                for (review, slot) in r.into_iter().zip(&mut reviews[batch]) {
+                    *slot = review;
+                }
+            }
+            None => warn!("Invalid Review Output. Giving up on this batch."),
+        }
+    }
+
+    Ok(reviews)
+}
@@ -0,0 +1,190 @@
+use std::ops::Range;
+
+/// What we know about an entry to decide where scenes begin.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SceneHint<'a> {
+    /// Value of an explicit "Scene" column. A new scene begins whenever it changes.
+    pub scene: Option<&'a str>,
+    /// When the entry is shown, in seconds.
+    pub start: Option<f64>,
+    /// When the entry stops being shown, in seconds.
+    pub end: Option<f64>,
+    /// Marker rows (e.g. comments) always begin a new scene.
+    pub is_marker: bool,
+}
+
+/// Splits the entries into scenes. Returns the range of entries of each scene, in order.
+/// A new scene begins on a marker row, when the Scene column changes, or when there are more
+/// than `max_gap` seconds between the end of an entry and the start of the next one.
+pub fn split(hints: &[SceneHint], max_gap: Option<f64>) -> Vec<Range<usize>> {
+    let mut scenes = Vec::new();
+    let mut scene_start = 0;
+
+    for i in 1..hints.len() {
+        let prev = &hints[i - 1];
+        let curr = &hints[i];
+
+        let gap = match (max_gap, prev.end.or(prev.start), curr.start) {
+            (Some(max_gap), Some(prev_end), Some(start)) => start - prev_end > max_gap,
+            _ => false,
+        };
+        let scene_changed = curr.scene.is_some() && curr.scene != prev.scene;
+
+        if curr.is_marker || scene_changed || gap {
+            scenes.push(scene_start..i);
+            scene_start = i;
+        }
+    }
+    if scene_start < hints.len() {
+        scenes.push(scene_start..hints.len());
+    }
+
+    scenes
+}
+
+/// Splits each scene in batches of at most `batch_size` entries. Batches never cross scenes.
+pub fn batches(scenes: &[Range<usize>], batch_size: usize) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    for scene in scenes {
+        for from in scene.clone().step_by(batch_size) {
+            batches.push(from..std::cmp::min(from + batch_size, scene.end));
+        }
+    }
+    batches
+}
+
+/// How many entries before and after a batch are sent as context, without crossing scenes.
+#[derive(Clone)]
+pub struct ContextWindow<'a> {
+    pub scenes: &'a [Range<usize>],
+    pub pre: usize,
+    pub pos: usize,
+}
+
+impl ContextWindow<'_> {
+    /// Returns the ranges of the previous and following context of the entries `from..to`.
+    pub fn around(&self, from: usize, to: usize) -> (Range<usize>, Range<usize>) {
+        let scene = self
+            .scenes
+            .iter()
+            .find(|s| s.contains(&from))
+            .cloned()
+            .unwrap_or(from..to);
+        let pre_from = std::cmp::max(from.saturating_sub(self.pre), scene.start);
+        let pos_to = std::cmp::min(to + self.pos, scene.end);
+        (pre_from..from, to..pos_to)
+    }
+}
+
+/// Splits each scene in runs of consecutive entries that still need translating
+/// (e.g. not reused from a previous run). Runs never cross scenes.
+pub fn pending(scenes: &[Range<usize>], is_done: impl Fn(usize) -> bool) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    for scene in scenes {
+        let mut run_start = None;
+        for i in scene.clone() {
+            match (is_done(i), run_start) {
+                (false, None) => run_start = Some(i),
+                (true, Some(start)) => {
+                    runs.push(start..i);
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            runs.push(start..scene.end);
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn timed(start: f64, end: f64) -> SceneHint<'static> {
+        SceneHint {
+            start: Some(start),
+            end: Some(end),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn splits_on_scene_column_and_markers() {
+        let scene = |s| SceneHint {
+            scene: Some(s),
+            ..Default::default()
+        };
+        let hints = [
+            scene("intro"),
+            scene("intro"),
+            SceneHint::default(),
+            scene("park"),
+            SceneHint {
+                is_marker: true,
+                ..Default::default()
+            },
+            SceneHint::default(),
+        ];
+        // An empty Scene cell doesn't begin a scene, but the next value does.
+        assert_eq!(split(&hints, None), [0..3, 3..4, 4..6]);
+        assert_eq!(split(&[], None), []);
+    }
+
+    #[test]
+    fn splits_on_gaps() {
+        let hints = [
+            timed(0.0, 2.0),
+            timed(3.0, 4.0),
+            timed(10.0, 11.0),
+            // Without an end, the start of the previous entry is used.
+            SceneHint {
+                start: Some(11.5),
+                ..Default::default()
+            },
+            timed(14.0, 15.0),
+        ];
+        assert_eq!(split(&hints, Some(5.0)), [0..2, 2..5]);
+        assert_eq!(split(&hints, Some(2.0)), [0..2, 2..4, 4..5]);
+        assert_eq!(split(&hints, None), [0..5]);
+    }
+
+    #[test]
+    fn batches_stay_within_scenes() {
+        assert_eq!(
+            batches(&[0..5, 5..6, 6..10], 3),
+            [0..3, 3..5, 5..6, 6..9, 9..10]
+        );
+        assert_eq!(batches(&[0..4], 10), [0..4]);
+    }
+
+    #[test]
+    fn context_stays_within_scenes() {
+        let scenes = [0..5, 5..20];
+        let window = ContextWindow {
+            scenes: &scenes,
+            pre: 3,
+            pos: 2,
+        };
+        assert_eq!(window.around(2, 4), (0..2, 4..5));
+        assert_eq!(window.around(5, 8), (5..5, 8..10));
+        assert_eq!(window.around(10, 19), (7..10, 19..20));
+
+        let window = ContextWindow {
+            scenes: &scenes,
+            pre: 0,
+            pos: 0,
+        };
+        assert_eq!(window.around(10, 12), (10..10, 12..12));
+    }
+
+    #[test]
+    fn pending_runs() {
+        let done = [false, true, false, false, true, false, false];
+        assert_eq!(pending(&[0..4, 4..7], |i| done[i]), [0..1, 2..4, 5..7]);
+        assert_eq!(pending(&[0..2], |_| true), []);
+    }
+}
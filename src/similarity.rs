@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use crate::open_ai;
+use crate::validation::CheckPolicy;
+
+/// Maximum character n-gram order used by chrF.
+const CHRF_ORDER: usize = 6;
+/// chrF weighs recall BETA times as much as precision.
+const CHRF_BETA: f64 = 2.0;
+
+/// How similar the back translation is to the original text. Both in the range [0; 100].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Score {
+    /// Character n-gram F-score (chrF).
+    pub chrf: f64,
+    /// Cosine similarity of the embeddings, if an embeddings endpoint was provided.
+    pub embedding: Option<f64>,
+}
+
+fn char_ngrams(chars: &[char], n: usize) -> HashMap<&[char], usize> {
+    let mut ngrams = HashMap::new();
+    for ngram in chars.windows(n) {
+        *ngrams.entry(ngram).or_insert(0) += 1;
+    }
+    ngrams
+}
+
+/// chrF score (0 to 100) of `hypothesis` against `reference`. Whitespace is ignored.
+pub fn chrf(hypothesis: &str, reference: &str) -> f64 {
+    let hyp: Vec<char> = hypothesis.chars().filter(|c| !c.is_whitespace()).collect();
+    let rf: Vec<char> = reference.chars().filter(|c| !c.is_whitespace()).collect();
+    if hyp.is_empty() || rf.is_empty() {
+        return if hyp.is_empty() && rf.is_empty() {
+            100.0
+        } else {
+            0.0
+        };
+    }
+
+    let mut precision = 0.0;
+    let mut recall = 0.0;
+    let mut orders = 0;
+    for n in 1..=CHRF_ORDER {
+        if hyp.len() < n || rf.len() < n {
+            break;
+        }
+        let hyp_ngrams = char_ngrams(&hyp, n);
+        let ref_ngrams = char_ngrams(&rf, n);
+        let matches: usize = hyp_ngrams
+            .iter()
+            .map(|(ngram, count)| std::cmp::min(*count, *ref_ngrams.get(ngram).unwrap_or(&0)))
+            .sum();
+        precision += matches as f64 / (hyp.len() - n + 1) as f64;
+        recall += matches as f64 / (rf.len() - n + 1) as f64;
+        orders += 1;
+    }
+    precision /= orders as f64;
+    recall /= orders as f64;
+
+    let beta2 = CHRF_BETA * CHRF_BETA;
+    if precision + recall == 0.0 {
+        return 0.0;
+    }
+    100.0 * (1.0 + beta2) * precision * recall / (beta2 * precision + recall)
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| *x as f64 * *y as f64).sum();
+    let norm_a: f64 = a.iter().map(|x| (*x as f64).powi(2)).sum::<f64>().sqrt();
+    let norm_b: f64 = b.iter().map(|x| (*x as f64).powi(2)).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Rounds to one decimal, which is all the precision that's worth writing.
+pub fn round(v: f64) -> f64 {
+    (v * 10.0).round() / 10.0
+}
+
+/// Compares the original texts against their back translations.
+#[derive(Debug, Default, Clone)]
+pub struct Scorer {
+    /// OpenAI-compatible embeddings endpoint, e.g. http://127.0.0.1:8081/v1/embeddings
+    pub embeddings_endpoint: Option<String>,
+    pub embeddings_model: String,
+    /// Entries with a lower chrF are flagged.
+    pub chrf_threshold: Option<f64>,
+    /// Entries with a lower embedding similarity are flagged.
+    pub embedding_threshold: Option<f64>,
+    pub policy: CheckPolicy,
+}
+
+impl Scorer {
+    /// Scores each (original, back translation) pair.
+    pub async fn score(
+        &self,
+        ai_settings: &open_ai::AiSettings<'_>,
+        originals: &[&str],
+        backs: &[&str],
+    ) -> Result<Vec<Score>, Box<dyn std::error::Error>> {
+        let mut scores: Vec<Score> = originals
+            .iter()
+            .zip(backs)
+            .map(|(original, back)| Score {
+                chrf: round(chrf(back, original)),
+                embedding: None,
+            })
+            .collect();
+
+        if let Some(endpoint) = &self.embeddings_endpoint {
+            // Some endpoints reject empty inputs.
+            let indices: Vec<usize> = (0..originals.len())
+                .filter(|i| !originals[*i].trim().is_empty() && !backs[*i].trim().is_empty())
+                .collect();
+            // Each request holds a chunk of originals followed by their back translations.
+            for chunk in indices.chunks(open_ai::EMBEDDINGS_BATCH / 2) {
+                let texts: Vec<&str> = chunk
+                    .iter()
+                    .map(|i| originals[*i])
+                    .chain(chunk.iter().map(|i| backs[*i]))
+                    .collect();
+                let embeddings =
+                    open_ai::embed(ai_settings, endpoint, &self.embeddings_model, &texts).await?;
+                let (original_emb, back_emb) = embeddings.split_at(chunk.len());
+                for (j, i) in chunk.iter().enumerate() {
+                    scores[*i].embedding =
+                        Some(round(100.0 * cosine(&original_emb[j], &back_emb[j])));
+                }
+            }
+        }
+
+        Ok(scores)
+    }
+
+    /// Returns a description of the problem if the score is below the thresholds.
+    pub fn check(&self, score: &Score) -> Option<String> {
+        if self.policy == CheckPolicy::Ignore {
+            return None;
+        }
+        if let Some(threshold) = self.chrf_threshold
+            && score.chrf < threshold
+        {
+            return Some(format!(
+                "Back translation score {} is below {}. The meaning may have changed.",
+                score.chrf, threshold
+            ));
+        }
+        if let Some(threshold) = self.embedding_threshold
+            && let Some(embedding) = score.embedding
+            && embedding < threshold
+        {
+            return Some(format!(
+                "Back translation similarity {} is below {}. The meaning may have changed.",
+                embedding, threshold
+            ));
+        }
+        None
+    }
+
+    /// Whether `a` is a better score than `b`.
+    pub fn is_better(a: &Score, b: &Score) -> bool {
+        match (a.embedding, b.embedding) {
+            (Some(ea), Some(eb)) => ea + a.chrf > eb + b.chrf,
+            _ => a.chrf > b.chrf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chrf_scores() {
+        assert_eq!(chrf("Hello there", "Hello there"), 100.0);
+        // Whitespace is ignored.
+        assert_eq!(chrf("Hello  there\n", "Hellothere"), 100.0);
+        assert_eq!(chrf("xyz", "abc"), 0.0);
+        assert_eq!(chrf("", ""), 100.0);
+        assert_eq!(chrf("", "Hello"), 0.0);
+
+        let close = chrf("The cat sat on the mat", "The cat sat on a mat");
+        let far = chrf("A dog ran in the park", "The cat sat on a mat");
+        assert!(close > 70.0 && close < 100.0, "{}", close);
+        assert!(far < 30.0, "{}", far);
+    }
+
+    #[test]
+    fn chrf_weighs_recall() {
+        // Missing words (low recall) cost more than extra ones (low precision).
+        let reference = "I really like green apples";
+        let missing = chrf("I like apples", reference);
+        let extra = chrf("I really like green apples very much indeed", reference);
+        assert!(missing < extra, "{} {}", missing, extra);
+    }
+
+    #[test]
+    fn cosine_similarity() {
+        assert!((cosine(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-9);
+        assert!(cosine(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-9);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn checks_thresholds() {
+        let scorer = Scorer {
+            chrf_threshold: Some(50.0),
+            embedding_threshold: Some(80.0),
+            ..Default::default()
+        };
+        let score = |chrf, embedding| Score { chrf, embedding };
+        assert!(scorer.check(&score(60.0, Some(90.0))).is_none());
+        assert!(scorer.check(&score(60.0, None)).is_none());
+        assert!(scorer.check(&score(40.0, None)).is_some());
+        assert!(scorer.check(&score(60.0, Some(70.0))).is_some());
+
+        let ignore = Scorer {
+            policy: CheckPolicy::Ignore,
+            ..scorer
+        };
+        assert!(ignore.check(&score(0.0, Some(0.0))).is_none());
+
+        assert!(Scorer::is_better(&score(60.0, None), &score(50.0, None)));
+        assert!(Scorer::is_better(
+            &score(50.0, Some(90.0)),
+            &score(60.0, Some(70.0))
+        ));
+    }
+}
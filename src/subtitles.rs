@@ -0,0 +1,370 @@
+use std::fs;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Srt,
+    Vtt,
+    Ass,
+}
+
+impl Format {
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let ext = path.rsplit_once('.')?.1.to_lowercase();
+        match ext.as_str() {
+            "srt" => Some(Format::Srt),
+            "vtt" => Some(Format::Vtt),
+            "ass" | "ssa" => Some(Format::Ass),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cue {
+    /// Start time in seconds.
+    pub start: f64,
+    /// End time in seconds.
+    pub end: f64,
+    pub speaker: String,
+    pub text: String,
+    /// Where the text is located in the file, so it can be replaced when writing.
+    range: Range<usize>,
+}
+
+impl Cue {
+    pub fn duration(&self) -> f64 {
+        (self.end - self.start).max(0.0)
+    }
+}
+
+/// A subtitle file. The original contents are kept so only the texts change when writing it back.
+pub struct SubtitleFile {
+    format: Format,
+    contents: String,
+    pub cues: Vec<Cue>,
+}
+
+/// Parses "hh:mm:ss,mmm" (SRT), "hh:mm:ss.mmm" / "mm:ss.mmm" (VTT) or "h:mm:ss.cc" (ASS).
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let s = s.trim().replace(',', ".");
+    let mut secs = 0.0;
+    for part in s.split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(secs)
+}
+
+/// Parses an SRT or VTT timing line: "00:00:01,000 --> 00:00:02,500 [settings]".
+fn parse_timing_line(line: &str) -> Option<(f64, f64)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((parse_timestamp(start)?, parse_timestamp(end)?))
+}
+
+/// Splits the contents into lines, keeping the byte offset where each line starts.
+fn lines_with_offsets(contents: &str) -> Vec<(usize, &str)> {
+    let mut offset = 0;
+    contents
+        .split('\n')
+        .map(|line| {
+            let start = offset;
+            offset += line.len() + 1;
+            (start, line.strip_suffix('\r').unwrap_or(line))
+        })
+        .collect()
+}
+
+/// Parses SRT and VTT. Both are blocks separated by blank lines, where the text follows
+/// the timing line. VTT voice tags (`<v Speaker>`) are used as the speaker.
+fn parse_srt_vtt(contents: &str) -> Vec<Cue> {
+    let lines = lines_with_offsets(contents);
+    let mut cues = Vec::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let Some((start, end)) = parse_timing_line(lines[i].1) else {
+            i += 1;
+            continue;
+        };
+        i += 1;
+
+        let first = i;
+        while i < lines.len() && !lines[i].1.trim().is_empty() {
+            i += 1;
+        }
+        if first == i {
+            continue;
+        }
+
+        let last = &lines[i - 1];
+        let mut range = lines[first].0..last.0 + last.1.len();
+        let mut speaker = String::new();
+        if let Some(voice) = contents[range.clone()].strip_prefix("<v")
+            && voice.starts_with([' ', '.'])
+            && let Some(close) = voice.find('>')
+        {
+            speaker = voice[..close]
+                .split_once(' ')
+                .map(|(_, name)| name.trim().to_string())
+                .unwrap_or_default();
+            range.start += "<v".len() + close + 1;
+        }
+
+        cues.push(Cue {
+            start,
+            end,
+            speaker,
+            text: contents[range.clone()].replace("\r\n", "\n"),
+            range,
+        });
+    }
+
+    cues
+}
+
+/// Parses the Dialogue lines of the [Events] section, following its Format line.
+fn parse_ass(contents: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut in_events = false;
+    let mut fields: Vec<String> = Vec::new();
+
+    for (offset, line) in lines_with_offsets(contents) {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_events = trimmed.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+
+        if let Some(format) = trimmed.strip_prefix("Format:") {
+            fields = format.split(',').map(|f| f.trim().to_lowercase()).collect();
+            continue;
+        }
+        let Some(dialogue) = line.strip_prefix("Dialogue:") else {
+            continue;
+        };
+        if fields.is_empty() {
+            continue;
+        }
+
+        // Text is always the last field and may contain commas.
+        let values: Vec<&str> = dialogue.splitn(fields.len(), ',').collect();
+        if values.len() != fields.len() {
+            continue;
+        }
+        let field = |name: &str| {
+            fields
+                .iter()
+                .position(|f| f == name)
+                .map(|i| values[i].trim())
+        };
+
+        let text = values[values.len() - 1];
+        let text_start = offset + line.len() - text.len();
+        cues.push(Cue {
+            start: field("start").and_then(parse_timestamp).unwrap_or(0.0),
+            end: field("end").and_then(parse_timestamp).unwrap_or(0.0),
+            speaker: field("name").unwrap_or_default().to_string(),
+            text: text.replace("\\N", "\n").replace("\\n", "\n"),
+            range: text_start..text_start + text.len(),
+        });
+    }
+
+    cues
+}
+
+impl SubtitleFile {
+    pub fn load(path: &str) -> Result<SubtitleFile, Box<dyn std::error::Error>> {
+        let format = Format::from_path(path)
+            .ok_or_else(|| format!("Unknown subtitle format {}. Use .srt, .vtt or .ass", path))?;
+        Ok(SubtitleFile::parse(format, fs::read_to_string(path)?))
+    }
+
+    pub fn parse(format: Format, contents: String) -> SubtitleFile {
+        let contents = contents
+            .strip_prefix('\u{FEFF}')
+            .map(str::to_string)
+            .unwrap_or(contents);
+
+        let cues = match format {
+            Format::Srt | Format::Vtt => parse_srt_vtt(&contents),
+            Format::Ass => parse_ass(&contents),
+        };
+
+        SubtitleFile {
+            format,
+            contents,
+            cues,
+        }
+    }
+
+    /// Writes the file with the text of each cue replaced by `texts` (see `render`).
+    pub fn write(&self, path: &str, texts: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, self.render(texts))?;
+        Ok(())
+    }
+
+    /// The file with the text of each cue replaced by `texts` (in the same order).
+    /// Cues with an empty text (e.g. the AI gave up) keep their original text, as a cue
+    /// without text would end early and break the following ones.
+    pub fn render(&self, texts: &[String]) -> String {
+        let mut contents = self.contents.clone();
+        let newline = match contents.contains("\r\n") {
+            true => "\r\n",
+            false => "\n",
+        };
+        // Replace back to front so the ranges of earlier cues remain valid.
+        for (cue, text) in self.cues.iter().zip(texts).rev() {
+            let text = match self.format {
+                Format::Srt | Format::Vtt => {
+                    // A blank line would end the cue.
+                    text.lines()
+                        .filter(|l| !l.trim().is_empty())
+                        .collect::<Vec<_>>()
+                        .join(newline)
+                }
+                Format::Ass => text.trim_matches('\n').replace('\n', "\\N"),
+            };
+            if text.trim().is_empty() {
+                continue;
+            }
+            contents.replace_range(cue.range.clone(), &text);
+        }
+        contents
+    }
+}
+
+/// Common subtitle guidelines (characters per second, characters per line) for a language.
+/// `language` can be a name ("Japanese") or a code ("ja", "ja-JP").
+pub fn default_limits(language: &str) -> (f32, usize) {
+    let language = language.trim().to_lowercase();
+    let code = language.split(['-', '_']).next().unwrap_or_default();
+    match (code, language.as_str()) {
+        ("ja", _) | (_, "japanese") => (4.0, 13),
+        ("zh", _) | (_, "chinese") => (9.0, 16),
+        ("ko", _) | (_, "korean") => (12.0, 16),
+        ("th", _) | (_, "thai") => (15.0, 35),
+        _ => (17.0, 42),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(file: &SubtitleFile) -> Vec<&str> {
+        file.cues.iter().map(|c| c.text.as_str()).collect()
+    }
+
+    const SRT: &str = "1\r\n00:00:01,000 --> 00:00:02,500\r\nHi Anna!\r\n\r\n\
+                       2\r\n00:01:02,250 --> 00:01:05,000\r\nDid you enjoy\r\nTokyo?\r\n\r\n\
+                       3\r\n00:01:06,000 --> 00:01:07,000\r\nYes!\r\n";
+
+    #[test]
+    fn parses_srt() {
+        let file = SubtitleFile::parse(Format::Srt, format!("\u{FEFF}{}", SRT));
+        assert_eq!(texts(&file), ["Hi Anna!", "Did you enjoy\nTokyo?", "Yes!"]);
+        assert_eq!(file.cues[1].start, 62.25);
+        assert_eq!(file.cues[1].duration(), 2.75);
+    }
+
+    #[test]
+    fn writes_srt() {
+        let file = SubtitleFile::parse(Format::Srt, SRT.to_string());
+        let written = file.render(&[
+            "¡Hola Anna!".to_string(),
+            "¿Disfrutaste\n\nde Tokio?".to_string(),
+            "¡Sí!".to_string(),
+        ]);
+        let reread = SubtitleFile::parse(Format::Srt, written.clone());
+        assert_eq!(
+            texts(&reread),
+            ["¡Hola Anna!", "¿Disfrutaste\nde Tokio?", "¡Sí!"]
+        );
+        // Only the texts change, keeping the line endings of the file.
+        assert!(written.starts_with("1\r\n00:00:01,000 --> 00:00:02,500\r\n¡Hola Anna!\r\n\r\n2"));
+        assert!(written.contains("\r\n¿Disfrutaste\r\nde Tokio?\r\n"));
+    }
+
+    #[test]
+    fn keeps_the_original_of_empty_translations() {
+        let file = SubtitleFile::parse(Format::Srt, SRT.to_string());
+        let written = file.render(&["¡Hola Anna!".to_string(), String::new(), "¡Sí!".to_string()]);
+        let reread = SubtitleFile::parse(Format::Srt, written);
+        assert_eq!(
+            texts(&reread),
+            ["¡Hola Anna!", "Did you enjoy\nTokyo?", "¡Sí!"]
+        );
+    }
+
+    #[test]
+    fn vtt_voices() {
+        let vtt = "WEBVTT\n\n\
+                   intro\n00:01.000 --> 00:02.000 align:start\n<v John>Hi Anna!\nHow are you?\n\n\
+                   00:00:03.000 --> 00:00:04.000\n<v.loud Anna Smith>Fine!\n";
+        let file = SubtitleFile::parse(Format::Vtt, vtt.to_string());
+        assert_eq!(texts(&file), ["Hi Anna!\nHow are you?", "Fine!"]);
+        let speakers: Vec<&str> = file.cues.iter().map(|c| c.speaker.as_str()).collect();
+        assert_eq!(speakers, ["John", "Anna Smith"]);
+        assert_eq!((file.cues[0].start, file.cues[0].end), (1.0, 2.0));
+
+        let written = file.render(&[
+            "¡Hola Anna!\n¿Cómo estás?".to_string(),
+            "¡Bien!".to_string(),
+        ]);
+        assert_eq!(
+            written,
+            "WEBVTT\n\n\
+             intro\n00:01.000 --> 00:02.000 align:start\n<v John>¡Hola Anna!\n¿Cómo estás?\n\n\
+             00:00:03.000 --> 00:00:04.000\n<v.loud Anna Smith>¡Bien!\n"
+        );
+    }
+
+    const ASS: &str = "[Script Info]\nTitle: Test\n\n\
+                       [Events]\n\
+                       Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+                       Comment: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Not a line\n\
+                       Dialogue: 0,0:00:01.50,0:00:03.00,Default,John,0,0,0,,{\\i1}Hi,{\\i0} Anna!\\NHow are you?\n\
+                       Dialogue: 0,1:00:00.00,1:00:02.00,Default,Anna,0,0,0,,Fine\n";
+
+    #[test]
+    fn parses_ass() {
+        let file = SubtitleFile::parse(Format::Ass, ASS.to_string());
+        assert_eq!(
+            texts(&file),
+            ["{\\i1}Hi,{\\i0} Anna!\nHow are you?", "Fine"]
+        );
+        assert_eq!(file.cues[0].speaker, "John");
+        assert_eq!((file.cues[0].start, file.cues[0].end), (1.5, 3.0));
+        assert_eq!(file.cues[1].start, 3600.0);
+    }
+
+    #[test]
+    fn writes_ass() {
+        let file = SubtitleFile::parse(Format::Ass, ASS.to_string());
+        let written = file.render(&[
+            "{\\i1}¡Hola,{\\i0} Anna!\n¿Cómo estás?\n".to_string(),
+            String::new(),
+        ]);
+        assert!(written.contains(
+            "Dialogue: 0,0:00:01.50,0:00:03.00,Default,John,0,0,0,,{\\i1}¡Hola,{\\i0} Anna!\\N¿Cómo estás?\n"
+        ));
+        assert!(written.ends_with(",Anna,0,0,0,,Fine\n"));
+        let reread = SubtitleFile::parse(Format::Ass, written);
+        assert_eq!(
+            texts(&reread),
+            ["{\\i1}¡Hola,{\\i0} Anna!\n¿Cómo estás?", "Fine"]
+        );
+    }
+
+    #[test]
+    fn limits_by_language() {
+        assert_eq!(default_limits("ja-JP"), (4.0, 13));
+        assert_eq!(default_limits("Japanese"), (4.0, 13));
+        assert_eq!(default_limits("es"), (17.0, 42));
+    }
+}
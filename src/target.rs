@@ -0,0 +1,57 @@
+use icu_locale_core::Locale;
+
+use crate::characters::Characters;
+use crate::examples::Examples;
+use crate::formality::Formality;
+use crate::glossary::Glossary;
+use crate::memory::Memory;
+use crate::translator::{self, Event, Events};
+use crate::validation::Validator;
+
+/// Everything that depends on the language being translated to:
+/// the resources used to enrich the prompts and the checks run on the output.
+#[derive(Default)]
+pub struct Target<'a> {
+    pub language: &'a str,
+    /// Locale of `language`, if it was given as a BCP-47 tag. Its conventions are sent to the AI.
+    pub locale: Option<&'a Locale>,
+    pub glossary: Option<&'a Glossary>,
+    pub characters: Option<&'a Characters>,
+    /// Formality, honorific and register policies (see --formality).
+    pub formality: Option<&'a Formality>,
+    /// Similar texts translated before are sent as reference (see --memory).
+    pub memory: Option<&'a Memory>,
+    /// Approved translations of similar lines are sent as few-shot examples (see --examples).
+    pub examples: Option<&'a Examples>,
+    /// Swap placeholders and markup for opaque tokens before sending them to the AI.
+    pub protect_placeholders: bool,
+    pub validator: Validator<'a>,
+    /// Where the progress is reported (see Translator::subscribe).
+    pub events: Option<&'a Events>,
+}
+
+impl<'a> Target<'a> {
+    /// A target with no extra resources nor checks. Used e.g. for back translations.
+    pub fn new(language: &'a str) -> Target<'a> {
+        Target {
+            language,
+            ..Default::default()
+        }
+    }
+
+    /// Target used to translate back to `src_language`.
+    /// The glossary, translation memory and examples only apply to src -> dst, so they aren't
+    /// used here.
+    pub fn back(&self, src_language: &'a str) -> Target<'a> {
+        Target {
+            characters: self.characters,
+            protect_placeholders: self.protect_placeholders,
+            events: self.events,
+            ..Target::new(src_language)
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        translator::emit(self.events, event);
+    }
+}
@@ -0,0 +1,360 @@
+use log::{info, warn};
+use std::{collections::HashMap, fmt::Write, fs::File, io::Write as iowrite};
+
+use crate::backend::Backend;
+use crate::format::{self, Format};
+use crate::glossary::{self, GlossaryEntry};
+use crate::locale::Language;
+use crate::{AiArgs, open_ai};
+
+/// System prompt used while proposing glossary translations.
+/// The user's system prompt is tailored for dialogue, so we don't use it here.
+const TERMS_SYSTEM_PROMPT: &str = "You are a professional translator building a glossary.
+You will receive a list of terms (names, places, items, recurring expressions) along with example sentences where they appear.
+For each term propose a single translation that should be used consistently across the whole work.
+If a term must not be translated (e.g. it's a name that stays the same), write it unchanged.
+
+Answer using exactly this format for every term, in the same order:
+{TERM}original term{TERM}
+translation
+{RMK} optional short note about the choice (gender, reading, etc)
+
+Do not add anything else.";
+
+/// How many terms to send per prompt.
+const TERMS_PER_QUERY: usize = 20;
+/// How many example sentences to send per term.
+const EXAMPLES_PER_TERM: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateKind {
+    Speaker,
+    Katakana,
+    Capitalised,
+}
+
+impl CandidateKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CandidateKind::Speaker => "speaker",
+            CandidateKind::Katakana => "katakana",
+            CandidateKind::Capitalised => "proper noun",
+        }
+    }
+}
+
+struct Candidate {
+    term: String,
+    kind: CandidateKind,
+    occurrences: usize,
+    examples: Vec<String>,
+}
+
+fn is_katakana(c: char) -> bool {
+    // Katakana block (includes the prolonged sound mark ー) + Halfwidth Katakana.
+    ('\u{30A1}'..='\u{30FF}').contains(&c) || ('\u{FF66}'..='\u{FF9F}').contains(&c)
+}
+
+/// Returns all runs of 2 or more katakana characters.
+fn katakana_runs(text: &str) -> Vec<String> {
+    let mut runs = Vec::new();
+    let mut current = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if is_katakana(c) {
+            current.push(c);
+        } else {
+            if current.chars().count() >= 2 && !current.chars().all(|c| c == 'ー') {
+                runs.push(current.clone());
+            }
+            current.clear();
+        }
+    }
+    runs
+}
+
+/// Returns sequences of capitalised words (e.g. "New York") that don't start a sentence.
+/// Single letter words like "I" or "A" are ignored.
+fn capitalised_terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut sentence_start = true;
+
+    let flush = |current: &mut Vec<&str>, terms: &mut Vec<String>| {
+        if !current.is_empty() {
+            terms.push(current.join(" "));
+            current.clear();
+        }
+    };
+
+    for raw in text.split_whitespace() {
+        let word = raw.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'' && c != '-');
+        let word = word.trim_end_matches("'s");
+        let is_capitalised = word.chars().next().is_some_and(|c| c.is_uppercase())
+            && word.chars().count() > 1
+            && word.chars().any(|c| c.is_lowercase());
+
+        if is_capitalised && !sentence_start {
+            current.push(word);
+        } else {
+            flush(&mut current, &mut terms);
+        }
+
+        // Punctuation (e.g. "Anna, Cecilia") splits terms. Some of it also ends the sentence.
+        if raw.chars().last().is_some_and(|c| !c.is_alphanumeric()) {
+            flush(&mut current, &mut terms);
+        }
+        sentence_start = raw.ends_with(['.', '!', '?', ':', '"', '…', '。', '！', '？']);
+    }
+    flush(&mut current, &mut terms);
+
+    terms
+}
+
+/// Scans all the lines looking for terms worth adding to a glossary.
+/// Speakers are always included. Katakana runs and capitalised terms must appear
+/// at least `min_occurrences` times.
+fn find_candidates(
+    speakers: &[String],
+    texts: &[String],
+    min_occurrences: usize,
+) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for speaker in speakers {
+        let speaker = speaker.trim();
+        if speaker.is_empty() || index.contains_key(speaker) {
+            continue;
+        }
+        index.insert(speaker.to_string(), candidates.len());
+        candidates.push(Candidate {
+            term: speaker.to_string(),
+            kind: CandidateKind::Speaker,
+            occurrences: 0,
+            examples: Vec::new(),
+        });
+    }
+
+    for text in texts {
+        let found = katakana_runs(text)
+            .into_iter()
+            .map(|t| (t, CandidateKind::Katakana))
+            .chain(
+                capitalised_terms(text)
+                    .into_iter()
+                    .map(|t| (t, CandidateKind::Capitalised)),
+            );
+
+        for (term, kind) in found {
+            let idx = *index.entry(term.clone()).or_insert_with(|| {
+                candidates.push(Candidate {
+                    term,
+                    kind,
+                    occurrences: 0,
+                    examples: Vec::new(),
+                });
+                candidates.len() - 1
+            });
+
+            let c = &mut candidates[idx];
+            c.occurrences += 1;
+            if c.examples.len() < EXAMPLES_PER_TERM && !c.examples.contains(text) {
+                c.examples.push(text.clone());
+            }
+        }
+    }
+
+    // Speakers may also appear in the text, grab examples for them too.
+    for c in candidates
+        .iter_mut()
+        .filter(|c| c.kind == CandidateKind::Speaker)
+    {
+        c.occurrences = 0;
+        for text in texts {
+            if glossary::contains_term(text, &c.term) {
+                c.occurrences += 1;
+                if c.examples.len() < EXAMPLES_PER_TERM && !c.examples.contains(text) {
+                    c.examples.push(text.clone());
+                }
+            }
+        }
+    }
+
+    candidates.retain(|c| c.kind == CandidateKind::Speaker || c.occurrences >= min_occurrences);
+    candidates
+}
+
+fn generate_terms_prompt(candidates: &[Candidate], dst_language: &str) -> String {
+    let mut prompt = String::new();
+
+    writeln!(prompt, "Propose translations to {}", dst_language).unwrap();
+    writeln!(prompt, "# TERMS BEGIN").unwrap();
+    for c in candidates {
+        writeln!(prompt, "{{TERM}}{}{{TERM}}", c.term).unwrap();
+        writeln!(prompt, "Kind: {}", c.kind.as_str()).unwrap();
+        for example in &c.examples {
+            writeln!(prompt, "Example: {}", example.replace('\n', " ")).unwrap();
+        }
+    }
+    writeln!(prompt, "# TERMS END").unwrap();
+
+    prompt
+}
+
+/// Parses the AI response. Terms the AI skipped are returned with an empty translation
+/// (`write_glossary_csv` comments them out).
+fn process_terms_response(response: &str, candidates: &[Candidate]) -> Vec<GlossaryEntry> {
+    let mut entries = Vec::with_capacity(candidates.len());
+
+    for c in candidates {
+        let pattern = format!("{{TERM}}{}{{TERM}}", c.term);
+        let (target, remarks) = match response.find(&pattern) {
+            Some(idx) => {
+                let start = idx + pattern.len();
+                let end = response[start..]
+                    .find("{TERM}")
+                    .map(|i| start + i)
+                    .unwrap_or(response.len());
+                let parts = response[start..end]
+                    .split_once("{RMK}")
+                    .unwrap_or((&response[start..end], ""));
+                (parts.0.trim().to_string(), parts.1.trim().to_string())
+            }
+            None => (
+                String::new(),
+                "AI ERROR. No translation proposed.".to_string(),
+            ),
+        };
+
+        let mut notes = format!("{}, {} occurrences", c.kind.as_str(), c.occurrences);
+        if !remarks.is_empty() {
+            notes += ". ";
+            notes += &remarks;
+        }
+
+        entries.push(GlossaryEntry {
+            do_not_translate: !target.is_empty() && target == c.term,
+            source: c.term.clone(),
+            target,
+            notes,
+        });
+    }
+
+    entries
+}
+
+/// Scans the speakers and texts for recurring terms, asks the AI for a consistent translation
+/// of each one, and writes a glossary (CSV) that can be reviewed and then used with --glossary.
+async fn extract_terms(
+    speakers: &[String],
+    texts: &[String],
+    min_occurrences: usize,
+    ai_settings: &open_ai::AiSettings<'_>,
+    dst_language: &str,
+    output_path: &str,
+    error_log: &mut File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let candidates = find_candidates(speakers, texts, min_occurrences);
+    info!("Found {} candidate terms", candidates.len());
+
+    let mut entries = Vec::with_capacity(candidates.len());
+    let num_batches = candidates.len().div_ceil(TERMS_PER_QUERY);
+    for (i, batch) in candidates.chunks(TERMS_PER_QUERY).enumerate() {
+        info!("Batch ID {} / {}", i, num_batches);
+
+        let prompt = generate_terms_prompt(batch, dst_language);
+        let response = open_ai::run_prompt(ai_settings, &prompt).await?;
+
+        let mut proposed = process_terms_response(&response, batch);
+        if proposed.iter().any(|e| e.target.is_empty()) {
+            writeln!(error_log, "# ERROR LOG Incomplete terms response:").ok();
+            writeln!(error_log, "==============================").ok();
+            writeln!(error_log, "{}", response).ok();
+            writeln!(error_log, "==============================").ok();
+        }
+        entries.append(&mut proposed);
+    }
+
+    let num_skipped = entries.iter().filter(|e| !e.is_complete()).count();
+    if num_skipped > 0 {
+        warn!(
+            "The AI proposed no translation for {} terms. They're commented out, fill them in by hand.",
+            num_skipped
+        );
+    }
+    info!("Writing glossary to {}", output_path);
+    glossary::write_glossary_csv(output_path, &entries)?;
+
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GlossaryArgs {
+    #[command(flatten)]
+    pub ai: AiArgs,
+
+    /// File to scan: a CSV, a subtitle file (.srt, .vtt, .ass) or an ODS spreadsheet.
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    pub input: String,
+
+    /// Glossary CSV to write. Review it, then pass it to translate --glossary.
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    pub output: String,
+
+    /// Language the terms will be translated to, as a BCP-47 tag (e.g. "es-AR") or a name.
+    #[arg(short, long)]
+    pub dst_lang: String,
+
+    /// In ODS files, comma-separated 0-based columns. The first one is the source text.
+    #[arg(long, default_value = "1")]
+    pub ods_columns: String,
+
+    /// How many times a term must appear to be considered. Speakers are always included.
+    #[arg(long, default_value_t = 2)]
+    pub min_term_occurrences: usize,
+
+    /// Frame rate used to convert the "Length" column to seconds.
+    #[arg(long, default_value_t = 24.0)]
+    pub fps: f64,
+}
+
+/// Scans the input for speakers, recurring proper nouns, capitalised terms and katakana runs,
+/// and asks the AI to propose consistent translations for them.
+/// The input is read with one of `formats` and the prompts are sent to one of `backends`.
+pub async fn glossary(
+    args: &GlossaryArgs,
+    formats: &[Box<dyn Format>],
+    backends: &[Box<dyn Backend>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dst_language = Language::parse(&args.dst_lang)?;
+
+    info!("Opening file {}", args.input);
+    let options = format::ReadOptions {
+        fps: args.fps,
+        ods_columns: args.ods_columns.clone(),
+        ..Default::default()
+    };
+    let document = format::find(formats, &args.input).read(&args.input, &options)?;
+    let speakers = document
+        .texts()
+        .map(|e| e.speaker.clone())
+        .collect::<Vec<_>>();
+    let texts = document.texts().map(|e| e.text.clone()).collect::<Vec<_>>();
+
+    let mut error_log = File::create(&args.ai.error_log)?;
+    let llm_options = args.ai.llm_options()?;
+    let ai_settings = args
+        .ai
+        .settings(TERMS_SYSTEM_PROMPT.to_string(), &llm_options, backends)?;
+
+    extract_terms(
+        &speakers,
+        &texts,
+        args.min_term_occurrences,
+        &ai_settings,
+        &dst_language.name,
+        &args.output,
+        &mut error_log,
+    )
+    .await
+}
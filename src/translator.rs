@@ -0,0 +1,486 @@
+//! The translation pipeline as a library, e.g. to call it from build tools.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use context_translate::{Args, translator::Translator};
+//!
+//! let args = Args::from_flags([
+//!     "--model", "gpt-4o",
+//!     "--endpoint", "https://api.openai.com/v1/chat/completions",
+//!     "--timeout-secs", "60",
+//!     "--system-prompt", "system_prompt.txt",
+//!     "--src-lang", "ja",
+//!     "--dst-lang", "en-US",
+//!     "--input", "script.csv",
+//!     "--output", "script.en.csv",
+//! ])?;
+//! let mut translator = Translator::new();
+//! let mut events = translator.subscribe();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.recv().await {
+//!         println!("{:?}", event);
+//!     }
+//! });
+//! for translation in translator.translate(&args).await? {
+//!     println!("{}: {} entries", translation.language, translation.entries.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::{info, warn};
+use std::fs::File;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::backend::{self, Backend};
+use crate::characters::Characters;
+use crate::formality::Formality;
+use crate::format::{self, Document, Format, Output, Pipeline};
+use crate::glossary::GlossaryFile;
+use crate::locale::Language;
+use crate::memory::Memory;
+use crate::target::Target;
+use crate::terms::{self, GlossaryArgs};
+use crate::validation::Validator;
+use crate::{
+    AiArgs, Args, BackScoring, BackTranslateArgs, BlenderTextRow, Prompts, convert, dialogue,
+    dst_languages, global_constraints, is_ods, load_examples, ods_reader, output_rows,
+    read_system_prompt, score_blender_lines, translate_blender_lines,
+};
+
+/// Progress of a translation.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Translation to one of the languages of --dst-lang began.
+    Started { language: String },
+    /// A batch is about to be sent to the AI. Batches of back translations report the
+    /// source language.
+    Batch {
+        language: String,
+        index: usize,
+        total: usize,
+    },
+    /// The output of a language was written.
+    Finished { language: String, output: String },
+}
+
+/// Where the events are sent.
+pub type Events = UnboundedSender<Event>;
+
+pub(crate) fn emit(events: Option<&Events>, event: Event) {
+    if let Some(events) = events {
+        // Nobody listening is fine.
+        events.send(event).ok();
+    }
+}
+
+/// The result of translating to one language.
+#[derive(Debug)]
+pub struct Translation {
+    /// As given in --dst-lang, e.g. "es-AR".
+    pub language: String,
+    /// Path of the output file.
+    pub output: String,
+    /// The translated entries, as written to the output (CSV columns).
+    pub entries: Vec<BlenderTextRow>,
+}
+
+/// Runs the same pipeline as the `translate` and `back-translate` commands.
+pub struct Translator {
+    events: Option<Events>,
+    formats: Vec<Box<dyn Format>>,
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Default for Translator {
+    fn default() -> Translator {
+        Translator::new()
+    }
+}
+
+impl Translator {
+    /// A translator with the built-in formats and backends.
+    pub fn new() -> Translator {
+        Translator {
+            events: None,
+            formats: format::builtin(),
+            backends: backend::builtin(),
+        }
+    }
+
+    /// Makes `backend` available to --backend, replacing any built-in one with the same name.
+    pub fn register_backend(&mut self, backend: Box<dyn Backend>) {
+        self.backends.insert(0, backend);
+    }
+
+    /// Reads and writes the files `format` matches with it, instead of the built-in formats.
+    pub fn register_format(&mut self, format: Box<dyn Format>) {
+        self.formats.insert(0, format);
+    }
+
+    /// Receives the progress events of the following runs.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// Translates --input to every language of --dst-lang and writes the output files.
+    pub async fn translate(
+        &self,
+        args: &Args,
+    ) -> Result<Vec<Translation>, Box<dyn std::error::Error>> {
+        let languages = dst_languages(&args.dst_lang);
+        let shared = Shared::load(args, &self.formats)?;
+        if languages.len() > 1 {
+            return translate_languages(
+                args,
+                &self.formats,
+                &self.backends,
+                &shared,
+                &languages,
+                self.events.as_ref(),
+            )
+            .await;
+        }
+
+        Ok(vec![
+            run(
+                args,
+                &self.formats,
+                &self.backends,
+                &shared,
+                self.events.as_ref(),
+            )
+            .await?,
+        ])
+    }
+
+    /// Finds speakers and recurring terms in --input and writes the translations the AI
+    /// proposes for them to --output, as a glossary CSV.
+    pub async fn glossary(&self, args: &GlossaryArgs) -> Result<(), Box<dyn std::error::Error>> {
+        terms::glossary(args, &self.formats, &self.backends).await
+    }
+
+    /// Translates --input, the output of a previous run, back to the source language
+    /// and scores it against the original. Writes it to --output.
+    pub async fn back_translate(
+        &self,
+        args: &BackTranslateArgs,
+    ) -> Result<Translation, Box<dyn std::error::Error>> {
+        let entries =
+            back_translate(args, &self.formats, &self.backends, self.events.as_ref()).await?;
+        Ok(Translation {
+            language: args.dst_lang.clone(),
+            output: args.output.clone(),
+            entries,
+        })
+    }
+}
+
+/// Translates the output of a previous run back to the source language, scores it
+/// against the original and writes it with the Original Back and score columns filled in.
+async fn back_translate(
+    args: &BackTranslateArgs,
+    formats: &[Box<dyn Format>],
+    backends: &[Box<dyn Backend>],
+    events: Option<&Events>,
+) -> Result<Vec<BlenderTextRow>, Box<dyn std::error::Error>> {
+    let dst_language = Language::parse(&args.dst_lang)?;
+    let src_language = Language::parse(&args.src_lang)?;
+    let mut error_log = File::create(&args.ai.error_log)?;
+    let llm_options = args.ai.llm_options()?;
+    let ai_settings = args.ai.settings(
+        read_system_prompt(&args.system_prompt)?,
+        &llm_options,
+        backends,
+    )?;
+
+    info!("Opening file {}", args.input);
+    let document =
+        format::find(formats, &args.input).read(&args.input, &args.read.read_options())?;
+    let mut translated = document.entries.clone();
+    let lines: Vec<BlenderTextRow> = translated
+        .iter()
+        .map(|t| BlenderTextRow {
+            text: t.original.clone().unwrap_or_default(),
+            ..t.clone()
+        })
+        .collect();
+    let scenes = dialogue::split_scenes(&args.scenes, &lines);
+
+    let target = Target {
+        locale: dst_language.locale.as_ref(),
+        protect_placeholders: args.protect_placeholders,
+        events,
+        ..Target::new(&dst_language.name)
+    };
+
+    info!("Begin Back Translation");
+    let scoring = BackScoring {
+        scorer: args.back_score.scorer(&args.ai.model),
+        prompts: Prompts::Dialogue(args.batch.window(&scenes)),
+        back_prompts: Prompts::Dialogue(args.batch.window(&scenes)),
+        target: target.back(&src_language.name),
+    };
+    let mut back = translate_blender_lines(
+        args.batch.batch_size as usize,
+        &scoring.back_prompts,
+        &translated,
+        &ai_settings,
+        &scoring.target,
+        &mut error_log,
+    )
+    .await?;
+    score_blender_lines(
+        &scoring,
+        &lines,
+        &mut translated,
+        &mut back,
+        &ai_settings,
+        &target,
+        &mut error_log,
+    )
+    .await?;
+
+    info!("Writing results to {}", args.output);
+    let rows = output_rows(translated, back, dst_language.tag().as_deref());
+    format::find(formats, &args.output).write(&args.output, &document, &dst_language, &rows)?;
+    Ok(rows)
+}
+
+/// What the runs of every language of --dst-lang have in common, loaded only once.
+struct Shared {
+    /// The contents of --input.
+    document: Document,
+    system_prompt: String,
+    llm_options: Option<serde_json::Value>,
+    glossary: Option<GlossaryFile>,
+    characters: Option<Characters>,
+}
+
+impl Shared {
+    fn load(
+        args: &Args,
+        formats: &[Box<dyn Format>],
+    ) -> Result<Shared, Box<dyn std::error::Error>> {
+        let system_prompt = read_system_prompt(&args.system_prompt)?;
+        let llm_options = args.ai.llm_options()?;
+
+        let glossary = match &args.glossary {
+            Some(path) => {
+                info!("Opening Glossary {}", path);
+                Some(GlossaryFile::read(path)?)
+            }
+            None => None,
+        };
+
+        let characters = match &args.characters {
+            Some(path) => {
+                info!("Opening Character Profiles {}", path);
+                Some(Characters::load(path)?)
+            }
+            None => None,
+        };
+
+        info!("Opening file {}", args.input);
+        let document =
+            format::find(formats, &args.input).read(&args.input, &args.read.read_options())?;
+
+        Ok(Shared {
+            document,
+            system_prompt,
+            llm_options,
+            glossary,
+            characters,
+        })
+    }
+}
+
+/// Output path for one of several languages, e.g. "out.csv" -> "out.fr.csv".
+fn language_path(path: &str, language: &str) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, language, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, language),
+    };
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
+/// Translates to each language in --dst-lang, writing one output file per language
+/// (and one error log). For ODS outputs, a workbook with one column per language is also written.
+/// The outputs of previous runs (--previous, and --examples unless it's a TMX file or a
+/// translation memory) are also per language.
+async fn translate_languages(
+    args: &Args,
+    formats: &[Box<dyn Format>],
+    backends: &[Box<dyn Backend>],
+    shared: &Shared,
+    languages: &[String],
+    events: Option<&Events>,
+) -> Result<Vec<Translation>, Box<dyn std::error::Error>> {
+    let runs: Vec<Args> = languages
+        .iter()
+        .map(|language| {
+            let path = |p: &String| language_path(p, language);
+            Args {
+                ai: AiArgs {
+                    error_log: path(&args.ai.error_log),
+                    ..args.ai.clone()
+                },
+                dst_lang: language.clone(),
+                output: path(&args.output),
+                dst_subtitles: args.dst_subtitles.as_ref().map(path),
+                previous: args.previous.as_ref().map(path),
+                examples: args
+                    .examples
+                    .as_ref()
+                    .map(|p| match convert::is_run_output(p) {
+                        true => path(p),
+                        false => p.clone(),
+                    }),
+                ..args.clone()
+            }
+        })
+        .collect();
+
+    let mut translations = Vec::with_capacity(runs.len());
+    if args.parallel_languages {
+        let results = futures::future::join_all(
+            runs.iter()
+                .map(|args| run(args, formats, backends, shared, events)),
+        )
+        .await;
+        for result in results {
+            translations.push(result?);
+        }
+    } else {
+        for args in &runs {
+            info!("Translating to {}", args.dst_lang);
+            translations.push(run(args, formats, backends, shared, events).await?);
+        }
+    }
+
+    if is_ods(&args.output) {
+        info!("Writing all languages to {}", args.output);
+        let outputs: Vec<(&str, &str)> = runs
+            .iter()
+            .map(|args| (args.dst_lang.as_str(), args.output.as_str()))
+            .collect();
+        ods_reader::write_languages(&args.output, &outputs)?;
+    }
+
+    Ok(translations)
+}
+
+/// Translates to a single language.
+async fn run(
+    args: &Args,
+    formats: &[Box<dyn Format>],
+    backends: &[Box<dyn Backend>],
+    shared: &Shared,
+    events: Option<&Events>,
+) -> Result<Translation, Box<dyn std::error::Error>> {
+    emit(
+        events,
+        Event::Started {
+            language: args.dst_lang.clone(),
+        },
+    );
+    let dst_language = Language::parse(&args.dst_lang)?;
+    let memory = match &args.memory {
+        Some(path) => {
+            info!("Opening Translation Memory {}", path);
+            let memory = Memory::open(
+                path,
+                args.src_lang
+                    .as_deref()
+                    .ok_or("--memory requires --src-lang")?,
+                &args.dst_lang,
+                args.memory_threshold,
+            )?;
+            info!("{} entries for this language pair", memory.num_units());
+            Some(memory)
+        }
+        None => None,
+    };
+
+    let mut error_log = File::create(&args.ai.error_log)?;
+
+    let ai_settings =
+        args.ai
+            .settings(shared.system_prompt.clone(), &shared.llm_options, backends)?;
+
+    let glossary = shared
+        .glossary
+        .as_ref()
+        .map(|g| g.glossary(args.src_lang.as_deref(), &args.dst_lang));
+
+    let examples = load_examples(args, &shared.document, &ai_settings).await?;
+
+    let formality = match &args.formality {
+        Some(path) => {
+            info!("Opening Formality Policies {}", path);
+            Some(Formality::load(path, dst_language.code())?)
+        }
+        None => None,
+    };
+
+    let target = Target {
+        language: &dst_language.name,
+        locale: dst_language.locale.as_ref(),
+        glossary: glossary.as_ref(),
+        characters: shared.characters.as_ref(),
+        formality: formality.as_ref(),
+        memory: memory.as_ref(),
+        examples: examples.as_ref(),
+        protect_placeholders: args.protect_placeholders,
+        events,
+        validator: Validator {
+            glossary: glossary.as_ref(),
+            glossary_policy: args.glossary_check,
+            placeholder_policy: args.placeholder_check,
+            newline_policy: args.newline_policy,
+            length_policy: args.length_check,
+            formality: formality.as_ref(),
+            formality_policy: args.formality_check,
+            constraints: global_constraints(args),
+        },
+    };
+
+    let format = format::find(formats, &args.input);
+    let output = Output {
+        path: &args.output,
+        format: format::find(formats, &args.output),
+        document: &shared.document,
+        language: &dst_language,
+    };
+
+    let entries = match args.pipeline.unwrap_or(format.pipeline()) {
+        Pipeline::KeyValue => {
+            if !args.candidates.is_empty() {
+                warn!("--candidates is not available in the key/value pipeline. Ignoring it.");
+            }
+            ods_reader::translate_key_value(args, &output, &mut error_log, &ai_settings, &target)
+                .await?
+        }
+        Pipeline::Dialogue => {
+            dialogue::translate_dialogue(args, &output, &mut error_log, &ai_settings, &target)
+                .await?
+        }
+    };
+
+    emit(
+        events,
+        Event::Finished {
+            language: args.dst_lang.clone(),
+            output: args.output.clone(),
+        },
+    );
+    Ok(Translation {
+        language: args.dst_lang.clone(),
+        output: args.output.clone(),
+        entries,
+    })
+}
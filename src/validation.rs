@@ -0,0 +1,418 @@
+use log::info;
+
+use crate::formality::Formality;
+use crate::glossary::Glossary;
+use crate::locale::Language;
+use crate::placeholders::{self, ProtectedText};
+use crate::{format, layout};
+
+/// What to do when a check fails.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CheckPolicy {
+    /// Don't check at all.
+    Ignore,
+    /// Add the problem to the Remarks.
+    #[default]
+    Warn,
+    /// Re-translate the entry explaining what went wrong. If it still fails, add it to the Remarks.
+    Retry,
+}
+
+/// What to do when the translation doesn't have the same number of lines as the source.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NewlinePolicy {
+    /// Don't check at all.
+    Ignore,
+    /// Add the problem to the Remarks.
+    #[default]
+    Warn,
+    /// Re-translate the entry explicitly asking for the right number of lines.
+    /// If it still fails, add it to the Remarks.
+    Retry,
+    /// Rebalance the line breaks automatically.
+    Reflow,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub policy: CheckPolicy,
+    pub message: String,
+}
+
+/// How many times a single entry is re-translated when a check with CheckPolicy::Retry fails.
+pub const ENTRY_RETRIES: usize = 3;
+/// How many times a batch is sent to the AI until its answer can be parsed. The entries of
+/// the batch are given up on after that.
+pub const BATCH_ATTEMPTS: usize = 9;
+
+/// Start of the remarks lines written by `add_remarks`.
+const ISSUE_PREFIX: &str = "CHECK: ";
+
+/// Post-translation checks that are run on every entry.
+/// The resources it holds (e.g. the glossary) are also used to enrich the prompts.
+#[derive(Default)]
+pub struct Validator<'a> {
+    pub glossary: Option<&'a Glossary>,
+    pub glossary_policy: CheckPolicy,
+    pub placeholder_policy: CheckPolicy,
+    pub newline_policy: NewlinePolicy,
+    pub length_policy: CheckPolicy,
+    pub formality: Option<&'a Formality>,
+    pub formality_policy: CheckPolicy,
+    /// Length limits applied to entries that don't specify their own.
+    pub constraints: layout::Constraints,
+}
+
+impl Validator<'_> {
+    /// Restores the placeholders in `translation` and checks the result.
+    /// `constraints` are the entry's own length limits (if any).
+    /// `speaker` selects the formality policy (empty if unknown).
+    /// Returns the restored translation and all the issues found.
+    pub fn check(
+        &self,
+        source: &ProtectedText,
+        translation: &str,
+        constraints: layout::Constraints,
+        speaker: &str,
+    ) -> (String, Vec<Issue>) {
+        let (mut translation, problems) = source.restore(translation);
+
+        let mut issues = Vec::new();
+        if self.placeholder_policy != CheckPolicy::Ignore {
+            for message in problems {
+                issues.push(Issue {
+                    policy: self.placeholder_policy,
+                    message,
+                });
+            }
+        }
+
+        self.check_line_count(&source.original, &mut translation, &mut issues);
+
+        let source = source.original.as_str();
+        let translation = translation.as_str();
+
+        if let Some(glossary) = self.glossary
+            && self.glossary_policy != CheckPolicy::Ignore
+        {
+            for message in glossary.check(source, translation) {
+                issues.push(Issue {
+                    policy: self.glossary_policy,
+                    message,
+                });
+            }
+        }
+
+        if let Some(formality) = self.formality
+            && self.formality_policy != CheckPolicy::Ignore
+        {
+            for message in formality.check(speaker, source, translation) {
+                issues.push(Issue {
+                    policy: self.formality_policy,
+                    message,
+                });
+            }
+        }
+
+        if self.length_policy != CheckPolicy::Ignore {
+            for message in constraints.or(self.constraints).check(translation) {
+                issues.push(Issue {
+                    policy: self.length_policy,
+                    message,
+                });
+            }
+        }
+
+        (translation.to_string(), issues)
+    }
+
+    fn check_line_count(&self, source: &str, translation: &mut String, issues: &mut Vec<Issue>) {
+        let expected = layout::line_count(source);
+        let actual = layout::line_count(translation);
+        if expected == actual {
+            return;
+        }
+
+        let message = format!(
+            "The translation must have exactly {} lines (it has {}).",
+            expected, actual
+        );
+        match self.newline_policy {
+            NewlinePolicy::Ignore => {}
+            NewlinePolicy::Warn => issues.push(Issue {
+                policy: CheckPolicy::Warn,
+                message,
+            }),
+            NewlinePolicy::Retry => issues.push(Issue {
+                policy: CheckPolicy::Retry,
+                message,
+            }),
+            NewlinePolicy::Reflow => match layout::reflow(translation, expected) {
+                Some(reflowed) => {
+                    *translation = reflowed;
+                    issues.push(Issue {
+                        policy: CheckPolicy::Warn,
+                        message: format!(
+                            "Line breaks were rebalanced automatically (the AI returned {} lines).",
+                            actual
+                        ),
+                    });
+                }
+                None => issues.push(Issue {
+                    policy: CheckPolicy::Warn,
+                    message,
+                }),
+            },
+        }
+    }
+}
+
+pub fn needs_retry(issues: &[Issue]) -> bool {
+    issues.iter().any(|i| i.policy == CheckPolicy::Retry)
+}
+
+/// Appends the issues to the remarks, one per line.
+pub fn add_remarks(remarks: &mut String, issues: &[Issue]) {
+    for issue in issues {
+        if !remarks.is_empty() {
+            remarks.push('\n');
+        }
+        remarks.push_str(ISSUE_PREFIX);
+        remarks.push_str(&issue.message);
+    }
+}
+
+/// Whether the remarks report a failed check (see `add_remarks`). Other remarks are notes
+/// of the AI.
+pub fn has_issues(remarks: &str) -> bool {
+    remarks.lines().any(|l| l.starts_with(ISSUE_PREFIX))
+}
+
+/// Prompt section explaining to the AI what was wrong with its previous attempt.
+pub fn write_retry_notes(prompt: &mut String, issues: &[Issue]) {
+    // Key/value prompts end with the text of the entry, without a line break.
+    if !prompt.is_empty() && !prompt.ends_with('\n') {
+        prompt.push_str("\n\n");
+    }
+    prompt.push_str("# CORRECTIONS BEGIN\n");
+    prompt.push_str("A previous translation of this text was rejected because:\n");
+    for issue in issues {
+        prompt.push_str("- ");
+        prompt.push_str(&issue.message);
+        prompt.push('\n');
+    }
+    prompt.push_str("# CORRECTIONS END\n");
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    /// Output (CSV or ODS) of a previous run. Its Original column is the source text.
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    pub input: String,
+
+    /// Source Language, as a BCP-47 tag or a name. Used to match glossary entries.
+    #[arg(short, long)]
+    pub src_lang: Option<String>,
+    /// Language of the translations, as a BCP-47 tag (e.g. "es-AR") or a name.
+    #[arg(short, long)]
+    pub dst_lang: String,
+
+    /// Glossary (CSV or TBX) whose target terms must be used (see translate --glossary).
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub glossary: Option<String>,
+
+    /// JSON file with formality and honorific policies (see translate --formality).
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub formality: Option<String>,
+
+    /// Check that placeholders and markup ({0}, %s, <b>...) of the original are kept.
+    #[arg(long)]
+    pub check_placeholders: bool,
+
+    /// Maximum number of characters per line for every entry.
+    /// Entries can override it with a "Max Chars" column in the CSV.
+    #[arg(long)]
+    pub max_chars: Option<usize>,
+
+    /// Maximum display width per line for every entry (CJK characters count as 2).
+    /// Entries can override it with a "Max Width" column in the CSV.
+    #[arg(long)]
+    pub max_width: Option<usize>,
+
+    /// Maximum reading speed in characters per second, for entries with a "Length" column.
+    #[arg(long)]
+    pub max_cps: Option<f32>,
+
+    /// Frame rate used to convert the "Length" column to seconds.
+    #[arg(long, default_value_t = 24.0)]
+    pub fps: f64,
+}
+
+/// Runs the checks on the output of a previous run and prints the issues found.
+/// Fails if there's any, so it can be used in scripts.
+pub fn validate(args: &ValidateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let dst_language = Language::parse(&args.dst_lang)?;
+
+    let glossary = match &args.glossary {
+        Some(path) => {
+            info!("Opening Glossary {}", path);
+            Some(Glossary::load(
+                path,
+                args.src_lang.as_deref(),
+                &args.dst_lang,
+            )?)
+        }
+        None => None,
+    };
+    let formality = match &args.formality {
+        Some(path) => {
+            info!("Opening Formality Policies {}", path);
+            Some(Formality::load(path, dst_language.code())?)
+        }
+        None => None,
+    };
+
+    let validator = Validator {
+        glossary: glossary.as_ref(),
+        glossary_policy: CheckPolicy::Warn,
+        placeholder_policy: match args.check_placeholders {
+            true => CheckPolicy::Warn,
+            false => CheckPolicy::Ignore,
+        },
+        newline_policy: NewlinePolicy::Warn,
+        length_policy: CheckPolicy::Warn,
+        formality: formality.as_ref(),
+        formality_policy: CheckPolicy::Warn,
+        constraints: layout::Constraints {
+            max_chars: args.max_chars,
+            max_width: args.max_width,
+            max_cps: args.max_cps,
+            ..Default::default()
+        },
+    };
+
+    // (key, speaker, original, translation, constraints)
+    info!("Opening file {}", args.input);
+    let options = format::ReadOptions {
+        fps: args.fps,
+        ..Default::default()
+    };
+    let entries: Vec<(String, String, String, String, layout::Constraints)> =
+        format::read(&args.input, &options)?
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let key = crate::row_key(&l.datablock_name, i);
+                let constraints = l.constraints();
+                let original = l.original.unwrap_or_default();
+                (key, l.speaker, original, l.text, constraints)
+            })
+            .collect();
+
+    let mut num_issues = 0;
+    let mut num_entries = 0;
+    for (key, speaker, original, translation, constraints) in &entries {
+        if original.is_empty() {
+            continue;
+        }
+        let source = match args.check_placeholders {
+            true => placeholders::protect(original),
+            false => placeholders::unprotected(original),
+        };
+        let (translation, mut problems) = source.reprotect(translation);
+        if !args.check_placeholders {
+            problems.clear();
+        }
+        let (_, issues) = validator.check(&source, &translation, *constraints, speaker);
+
+        let messages: Vec<&str> = problems
+            .iter()
+            .map(String::as_str)
+            .chain(issues.iter().map(|i| i.message.as_str()))
+            .collect();
+        if messages.is_empty() {
+            continue;
+        }
+        num_entries += 1;
+        num_issues += messages.len();
+        info!("{}:", key);
+        for message in messages {
+            info!("  - {}", message);
+        }
+    }
+
+    info!(
+        "{} issues in {} of {} entries",
+        num_issues,
+        num_entries,
+        entries.len()
+    );
+    if num_issues > 0 {
+        return Err(format!("{} entries didn't pass the checks", num_entries).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: NewlinePolicy, source: &str, translation: &str) -> (String, Vec<Issue>) {
+        let validator = Validator {
+            newline_policy: policy,
+            ..Default::default()
+        };
+        validator.check(
+            &placeholders::unprotected(source),
+            translation,
+            layout::Constraints::default(),
+            "",
+        )
+    }
+
+    #[test]
+    fn line_count_policies() {
+        let source = "Hello there,\nhow are you?";
+        let translation = "Hola, ¿cómo estás?";
+
+        let (text, issues) = check(NewlinePolicy::Ignore, source, translation);
+        assert_eq!(text, translation);
+        assert!(issues.is_empty());
+
+        let (_, issues) = check(NewlinePolicy::Warn, source, translation);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].policy, CheckPolicy::Warn);
+        assert_eq!(
+            issues[0].message,
+            "The translation must have exactly 2 lines (it has 1)."
+        );
+
+        let (_, issues) = check(NewlinePolicy::Retry, source, translation);
+        assert!(needs_retry(&issues));
+
+        let (text, issues) = check(NewlinePolicy::Reflow, source, translation);
+        assert_eq!(text, "Hola, ¿cómo\nestás?");
+        assert!(!needs_retry(&issues));
+        assert!(issues[0].message.contains("rebalanced"));
+
+        // Blank lines around the text don't count.
+        let (_, issues) = check(NewlinePolicy::Warn, source, "\nHola,\n¿cómo estás?\n");
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn remarks() {
+        let mut remarks = "AI note".to_string();
+        assert!(!has_issues(&remarks));
+        add_remarks(
+            &mut remarks,
+            &[Issue {
+                policy: CheckPolicy::Warn,
+                message: "Too long.".to_string(),
+            }],
+        );
+        assert_eq!(remarks, "AI note\nCHECK: Too long.");
+        assert!(has_issues(&remarks));
+    }
+}
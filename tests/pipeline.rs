@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+
+use context_translate::Args;
+use context_translate::backend::{Backend, Completion, CompletionFuture, Message};
+use context_translate::open_ai::AiSettings;
+use context_translate::translator::Translator;
+
+/// Answers the dialogue prompts with each line prefixed by "T:". The first answer is
+/// garbage, so the batch has to be retried.
+struct Mock {
+    calls: Arc<AtomicUsize>,
+    prompts: Arc<Mutex<Vec<String>>>,
+}
+
+impl Backend for Mock {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn send<'a>(
+        &'a self,
+        _settings: &'a AiSettings<'_>,
+        messages: &'a [Message<'a>],
+    ) -> CompletionFuture<'a> {
+        async move {
+            let prompt = messages.last().unwrap().content;
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Ok(Completion {
+                    text: "Sorry, I can't help with that.".to_string(),
+                    ..Default::default()
+                });
+            }
+
+            let text = prompt
+                .split("# TEXT BEGIN\n")
+                .nth(1)
+                .and_then(|t| t.split("# TEXT END").next())
+                .unwrap_or_default();
+            let answer: Vec<String> = text
+                .lines()
+                .map(|l| match l.starts_with("{SPK}") {
+                    true => l.to_string(),
+                    false => format!("T:{}", l),
+                })
+                .collect();
+            Ok(Completion {
+                text: answer.join("\n"),
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+}
+
+#[tokio::test]
+async fn translates_csv_with_registered_backend() {
+    let dir = std::env::temp_dir().join(format!("context_translate_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.csv");
+    let output = dir.join("out.csv");
+    let system_prompt = dir.join("system.txt");
+    std::fs::write(
+        &input,
+        "datablock_name;Collection;Text Contents\n\
+         k1;John;Hi Anna!\n\
+         k2;Anna;Hello John.\n",
+    )
+    .unwrap();
+    std::fs::write(&system_prompt, "Translate.").unwrap();
+
+    let args = Args::from_flags([
+        "--model",
+        "mock-model",
+        "--endpoint",
+        "http://127.0.0.1:9/unused",
+        "--api-key",
+        "unused",
+        "--timeout-secs",
+        "10",
+        "--backend",
+        "mock",
+        "--system-prompt",
+        system_prompt.to_str().unwrap(),
+        "--input",
+        input.to_str().unwrap(),
+        "--output",
+        output.to_str().unwrap(),
+        "--dst-lang",
+        "French",
+    ])
+    .unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let prompts = Arc::new(Mutex::new(Vec::new()));
+    let mut translator = Translator::new();
+    translator.register_backend(Box::new(Mock {
+        calls: calls.clone(),
+        prompts: prompts.clone(),
+    }));
+    let translations = translator.translate(&args).await.unwrap();
+
+    // The garbage answer was retried with the same prompt.
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    let prompts = prompts.lock().unwrap();
+    assert_eq!(prompts[0], prompts[1]);
+    assert!(prompts[0].contains("Hi Anna!"));
+
+    assert_eq!(translations.len(), 1);
+    let entries = &translations[0].entries;
+    let texts: Vec<&str> = entries.iter().map(|e| e.text.as_str()).collect();
+    assert_eq!(texts, ["T:Hi Anna!", "T:Hello John."]);
+    assert_eq!(entries[0].datablock_name, "k1");
+    assert_eq!(entries[1].speaker, "Anna");
+    assert_eq!(entries[1].original.as_deref(), Some("Hello John."));
+
+    let written = std::fs::read_to_string(&output).unwrap();
+    assert!(written.contains("k1;John;T:Hi Anna!;Hi Anna!"));
+
+    std::fs::remove_dir_all(&dir).ok();
+}